@@ -0,0 +1,106 @@
+// WebAuthn ceremonies: the options are fetched as json,
+// then the response of the authenticator is sent back with the form (base64url encoded)
+
+function base64urlToBuffer(value) {
+    const base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+    const padded = base64.padEnd(base64.length + ((4 - (base64.length % 4)) % 4), "=");
+
+    return Uint8Array.from(atob(padded), (char) => char.charCodeAt(0)).buffer;
+}
+
+function bufferToBase64url(buffer) {
+    const binary = String.fromCharCode(...new Uint8Array(buffer));
+
+    return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+async function fetchWebauthnOptions(form, optionsUrl) {
+    const response = await fetch(optionsUrl, {
+        method: "POST",
+        body: new URLSearchParams(new FormData(form)),
+    });
+
+    // Sensitive actions may first need the user to authenticate again
+    if (response.redirected) {
+        window.location.assign(response.url);
+        return new Promise(() => {});
+    }
+
+    if (!response.ok) {
+        throw new Error(await response.text());
+    }
+
+    return response.json();
+}
+
+function setWebauthnField(form, name, value) {
+    let input = form.querySelector(`input[name="${name}"]`);
+
+    if (!input) {
+        input = document.createElement("input");
+        input.type = "hidden";
+        input.name = name;
+        form.appendChild(input);
+    }
+
+    input.value = value;
+}
+
+function showWebauthnError(form, error) {
+    const errorLabel = form.querySelector("[data-webauthn-error]");
+
+    if (errorLabel) {
+        errorLabel.textContent = error.message || "La clé d'accès n'a pas pu être utilisée";
+        errorLabel.hidden = false;
+    }
+}
+
+async function registerPasskey(form, optionsUrl) {
+    try {
+        const options = await fetchWebauthnOptions(form, optionsUrl);
+
+        options.challenge = base64urlToBuffer(options.challenge);
+        options.user.id = base64urlToBuffer(options.user.id);
+        options.excludeCredentials = options.excludeCredentials.map((credential) => ({
+            ...credential,
+            id: base64urlToBuffer(credential.id),
+        }));
+
+        const credential = await navigator.credentials.create({ publicKey: options });
+
+        setWebauthnField(form, "client_data", bufferToBase64url(credential.response.clientDataJSON));
+        setWebauthnField(form, "attestation_object", bufferToBase64url(credential.response.attestationObject));
+
+        form.submit();
+    } catch (error) {
+        showWebauthnError(form, error);
+    }
+}
+
+async function usePasskey(form, optionsUrl) {
+    try {
+        const options = await fetchWebauthnOptions(form, optionsUrl);
+
+        options.challenge = base64urlToBuffer(options.challenge);
+        options.allowCredentials = options.allowCredentials.map((credential) => ({
+            ...credential,
+            id: base64urlToBuffer(credential.id),
+        }));
+
+        const credential = await navigator.credentials.get({ publicKey: options });
+
+        setWebauthnField(form, "credential_id", bufferToBase64url(credential.rawId));
+        setWebauthnField(form, "client_data", bufferToBase64url(credential.response.clientDataJSON));
+        setWebauthnField(form, "authenticator_data", bufferToBase64url(credential.response.authenticatorData));
+        setWebauthnField(form, "signature", bufferToBase64url(credential.response.signature));
+        setWebauthnField(
+            form,
+            "user_handle",
+            credential.response.userHandle ? bufferToBase64url(credential.response.userHandle) : "",
+        );
+
+        form.submit();
+    } catch (error) {
+        showWebauthnError(form, error);
+    }
+}
@@ -0,0 +1,12 @@
+-- Apps subject type
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS subject_type VARCHAR NOT NULL DEFAULT 'public';
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS sector_identifier VARCHAR NOT NULL DEFAULT '';
+
+-- Pairwise subjects
+CREATE TABLE IF NOT EXISTS pairwise_subjects (
+    sector_identifier VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (sector_identifier, subject)
+);
@@ -0,0 +1,23 @@
+-- Grants given by users to apps
+CREATE TABLE IF NOT EXISTS grants (
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    app_id INTEGER NOT NULL,
+    scope VARCHAR NOT NULL,
+    claims VARCHAR NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, app_id)
+);
+
+-- Authorization codes
+CREATE TABLE IF NOT EXISTS authorization_codes (
+    hashed_code VARCHAR PRIMARY KEY,
+    app_id INTEGER NOT NULL,
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    redirect_uri VARCHAR NOT NULL,
+    scope VARCHAR NOT NULL,
+    claims VARCHAR NOT NULL,
+    nonce VARCHAR,
+    auth_time BIGINT NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Authentication methods used to get the authorization code
+ALTER TABLE authorization_codes ADD COLUMN IF NOT EXISTS auth_methods VARCHAR NOT NULL DEFAULT '';
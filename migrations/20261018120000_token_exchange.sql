@@ -0,0 +1,2 @@
+-- Apps for which an app can exchange the tokens of its users
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS token_exchange_audiences VARCHAR NOT NULL DEFAULT '';
@@ -0,0 +1,13 @@
+-- API resources of the apps (RFC 8707)
+CREATE TABLE IF NOT EXISTS api_resources (
+    id SERIAL PRIMARY KEY,
+    app_id INTEGER NOT NULL REFERENCES apps ON DELETE CASCADE,
+    identifier VARCHAR NOT NULL UNIQUE,
+    name VARCHAR NOT NULL,
+    scopes VARCHAR NOT NULL DEFAULT '',
+    allowed_clients VARCHAR NOT NULL DEFAULT '',
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Resource requested with the authorization code
+ALTER TABLE authorization_codes ADD COLUMN IF NOT EXISTS resource VARCHAR;
@@ -0,0 +1,5 @@
+-- DPoP proofs already used (RFC 9449)
+CREATE TABLE IF NOT EXISTS dpop_proofs (
+    hashed_jti VARCHAR PRIMARY KEY,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Native apps (loopback and private-use redirect uris)
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS application_type VARCHAR NOT NULL DEFAULT 'web';
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS private_use_schemes VARCHAR NOT NULL DEFAULT '';
+
+-- PKCE challenge bound to the authorization code
+ALTER TABLE authorization_codes ADD COLUMN IF NOT EXISTS code_challenge VARCHAR;
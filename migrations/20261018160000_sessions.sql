@@ -0,0 +1,13 @@
+-- Sessions of the users on their devices
+CREATE TABLE IF NOT EXISTS sessions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    hashed_session_id VARCHAR NOT NULL UNIQUE,
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    user_agent VARCHAR NOT NULL,
+    ip_address VARCHAR NOT NULL,
+    auth_methods VARCHAR NOT NULL,
+    auth_time BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
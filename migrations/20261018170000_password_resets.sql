@@ -0,0 +1,6 @@
+-- Single use links to reset a forgotten password
+CREATE TABLE IF NOT EXISTS password_resets (
+    hashed_token VARCHAR PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Apps allowing passwordless sign in by mail
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS magic_link_allowed BOOLEAN NOT NULL DEFAULT false;
+
+-- Single use sign in links
+CREATE TABLE IF NOT EXISTS magic_links (
+    hashed_token VARCHAR PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    app_id INTEGER NOT NULL,
+    requested_endpoint VARCHAR,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Short codes sent by mail (sign in and mail confirmation)
+CREATE TABLE IF NOT EXISTS mail_codes (
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    purpose VARCHAR NOT NULL,
+    hashed_code VARCHAR NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (user_id, purpose)
+);
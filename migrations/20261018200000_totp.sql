@@ -0,0 +1,19 @@
+-- TOTP second factor (secret encrypted with the app encryption key)
+CREATE TABLE IF NOT EXISTS totp_secrets (
+    user_id UUID PRIMARY KEY REFERENCES users ON DELETE CASCADE,
+    encrypted_secret VARCHAR NOT NULL,
+    is_enabled BOOLEAN NOT NULL DEFAULT false,
+    last_used_step BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Sign in waiting for the second factor
+CREATE TABLE IF NOT EXISTS second_factor_challenges (
+    hashed_token VARCHAR PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    app_id INTEGER NOT NULL,
+    requested_endpoint VARCHAR,
+    first_methods VARCHAR NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
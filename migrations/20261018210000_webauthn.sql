@@ -0,0 +1,19 @@
+-- Passkeys and security keys of the users (ES256 public keys)
+CREATE TABLE IF NOT EXISTS webauthn_credentials (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    credential_id VARCHAR NOT NULL UNIQUE,
+    public_key VARCHAR NOT NULL,
+    sign_count BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Challenges given to the authenticators
+CREATE TABLE IF NOT EXISTS webauthn_challenges (
+    hashed_challenge VARCHAR PRIMARY KEY,
+    ceremony VARCHAR NOT NULL,
+    user_id UUID REFERENCES users ON DELETE CASCADE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
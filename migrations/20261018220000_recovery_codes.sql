@@ -0,0 +1,8 @@
+-- Single use codes to get back into an account without the mailbox
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    hashed_code VARCHAR NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, hashed_code)
+);
@@ -0,0 +1,16 @@
+-- Failed sign ins of an account (hash of the mail) or of a client ip address
+CREATE TABLE IF NOT EXISTS signin_failures (
+    kind VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    blocked_until TIMESTAMP WITH TIME ZONE,
+    PRIMARY KEY (kind, subject)
+);
+
+-- Single use links sent by mail to unlock an account
+CREATE TABLE IF NOT EXISTS unlock_links (
+    hashed_token VARCHAR PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
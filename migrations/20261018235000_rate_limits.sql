@@ -0,0 +1,7 @@
+-- Requests counted per key (ip, account or app) in fixed windows (Postgres rate limit store)
+CREATE TABLE IF NOT EXISTS rate_limit_windows (
+    key VARCHAR NOT NULL,
+    window_start BIGINT NOT NULL,
+    hits BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (key, window_start)
+);
@@ -0,0 +1,4 @@
+-- Token exchanges are allowed by the audience app: the apps whose tokens it accepts
+-- The previous lists were set by the requesting apps, they are not kept
+ALTER TABLE apps RENAME COLUMN token_exchange_audiences TO token_exchange_clients;
+UPDATE apps SET token_exchange_clients = '';
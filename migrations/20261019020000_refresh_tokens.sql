@@ -0,0 +1,13 @@
+-- Refresh tokens (scope offline_access), bound to the DPoP key of the app if any
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    hashed_token VARCHAR PRIMARY KEY,
+    app_id INTEGER NOT NULL,
+    user_id UUID NOT NULL REFERENCES users ON DELETE CASCADE,
+    scope VARCHAR NOT NULL,
+    resource VARCHAR,
+    jkt VARCHAR,
+    auth_time BIGINT NOT NULL,
+    auth_methods VARCHAR NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+-- Mail codes only work for the mail they were sent to
+ALTER TABLE mail_codes ADD COLUMN IF NOT EXISTS hashed_mail VARCHAR NOT NULL DEFAULT '';
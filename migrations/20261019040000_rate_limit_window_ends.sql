@@ -0,0 +1,2 @@
+-- Windows are deleted once ended, whatever the quota they belong to
+ALTER TABLE rate_limit_windows ADD COLUMN IF NOT EXISTS window_end BIGINT NOT NULL DEFAULT 0;
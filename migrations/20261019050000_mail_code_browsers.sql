@@ -0,0 +1,2 @@
+-- Only the browser that asked for a mail code can spend its attempts
+ALTER TABLE mail_codes ADD COLUMN IF NOT EXISTS hashed_browser VARCHAR NOT NULL DEFAULT '';
@@ -0,0 +1,2 @@
+-- Expired challenges are deleted each time a challenge is given
+CREATE INDEX IF NOT EXISTS webauthn_challenges_expires_at ON webauthn_challenges (expires_at);
@@ -0,0 +1,229 @@
+use sqlx::{types::time::OffsetDateTime, FromRow};
+use tracing::log::error;
+
+use crate::{auth::IdSession, general::AuthenticatorError, AppState};
+
+use super::App;
+
+/// API of an app that only accepts the access tokens issued for it
+/// identifier = resource indicator used as audience of the tokens (RFC 8707)
+#[derive(Clone, Debug, FromRow)]
+pub struct ApiResource {
+    pub id: i32,
+    pub app_id: i32,
+    pub identifier: String,
+    pub name: String,
+    pub scopes: String,
+    pub allowed_clients: String,
+    pub created_at: OffsetDateTime,
+}
+
+impl ApiResource {
+    pub fn new(app_id: i32) -> Self {
+        Self {
+            id: -1,
+            app_id,
+            identifier: "".to_owned(),
+            name: "".to_owned(),
+            scopes: "".to_owned(),
+            allowed_clients: "".to_owned(),
+            created_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    pub fn is_new(&self) -> bool {
+        self.id < 0
+    }
+
+    /// Check if the client app can call the resource
+    pub fn permits(&self, client: &App) -> bool {
+        client.id == self.app_id
+            || self
+                .allowed_clients
+                .split_whitespace()
+                .any(|app_id| app_id == client.id.to_string())
+    }
+
+    /// Part of the granted scope that concerns the resource
+    pub fn granted_scope(&self, scope: &str) -> String {
+        scope
+            .split_whitespace()
+            .filter(|granted| self.scopes.split_whitespace().any(|own| own == *granted))
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    pub async fn select_for_app(
+        state: &AppState,
+        app_id: i32,
+    ) -> Result<Vec<Self>, AuthenticatorError> {
+        let resources: Vec<ApiResource> = sqlx::query_as(
+            "SELECT
+                id,
+                app_id,
+                identifier,
+                name,
+                scopes,
+                allowed_clients,
+                created_at
+            FROM api_resources
+            WHERE
+                app_id = $1
+            ORDER BY
+                name",
+        )
+        .bind(app_id)
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Selecting resources for app {} -> {:?}", app_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(resources)
+    }
+
+    pub async fn select_from_id(state: &AppState, id: i32) -> Result<Self, AuthenticatorError> {
+        let resource: ApiResource = sqlx::query_as(
+            "SELECT
+                id,
+                app_id,
+                identifier,
+                name,
+                scopes,
+                allowed_clients,
+                created_at
+            FROM api_resources
+            WHERE
+                id = $1",
+        )
+        .bind(id)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Selecting resource from id {} -> {:?}", id, error);
+            AuthenticatorError::ResourceNotFound
+        })?;
+
+        Ok(resource)
+    }
+
+    pub async fn select_from_identifier(
+        state: &AppState,
+        identifier: &str,
+    ) -> Result<Self, AuthenticatorError> {
+        let resource: ApiResource = sqlx::query_as(
+            "SELECT
+                id,
+                app_id,
+                identifier,
+                name,
+                scopes,
+                allowed_clients,
+                created_at
+            FROM api_resources
+            WHERE
+                identifier = $1",
+        )
+        .bind(identifier)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::ResourceNotFound)?;
+
+        Ok(resource)
+    }
+
+    /// Resource the client app is permitted to call
+    pub async fn select_permitted(
+        state: &AppState,
+        identifier: &str,
+        client: &App,
+    ) -> Result<Self, AuthenticatorError> {
+        let resource = Self::select_from_identifier(state, identifier).await?;
+
+        if !resource.permits(client) {
+            return Err(AuthenticatorError::Unauthorized);
+        }
+
+        Ok(resource)
+    }
+
+    pub async fn save(
+        &self,
+        state: &AppState,
+        id_session: &IdSession,
+    ) -> Result<Self, AuthenticatorError> {
+        let app = App::select_from_app_id(state, self.app_id).await?;
+
+        if !app.can_be_updated_by(id_session.user_id) {
+            return Err(AuthenticatorError::Unauthorized);
+        }
+
+        if self.is_new() {
+            let inserted_resource: ApiResource = sqlx::query_as(
+                "INSERT INTO api_resources (
+                    app_id,
+                    identifier,
+                    name,
+                    scopes,
+                    allowed_clients)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING
+                    id,
+                    app_id,
+                    identifier,
+                    name,
+                    scopes,
+                    allowed_clients,
+                    created_at",
+            )
+            .bind(self.app_id)
+            .bind(&self.identifier)
+            .bind(&self.name)
+            .bind(&self.scopes)
+            .bind(&self.allowed_clients)
+            .fetch_one(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Inserting resource {:?} -> {:?}", self, error);
+                AuthenticatorError::ResourceNotFound
+            })?;
+
+            Ok(inserted_resource)
+        } else {
+            let updated_resource: ApiResource = sqlx::query_as(
+                "UPDATE api_resources
+                SET
+                    identifier = $1,
+                    name = $2,
+                    scopes = $3,
+                    allowed_clients = $4
+                WHERE
+                    id = $5
+                    AND app_id = $6
+                RETURNING
+                    id,
+                    app_id,
+                    identifier,
+                    name,
+                    scopes,
+                    allowed_clients,
+                    created_at",
+            )
+            .bind(&self.identifier)
+            .bind(&self.name)
+            .bind(&self.scopes)
+            .bind(&self.allowed_clients)
+            .bind(self.id)
+            .bind(self.app_id)
+            .fetch_one(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Updating resource {:?} -> {:?}", self, error);
+                AuthenticatorError::ResourceNotFound
+            })?;
+
+            Ok(updated_resource)
+        }
+    }
+}
@@ -1,21 +1,25 @@
 use askama::Template;
 use askama_axum::IntoResponse;
-use axum::{
-    extract::{Query, State},
-    Form,
-};
+use axum::extract::{Query, State};
+use http::{HeaderMap, Method};
 use serde::Deserialize;
 use time::OffsetDateTime;
 
-use crate::{auth::IdSession, general::navbar::NavBarBlock, AppState};
+use crate::{
+    auth::{csrf::CsrfForm, reauth::redirect_to_reauthentication, IdSession},
+    general::navbar::NavBarBlock,
+    utils::crypto::random_token,
+    AppState,
+};
 
-use super::App;
+use super::{api_resource::ApiResource, native::ApplicationType, subject::SubjectType, App};
 
 #[derive(Template)]
 #[template(path = "apps/app_page.html")]
 pub struct AppPage {
     navbar: NavBarBlock,
     app: Option<App>,
+    resources: Vec<ApiResource>,
     read_only: bool,
 }
 
@@ -28,11 +32,18 @@ impl AppPage {
         }
     }
 
-    fn from_app(state: &AppState, id_session: &IdSession, app: Option<App>) -> Result<Self, Self> {
+    async fn from_app(
+        state: &AppState,
+        id_session: &IdSession,
+        app: Option<App>,
+    ) -> Result<Self, Self> {
         match app {
             Some(app) => Ok(AppPage {
                 navbar: NavBarBlock::from(state, Some(id_session.clone())),
                 app: Some(app.clone()),
+                resources: ApiResource::select_for_app(state, app.id)
+                    .await
+                    .unwrap_or_default(),
                 read_only: (!app.can_be_updated_by(id_session.user_id) && !app.is_new())
                     || (!App::can_be_created_by(state, id_session.mail.clone()) && app.is_new()),
             }),
@@ -40,6 +51,7 @@ impl AppPage {
             None => Ok(AppPage {
                 navbar: NavBarBlock::from(state, Some(id_session.clone())),
                 app: app.clone(),
+                resources: vec![],
                 read_only: !App::can_be_created_by(state, id_session.mail.clone()),
             }),
         }
@@ -51,15 +63,19 @@ impl AppPage {
         app_id: Option<i32>,
     ) -> Result<Self, Self> {
         match app_id {
-            Some(app_id) => Self::from_app(
-                state,
-                id_session,
-                App::select_from_app_id(&state, app_id).await.ok(),
-            ),
+            Some(app_id) => {
+                Self::from_app(
+                    state,
+                    id_session,
+                    App::select_from_app_id(&state, app_id).await.ok(),
+                )
+                .await
+            }
 
             None => Err(AppPage {
                 navbar: NavBarBlock::from(state, Some(id_session.clone())),
                 app: Some(App::new(&id_session.user_id)),
+                resources: vec![],
                 read_only: !App::can_be_created_by(state, id_session.mail.clone()),
             }),
         }
@@ -89,34 +105,73 @@ pub struct PostForm {
     logo_endpoint: Option<String>,
     jwt_secret: Option<String>,
     jwt_seconds_to_expire: Option<i32>,
+    subject_type: Option<SubjectType>,
+    token_exchange_clients: Option<String>,
+    application_type: Option<ApplicationType>,
+    private_use_schemes: Option<String>,
+    magic_link_allowed: Option<String>,
 }
 
 pub async fn post_handler(
+    headers: HeaderMap,
     id_session: IdSession,
     State(state): State<AppState>,
-    Form(form): Form<PostForm>,
+    CsrfForm(form): CsrfForm<PostForm>,
 ) -> impl IntoResponse {
     // Check if read only (= name is missing)
-    match form.name {
-        Some(name) => AppPage::from_app(
-            &state,
-            &id_session,
-            App {
-                id: form.id,
-                name,
-                description: form.description.unwrap_or("".to_owned()),
-                base_url: form.base_url.unwrap_or("".to_owned()),
-                redirect_endpoint: form.redirect_endpoint.unwrap_or("".to_owned()),
-                logo_endpoint: form.logo_endpoint.unwrap_or("".to_owned()),
-                jwt_secret: form.jwt_secret.unwrap_or("".to_owned()),
-                jwt_seconds_to_expire: form.jwt_seconds_to_expire.unwrap_or(0),
-                created_at: OffsetDateTime::now_utc(),
-                owner_id: Some(id_session.user_id),
-            }
-            .save(&state, &id_session)
+    let Some(name) = form.name else {
+        return AppPage::from_app_id(&state, &id_session, Some(form.id))
             .await
-            .ok(),
-        ),
-        None => AppPage::from_app_id(&state, &id_session, Some(form.id)).await,
+            .into_response();
+    };
+
+    let mut app = App {
+        id: form.id,
+        name,
+        description: form.description.unwrap_or("".to_owned()),
+        base_url: form.base_url.unwrap_or("".to_owned()),
+        redirect_endpoint: form.redirect_endpoint.unwrap_or("".to_owned()),
+        logo_endpoint: form.logo_endpoint.unwrap_or("".to_owned()),
+        jwt_secret: form.jwt_secret.unwrap_or("".to_owned()),
+        jwt_seconds_to_expire: form.jwt_seconds_to_expire.unwrap_or(0),
+        created_at: OffsetDateTime::now_utc(),
+        owner_id: Some(id_session.user_id),
+        subject_type: form.subject_type.unwrap_or_default(),
+        // Saved by the authenticator only, with the first pairwise subject
+        sector_identifier: "".to_owned(),
+        token_exchange_clients: form.token_exchange_clients.unwrap_or("".to_owned()),
+        application_type: form.application_type.unwrap_or_default(),
+        private_use_schemes: form.private_use_schemes.unwrap_or("".to_owned()),
+        magic_link_allowed: form.magic_link_allowed.is_some(),
+    };
+
+    let saved_app = App::select_from_app_id(&state, form.id).await.ok();
+
+    // Native apps can't keep a secret: their tokens are signed with one kept by the authenticator
+    if app.is_native() && app.jwt_secret.is_empty() {
+        app.jwt_secret = saved_app
+            .as_ref()
+            .map(|saved_app| saved_app.jwt_secret.clone())
+            .filter(|jwt_secret| !jwt_secret.is_empty())
+            .unwrap_or_else(random_token);
     }
+
+    // Changing the secret or the redirection of an existing app is a sensitive action
+    if !id_session.is_recently_authenticated() {
+        let has_sensitive_changes = saved_app
+            .as_ref()
+            .is_some_and(|saved_app| saved_app.has_sensitive_changes(&app));
+
+        if has_sensitive_changes {
+            return redirect_to_reauthentication(&state, &Method::POST, &headers, None);
+        }
+    }
+
+    AppPage::from_app(
+        &state,
+        &id_session,
+        app.save(&state, &id_session).await.ok(),
+    )
+    .await
+    .into_response()
 }
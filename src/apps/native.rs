@@ -0,0 +1,87 @@
+use serde::Deserialize;
+use url::{Host, Url};
+
+/// web = confidential app running on a server
+/// native = desktop or mobile app receiving the response on a loopback or private-use uri
+/// https://www.rfc-editor.org/rfc/rfc8252.html
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum ApplicationType {
+    #[default]
+    Web,
+    Native,
+}
+
+/// Loopback interface redirection with any port (http://127.0.0.1:{port} or http://[::1]:{port})
+/// https://www.rfc-editor.org/rfc/rfc8252.html#section-7.3
+pub fn is_loopback(redirect_uri: &Url) -> bool {
+    let is_loopback_ip = match redirect_uri.host() {
+        Some(Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(Host::Ipv6(ip)) => ip.is_loopback(),
+        _ => false,
+    };
+
+    redirect_uri.scheme() == "http" && is_loopback_ip
+}
+
+/// Private-use uri scheme based on a domain name of the app (ex: com.example.app:/callback)
+/// https://www.rfc-editor.org/rfc/rfc8252.html#section-7.1
+pub fn uses_private_scheme(redirect_uri: &Url, private_use_schemes: &str) -> bool {
+    let scheme = redirect_uri.scheme();
+
+    scheme.contains('.')
+        && private_use_schemes
+            .split_whitespace()
+            .any(|registered| registered.eq_ignore_ascii_case(scheme))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn loopback_ips_with_any_port_are_loopback() {
+        assert!(is_loopback(&url("http://127.0.0.1:51004/callback")));
+        assert!(is_loopback(&url("http://127.0.0.1/callback")));
+        assert!(is_loopback(&url("http://[::1]:8080/callback")));
+    }
+
+    #[test]
+    fn other_hosts_and_schemes_are_not_loopback() {
+        assert!(!is_loopback(&url("http://localhost:8080/callback")));
+        assert!(!is_loopback(&url("https://127.0.0.1/callback")));
+        assert!(!is_loopback(&url("http://10.0.0.1/callback")));
+        assert!(!is_loopback(&url("http://127.0.0.1.evil.com/callback")));
+        assert!(!is_loopback(&url("com.example.app:/callback")));
+    }
+
+    #[test]
+    fn private_schemes_must_be_registered_domain_names() {
+        let registered = "com.example.app  org.example.other";
+
+        assert!(uses_private_scheme(
+            &url("com.example.app:/callback"),
+            registered
+        ));
+        assert!(uses_private_scheme(
+            &url("ORG.example.other:/callback"),
+            registered
+        ));
+
+        assert!(!uses_private_scheme(
+            &url("com.example.evil:/callback"),
+            registered
+        ));
+        assert!(!uses_private_scheme(&url("myapp:/callback"), "myapp"));
+        assert!(!uses_private_scheme(
+            &url("https://example.com/callback"),
+            "https"
+        ));
+        assert!(!uses_private_scheme(&url("com.example.app:/callback"), ""));
+    }
+}
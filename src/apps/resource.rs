@@ -0,0 +1,99 @@
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::extract::{Query, State};
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::{
+    auth::{csrf::CsrfForm, IdSession},
+    general::navbar::NavBarBlock,
+    AppState,
+};
+
+use super::{api_resource::ApiResource, App};
+
+#[derive(Template)]
+#[template(path = "apps/resource_page.html")]
+pub struct ResourcePage {
+    navbar: NavBarBlock,
+    app: App,
+    resource: Option<ApiResource>,
+}
+
+impl ResourcePage {
+    async fn from_resource(
+        state: &AppState,
+        id_session: &IdSession,
+        resource: Option<ApiResource>,
+    ) -> Result<Self, Self> {
+        let app = match &resource {
+            Some(resource) => App::select_from_app_id(state, resource.app_id).await.ok(),
+            None => None,
+        };
+
+        match app {
+            Some(app) if app.can_be_updated_by(id_session.user_id) => Ok(ResourcePage {
+                navbar: NavBarBlock::from(state, Some(id_session.clone())),
+                app,
+                resource,
+            }),
+
+            _ => Err(ResourcePage {
+                navbar: NavBarBlock::from(state, Some(id_session.clone())),
+                app: state.authenticator_app.clone(),
+                resource: None,
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+    id: Option<i32>,
+    app_id: Option<i32>,
+}
+
+pub async fn get_handler(
+    id_session: IdSession,
+    State(state): State<AppState>,
+    Query(params): Query<QueryParams>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let resource = match (params.id, params.app_id) {
+        (Some(id), _) => ApiResource::select_from_id(&state, id).await.ok(),
+        (None, Some(app_id)) => Some(ApiResource::new(app_id)),
+        (None, None) => None,
+    };
+
+    ResourcePage::from_resource(&state, &id_session, resource).await
+}
+
+#[derive(Deserialize)]
+pub struct PostForm {
+    id: i32,
+    app_id: i32,
+    identifier: String,
+    name: String,
+    scopes: Option<String>,
+    allowed_clients: Option<String>,
+}
+
+pub async fn post_handler(
+    id_session: IdSession,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<PostForm>,
+) -> impl IntoResponse {
+    let resource = ApiResource {
+        id: form.id,
+        app_id: form.app_id,
+        identifier: form.identifier,
+        name: form.name,
+        scopes: form.scopes.unwrap_or("".to_owned()),
+        allowed_clients: form.allowed_clients.unwrap_or("".to_owned()),
+        created_at: OffsetDateTime::now_utc(),
+    }
+    .save(&state, &id_session)
+    .await
+    .ok();
+
+    ResourcePage::from_resource(&state, &id_session, resource).await
+}
@@ -0,0 +1,104 @@
+use serde::Deserialize;
+use sqlx::types::Uuid;
+use tracing::log::error;
+
+use crate::{general::AuthenticatorError, utils::crypto::hash_text, AppState};
+
+use super::App;
+
+/// public = the raw user id is sent to the app
+/// pairwise = each sector (host of the redirect url when the first subject is given) receives its own stable identifier for the same user
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum SubjectType {
+    #[default]
+    Public,
+    Pairwise,
+}
+
+/// Subject identifier (sub claim) of a user for a given app
+pub struct Subject;
+
+impl Subject {
+    /// Give the sub of the user for the app
+    /// Pairwise subjects are saved so that they can be mapped back to the user
+    pub async fn for_user(
+        state: &AppState,
+        app: &App,
+        user_id: &Uuid,
+    ) -> Result<String, AuthenticatorError> {
+        if app.subject_type == SubjectType::Public {
+            return Ok(user_id.to_string());
+        }
+
+        let sector_identifier = app.sector_identifier(state).await?;
+
+        // Separated so that no other sector and user can give the same text
+        let subject = hash_text(&format!(
+            "{}:{}|{}|{}",
+            sector_identifier.len(),
+            sector_identifier,
+            user_id,
+            state.pairwise_salt
+        ));
+
+        sqlx::query(
+            "INSERT INTO pairwise_subjects (
+                sector_identifier,
+                subject,
+                user_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(&sector_identifier)
+        .bind(&subject)
+        .bind(user_id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Saving pairwise subject of {} for sector {} -> {:?}",
+                user_id, sector_identifier, error
+            );
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(subject)
+    }
+
+    /// Map back the sub received from the app to the user id
+    pub async fn user_id(
+        state: &AppState,
+        app: &App,
+        subject: &str,
+    ) -> Result<Uuid, AuthenticatorError> {
+        if app.subject_type == SubjectType::Public {
+            return Uuid::parse_str(subject).map_err(|_| AuthenticatorError::InvalidUserId);
+        }
+
+        let sector_identifier = app.sector_identifier(state).await?;
+
+        let (user_id,): (Uuid,) = sqlx::query_as(
+            "SELECT 
+                user_id
+            FROM pairwise_subjects
+            WHERE 
+                sector_identifier = $1
+                AND subject = $2",
+        )
+        .bind(&sector_identifier)
+        .bind(subject)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Selecting pairwise subject {} for sector {} -> {:?}",
+                subject, sector_identifier, error
+            );
+            AuthenticatorError::InvalidUserId
+        })?;
+
+        Ok(user_id)
+    }
+}
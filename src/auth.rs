@@ -1,34 +1,57 @@
+pub mod assurance;
+pub mod cookies;
+pub mod csrf;
+pub mod lockout;
+pub mod magic_link;
+pub mod mail_code;
+pub mod passkey;
+pub mod password_reset;
+pub mod reauth;
+pub mod recovery;
+pub mod second_factor;
+pub mod session;
 pub mod signin;
 pub mod signout;
 pub mod signup;
+pub mod totp;
+pub mod webauthn;
 
 use askama_axum::IntoResponse;
 use axum::extract::{FromRef, FromRequestParts, Request};
 use axum::response::Redirect;
 use axum::{async_trait, RequestPartsExt};
-use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::CookieJar;
 use core::fmt::Debug;
 use http::request::Parts;
+use http::HeaderMap;
 use sqlx::types::Uuid;
 use time::{Date, Duration, OffsetDateTime};
 use tracing::log::error;
 
 use crate::general::message::{Level, MessageBlock};
+
+use self::assurance::Authentication;
+use self::session::Session;
 use crate::general::AuthenticatorError;
 use crate::users::User;
-use crate::utils::jwt::TokenFactory;
+use crate::utils::crypto::random_token;
 use crate::AppState;
 
-const SESSION_TOKEN: &str = "session_token";
+/// Opaque id of the session saved by the authenticator
+const SESSION_ID: &str = "session_id";
+/// Changes at each sign in and is removed at sign out
+/// Readable by the check session iframe (OpenID Connect Session Management)
+pub const BROWSER_STATE: &str = "browser_state";
 
 #[derive(Clone, Debug)]
 pub struct IdSession {
+    pub session_id: Uuid,
     pub user_id: Uuid,
     pub name: String,
     pub mail: String,
     pub avatar: String,
     pub birthday: Date,
+    pub authentication: Authentication,
     pub seconds_to_expire: i64,
 }
 
@@ -63,75 +86,130 @@ where
             )
         })?;
 
-        let id_session = Self::extract(state.clone(), cookie_jar).map_err(|error| {
-            signin::SigninPage::for_app_with_redirect_and_message(
-                state.authenticator_app.clone(),
-                Some(request_uri.to_string()),
-                MessageBlock::new(Level::Error, "", &error.to_string()),
-            )
-        })?;
+        let id_session = Self::extract(state.clone(), cookie_jar)
+            .await
+            .map_err(|error| {
+                signin::SigninPage::for_app_with_redirect_and_message(
+                    state.authenticator_app.clone(),
+                    Some(request_uri.to_string()),
+                    MessageBlock::new(Level::Error, "", &error.to_string()),
+                )
+            })?;
 
         Ok(id_session)
     }
 }
 
 impl IdSession {
-    pub fn remove_and_redirect_to(cookies: CookieJar, redirect_to: &str) -> impl IntoResponse {
+    pub async fn remove_and_redirect_to(
+        state: &AppState,
+        cookies: CookieJar,
+        redirect_to: &str,
+    ) -> impl IntoResponse {
+        if let Some(session_id) = cookies.get(&state.cookie_policy.name(SESSION_ID)) {
+            let _ = Session::delete_from_session_id(state, session_id.value()).await;
+        }
+
         (
             cookies
                 .clone()
-                .remove(Cookie::build(SESSION_TOKEN).path("/")),
+                .remove(state.cookie_policy.removal(SESSION_ID))
+                .remove(state.cookie_policy.cross_site_removal(BROWSER_STATE)),
             Redirect::to(redirect_to),
         )
     }
 
-    fn extract(state: AppState, cookies: CookieJar) -> Result<Self, AuthenticatorError> {
-        let token = cookies
-            .get(SESSION_TOKEN)
+    /// The user proved their identity a short time ago (needed for the sensitive actions)
+    pub fn is_recently_authenticated(&self) -> bool {
+        OffsetDateTime::now_utc().unix_timestamp() - self.authentication.time
+            <= reauth::RECENT_AUTHENTICATION_SECONDS
+    }
+
+    async fn extract(state: AppState, cookies: CookieJar) -> Result<Self, AuthenticatorError> {
+        let session_id = cookies
+            .get(&state.cookie_policy.name(SESSION_ID))
             .ok_or(AuthenticatorError::InvalidToken)?;
 
-        let id_claims = TokenFactory::for_authenticator(&state)
-            .extract_id_token(token.value().to_string())?
-            .claims;
+        let session = Session::select_from_session_id(&state, session_id.value()).await?;
 
-        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let user = User::select_from_id(&state.db_pool, session.user_id)
+            .await
+            .map_err(|_| AuthenticatorError::InvalidToken)?;
 
         Ok(IdSession {
-            user_id: id_claims.user_id(),
-            name: id_claims.name,
-            mail: id_claims.mail,
-            avatar: id_claims.avatar,
-            birthday: id_claims.birthday,
-            seconds_to_expire: id_claims.exp - now,
+            session_id: session.id,
+            user_id: user.id,
+            name: user.name,
+            mail: user.mail,
+            avatar: user.avatar_url,
+            birthday: user.birthday,
+            authentication: session.authentication(),
+            seconds_to_expire: (session.expires_at - OffsetDateTime::now_utc()).whole_seconds(),
         })
     }
 
-    pub fn set_with_redirect_to_endpoint(
+    /// A new session is always created at sign in, the one the browser had before is revoked
+    /// so that a session id planted in the browser can't be used once signed in
+    pub async fn set_with_redirect_to_endpoint(
         cookies: CookieJar,
+        headers: &HeaderMap,
         state: &AppState,
         user: &User,
+        authentication: &Authentication,
         requested_endpoint: Option<String>,
     ) -> Result<impl IntoResponse, AuthenticatorError> {
-        let session_duration = state.authenticator_app.jwt_seconds_to_expire.clone();
-
-        let id_token = TokenFactory::for_authenticator(state).generate_id_token(user)?;
+        if let Some(previous_session_id) = cookies.get(&state.cookie_policy.name(SESSION_ID)) {
+            Session::delete_from_session_id(state, previous_session_id.value()).await?;
+        }
 
-        let secure_domain = state.authenticator_app.domain()?;
+        let session_id = Session::create(state, user.id, authentication, headers).await?;
 
-        let cookie = Cookie::build((SESSION_TOKEN, id_token.token))
-            .domain(secure_domain)
-            .path("/")
-            .secure(true)
-            .http_only(true)
-            .max_age(Duration::seconds(session_duration.into()));
+        let session_duration = state.authenticator_app.jwt_seconds_to_expire.clone();
 
         let redirect = state
             .authenticator_app
             .redirect_to_endpoint(requested_endpoint)
             .clone();
 
-        let response_with_session_cookie = (cookies.add(cookie), redirect);
+        let response_with_session_cookie = (
+            Self::session_cookies(state, cookies, session_id, session_duration.into()),
+            redirect,
+        );
 
         Ok(response_with_session_cookie)
     }
+
+    /// Give the session a new id (ex: after a password or role change), the old one stops working
+    pub async fn rotate(
+        &self,
+        state: &AppState,
+        cookies: CookieJar,
+    ) -> Result<CookieJar, AuthenticatorError> {
+        let session_id = Session::rotate(state, self.session_id, self.user_id).await?;
+
+        Ok(Self::session_cookies(
+            state,
+            cookies,
+            session_id,
+            self.seconds_to_expire,
+        ))
+    }
+
+    fn session_cookies(
+        state: &AppState,
+        cookies: CookieJar,
+        session_id: String,
+        seconds_to_expire: i64,
+    ) -> CookieJar {
+        let mut cookie = state.cookie_policy.cookie(SESSION_ID, session_id);
+        cookie.set_max_age(Duration::seconds(seconds_to_expire));
+
+        // Read by the check session iframe embedded in the apps
+        let mut browser_state_cookie = state
+            .cookie_policy
+            .cross_site_cookie(BROWSER_STATE, random_token());
+        browser_state_cookie.set_max_age(Duration::seconds(seconds_to_expire));
+
+        cookies.add(cookie).add(browser_state_cookie)
+    }
 }
@@ -0,0 +1,182 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{users::User, AppState};
+
+use super::{totp::Totp, webauthn::WebauthnCredential};
+
+/// Authentication methods references (amr claim)
+/// https://www.rfc-editor.org/rfc/rfc8176.html
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    Pwd,
+    Otp,
+    Hwk,
+    Mfa,
+    /// Proof of possession of the mail address (sign in link), not registered in RFC 8176
+    Mail,
+    /// Printed recovery code, not registered in RFC 8176
+    Recovery,
+}
+
+impl AuthMethod {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pwd" => Some(AuthMethod::Pwd),
+            "otp" => Some(AuthMethod::Otp),
+            "hwk" => Some(AuthMethod::Hwk),
+            "mfa" => Some(AuthMethod::Mfa),
+            "mail" => Some(AuthMethod::Mail),
+            "recovery" => Some(AuthMethod::Recovery),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AuthMethod::Pwd => "pwd",
+            AuthMethod::Otp => "otp",
+            AuthMethod::Hwk => "hwk",
+            AuthMethod::Mfa => "mfa",
+            AuthMethod::Mail => "mail",
+            AuthMethod::Recovery => "recovery",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Authentication context class reference (acr claim)
+/// 1 = single factor, 2 = multi factor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AuthLevel {
+    SingleFactor,
+    MultiFactor,
+}
+
+impl AuthLevel {
+    pub fn from_acr(acr: &str) -> Option<Self> {
+        match acr {
+            "1" => Some(AuthLevel::SingleFactor),
+            "2" => Some(AuthLevel::MultiFactor),
+            _ => None,
+        }
+    }
+
+    /// Best level the user can reach with the methods they have set up
+    pub async fn available_for(state: &AppState, user: &User) -> Self {
+        let has_totp = Totp::is_enabled(state, user.id).await.unwrap_or(false);
+        let has_passkeys = WebauthnCredential::exists_for_user(state, user.id)
+            .await
+            .unwrap_or(false);
+
+        if has_totp || has_passkeys {
+            AuthLevel::MultiFactor
+        } else {
+            AuthLevel::SingleFactor
+        }
+    }
+
+    /// Check if the level meets one of the acr values requested by an app
+    pub fn satisfies(&self, acr_values: &str) -> bool {
+        let requested_levels: Vec<AuthLevel> = acr_values
+            .split_whitespace()
+            .filter_map(AuthLevel::from_acr)
+            .collect();
+
+        requested_levels.is_empty()
+            || requested_levels
+                .iter()
+                .any(|requested_level| self >= requested_level)
+    }
+}
+
+impl fmt::Display for AuthLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let acr = match self {
+            AuthLevel::SingleFactor => "1",
+            AuthLevel::MultiFactor => "2",
+        };
+
+        write!(f, "{}", acr)
+    }
+}
+
+/// How and when the user proved his identity
+#[derive(Clone, Debug, Default)]
+pub struct Authentication {
+    pub methods: Vec<AuthMethod>,
+    pub time: i64,
+}
+
+impl Authentication {
+    pub fn now(methods: Vec<AuthMethod>) -> Self {
+        Self {
+            methods,
+            time: OffsetDateTime::now_utc().unix_timestamp(),
+        }
+    }
+
+    /// Read methods saved as a space separated list
+    pub fn from_saved(methods: &str, time: i64) -> Self {
+        Self {
+            methods: methods
+                .split_whitespace()
+                .filter_map(AuthMethod::from_name)
+                .collect(),
+            time,
+        }
+    }
+
+    /// Methods as a space separated list
+    pub fn saved_methods(&self) -> String {
+        self.methods
+            .iter()
+            .map(|method| method.to_string())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    pub fn level(&self) -> AuthLevel {
+        let factors = self
+            .methods
+            .iter()
+            .filter(|method| **method != AuthMethod::Mfa)
+            .count();
+
+        if factors > 1 || self.methods.contains(&AuthMethod::Mfa) {
+            AuthLevel::MultiFactor
+        } else {
+            AuthLevel::SingleFactor
+        }
+    }
+
+    /// amr claim, with mfa added when several factors were used
+    pub fn amr(&self) -> Vec<AuthMethod> {
+        let mut amr = self.methods.clone();
+
+        if self.level() == AuthLevel::MultiFactor && !amr.contains(&AuthMethod::Mfa) {
+            amr.push(AuthMethod::Mfa);
+        }
+
+        amr
+    }
+
+    /// acr claim, none if the user did not authenticate
+    pub fn acr(&self) -> Option<String> {
+        if self.methods.is_empty() {
+            None
+        } else {
+            Some(self.level().to_string())
+        }
+    }
+
+    pub fn satisfies(&self, acr_values: &str) -> bool {
+        self.level().satisfies(acr_values)
+    }
+}
@@ -0,0 +1,85 @@
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use shuttle_runtime::SecretStore;
+
+/// Prefix making the browser refuse the cookie unless it is secure, host only and on every path
+/// https://datatracker.ietf.org/doc/html/draft-ietf-httpbis-rfc6265bis#name-the-__host-prefix
+const HOST_PREFIX: &str = "__Host-";
+const DEVELOPMENT_HOSTS: [&str; 2] = ["http://localhost", "http://127.0.0.1"];
+
+/// How the cookies of the authenticator are set
+/// The development mode (plain http on localhost) has to be asked for explicitly with DEV_MODE
+#[derive(Clone, Debug)]
+pub struct CookiePolicy {
+    is_development: bool,
+    same_site: SameSite,
+}
+
+impl CookiePolicy {
+    pub fn from_secrets(secrets: &SecretStore) -> Self {
+        let is_development = secrets
+            .get("DEV_MODE")
+            .is_some_and(|dev_mode| dev_mode == "true");
+
+        let app_url = secrets.get("APP_URL").unwrap_or_default();
+
+        if is_development
+            && !DEVELOPMENT_HOSTS
+                .iter()
+                .any(|host| app_url.starts_with(host))
+        {
+            panic!("DEV_MODE can only be used with an APP_URL on http://localhost");
+        }
+
+        let same_site = match secrets.get("COOKIES_SAME_SITE").as_deref() {
+            Some("strict") => SameSite::Strict,
+            Some("none") if !is_development => SameSite::None,
+            _ => SameSite::Lax,
+        };
+
+        Self {
+            is_development,
+            same_site,
+        }
+    }
+
+    /// Name given to the browser, prefixed when the cookies are secure
+    pub fn name(&self, name: &str) -> String {
+        if self.is_development {
+            name.to_owned()
+        } else {
+            format!("{}{}", HOST_PREFIX, name)
+        }
+    }
+
+    /// Host only cookie on every path, not readable by scripts
+    pub fn cookie(&self, name: &str, value: String) -> Cookie<'static> {
+        Cookie::build((self.name(name), value))
+            .path("/")
+            .secure(!self.is_development)
+            .http_only(true)
+            .same_site(self.same_site)
+            .build()
+    }
+
+    /// Cookie read by the check session iframe embedded in the apps (other sites)
+    pub fn cross_site_cookie(&self, name: &str, value: String) -> Cookie<'static> {
+        let mut cookie = self.cookie(name, value);
+        cookie.set_http_only(false);
+
+        // SameSite=None is refused by the browsers without a secure cookie
+        if !self.is_development {
+            cookie.set_same_site(SameSite::None);
+        }
+
+        cookie
+    }
+
+    /// Same attributes as the cookie to remove: __Host- cookies are only replaced by secure ones
+    pub fn removal(&self, name: &str) -> Cookie<'static> {
+        self.cookie(name, String::new())
+    }
+
+    pub fn cross_site_removal(&self, name: &str) -> Cookie<'static> {
+        self.cross_site_cookie(name, String::new())
+    }
+}
@@ -0,0 +1,136 @@
+use askama::Template;
+use askama_axum::{IntoResponse, Response};
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRef, FromRequest, Request, State},
+    middleware::Next,
+};
+use axum_extra::extract::CookieJar;
+use http::StatusCode;
+use serde::de::DeserializeOwned;
+use url::form_urlencoded;
+
+use crate::{
+    utils::crypto::{random_token, secrets_match},
+    AppState,
+};
+
+/// Random value of the browser, every form it posts must contain it (double submit)
+const CSRF_TOKEN: &str = "csrf_token";
+
+tokio::task_local! {
+    /// Token of the browser making the current request, rendered in the forms
+    static REQUEST_CSRF_TOKEN: String;
+}
+
+/// Give the browser a token if it has none, and make it available to the templates
+pub async fn token_middleware(
+    State(state): State<AppState>,
+    cookies: CookieJar,
+    request: Request,
+    next: Next,
+) -> Response {
+    let saved_token = cookies
+        .get(&state.cookie_policy.name(CSRF_TOKEN))
+        .map(|cookie| cookie.value().to_owned())
+        .filter(|token| !token.is_empty());
+
+    match saved_token {
+        Some(token) => REQUEST_CSRF_TOKEN.scope(token, next.run(request)).await,
+
+        None => {
+            let token = random_token();
+
+            let response = REQUEST_CSRF_TOKEN
+                .scope(token.clone(), next.run(request))
+                .await;
+
+            (
+                cookies.add(state.cookie_policy.cookie(CSRF_TOKEN, token)),
+                response,
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Called by the templates: {% include "general/csrf_field.html" %}
+pub fn csrf_token() -> String {
+    REQUEST_CSRF_TOKEN
+        .try_with(|token| token.clone())
+        .unwrap_or_default()
+}
+
+/// Shown when a form doesn't come from a page of the authenticator (or a very old one)
+#[derive(Template)]
+#[template(path = "general/csrf_error_page.html")]
+pub struct CsrfErrorPage;
+
+/// Form posted from a page of the authenticator, the token is checked before reading it
+pub struct CsrfForm<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for CsrfForm<T>
+where
+    T: DeserializeOwned,
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let form = checked_form(request, state).await?;
+
+        serde_urlencoded::from_bytes(&form)
+            .map(CsrfForm)
+            .map_err(|error| (StatusCode::UNPROCESSABLE_ENTITY, error.to_string()).into_response())
+    }
+}
+
+/// For the forms with nothing else than the token
+pub struct CsrfChecked;
+
+#[async_trait]
+impl<S> FromRequest<S> for CsrfChecked
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        checked_form(request, state).await.map(|_| CsrfChecked)
+    }
+}
+
+/// Body of the form once its token has been compared to the one of the cookie
+async fn checked_form<S>(request: Request, state: &S) -> Result<Bytes, Response>
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    let state = AppState::from_ref(state);
+
+    let cookies = CookieJar::from_headers(request.headers());
+
+    let cookie_token = cookies
+        .get(&state.cookie_policy.name(CSRF_TOKEN))
+        .map(|cookie| cookie.value().to_owned())
+        .unwrap_or_default();
+
+    let form = Bytes::from_request(request, &state)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    let form_token = form_urlencoded::parse(&form)
+        .find(|(name, _)| name == CSRF_TOKEN)
+        .map(|(_, token)| token.into_owned())
+        .unwrap_or_default();
+
+    if cookie_token.is_empty() || !secrets_match(&cookie_token, &form_token) {
+        return Err((StatusCode::FORBIDDEN, CsrfErrorPage).into_response());
+    }
+
+    Ok(form)
+}
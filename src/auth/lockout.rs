@@ -0,0 +1,390 @@
+use askama_axum::{IntoResponse, Template};
+use axum::extract::{Query, State};
+use serde::Deserialize;
+use sqlx::types::{time::OffsetDateTime, Uuid};
+use time::Duration;
+use tracing::log::error;
+
+use crate::{
+    apps::App,
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    users::User,
+    utils::crypto::{hash_text, random_token},
+    AppState,
+};
+
+use super::{csrf::CsrfForm, signin::SigninPage};
+
+/// Failures older than this are forgotten
+const FAILURES_FORGOTTEN_AFTER_SECONDS: i64 = 86400;
+const MAX_DELAY_SECONDS: i64 = 300;
+const UNLOCK_LINK_SECONDS_TO_EXPIRE: i64 = 86400;
+
+/// Who the failed sign ins are counted for
+#[derive(Clone, Copy, Debug, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum FailureKind {
+    /// Attempts against one mail, from anywhere
+    Account,
+    /// Attempts from one client, against any mail
+    Ip,
+}
+
+impl FailureKind {
+    /// Failures allowed before the attempts get delayed
+    fn free_failures(&self) -> i32 {
+        match self {
+            FailureKind::Account => 3,
+            FailureKind::Ip => 10,
+        }
+    }
+
+    fn lock_failures(&self) -> i32 {
+        match self {
+            FailureKind::Account => 10,
+            FailureKind::Ip => 50,
+        }
+    }
+
+    fn lock_seconds(&self) -> i64 {
+        match self {
+            FailureKind::Account => 1800,
+            FailureKind::Ip => 3600,
+        }
+    }
+
+    /// Exponential back off (1s, 2s, 4s...) then a temporary lockout
+    fn blocking_seconds(&self, failures: i32) -> Option<i64> {
+        if failures >= self.lock_failures() {
+            return Some(self.lock_seconds());
+        }
+
+        let delayed_failures = failures - self.free_failures();
+
+        if delayed_failures < 0 {
+            return None;
+        }
+
+        Some(
+            2i64.checked_pow(delayed_failures as u32)
+                .map_or(MAX_DELAY_SECONDS, |delay| delay.min(MAX_DELAY_SECONDS)),
+        )
+    }
+}
+
+/// Failed sign ins counted per account and per client ip address
+/// The account is found from the hash of the mail, so unknown mails are delayed the same way
+pub struct SigninLockout {
+    account: String,
+    ip_address: String,
+}
+
+impl SigninLockout {
+    pub fn new(mail: &str, ip_address: String) -> Self {
+        Self {
+            account: account_subject(mail),
+            ip_address,
+        }
+    }
+
+    /// Refuse the attempt without checking the password while the account or the client is blocked
+    pub async fn check(&self, state: &AppState) -> Result<(), AuthenticatorError> {
+        let blocked: Vec<(FailureKind, i32)> = sqlx::query_as(
+            "SELECT
+                kind,
+                failures
+            FROM signin_failures
+            WHERE
+                ((kind = $1 AND subject = $2) OR (kind = $3 AND subject = $4))
+                AND blocked_until > CURRENT_TIMESTAMP",
+        )
+        .bind(FailureKind::Account)
+        .bind(&self.account)
+        .bind(FailureKind::Ip)
+        .bind(&self.ip_address)
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Selecting signin failures -> {:?}", error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        let is_account_locked = blocked.iter().any(|(kind, failures)| {
+            *kind == FailureKind::Account && *failures >= kind.lock_failures()
+        });
+
+        if is_account_locked {
+            Err(AuthenticatorError::AccountLocked)
+        } else if !blocked.is_empty() {
+            Err(AuthenticatorError::SigninDelayed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Count the failure for the account and the client, true if the account has just been locked
+    pub async fn record_failure(&self, state: &AppState) -> Result<bool, AuthenticatorError> {
+        let account_failures =
+            Self::add_failure(state, FailureKind::Account, &self.account).await?;
+
+        // Without the address of the client only the account is protected
+        if !self.ip_address.is_empty() {
+            Self::add_failure(state, FailureKind::Ip, &self.ip_address).await?;
+        }
+
+        Ok(account_failures == FailureKind::Account.lock_failures())
+    }
+
+    /// The right password was given, previous failures of the account are forgotten
+    /// (the ones of the client are kept, an attacker may own one of the accounts)
+    pub async fn record_success(&self, state: &AppState) -> Result<(), AuthenticatorError> {
+        Self::forget_account(state, &self.account).await
+    }
+
+    async fn add_failure(
+        state: &AppState,
+        kind: FailureKind,
+        subject: &str,
+    ) -> Result<i32, AuthenticatorError> {
+        let now = OffsetDateTime::now_utc();
+
+        let failures: i32 = sqlx::query_scalar(
+            "INSERT INTO signin_failures (
+                kind,
+                subject,
+                failures,
+                last_failure_at)
+            VALUES ($1, $2, 1, $3)
+            ON CONFLICT (kind, subject) DO UPDATE
+            SET
+                failures = CASE
+                    WHEN signin_failures.last_failure_at < $4 THEN 1
+                    ELSE signin_failures.failures + 1
+                END,
+                last_failure_at = $3
+            RETURNING
+                failures",
+        )
+        .bind(kind)
+        .bind(subject)
+        .bind(now)
+        .bind(now - Duration::seconds(FAILURES_FORGOTTEN_AFTER_SECONDS))
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Counting signin failure -> {:?}", error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        if let Some(blocking_seconds) = kind.blocking_seconds(failures) {
+            sqlx::query(
+                "UPDATE signin_failures
+                SET
+                    blocked_until = $3
+                WHERE
+                    kind = $1
+                    AND subject = $2",
+            )
+            .bind(kind)
+            .bind(subject)
+            .bind(now + Duration::seconds(blocking_seconds))
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Blocking signins -> {:?}", error);
+                AuthenticatorError::DatabaseError
+            })?;
+        }
+
+        Ok(failures)
+    }
+
+    async fn forget_account(state: &AppState, account: &str) -> Result<(), AuthenticatorError> {
+        sqlx::query(
+            "DELETE FROM signin_failures
+            WHERE
+                (kind = $1 AND subject = $2)
+                OR last_failure_at < $3",
+        )
+        .bind(FailureKind::Account)
+        .bind(account)
+        .bind(OffsetDateTime::now_utc() - Duration::seconds(FAILURES_FORGOTTEN_AFTER_SECONDS))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Deleting signin failures -> {:?}", error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(())
+    }
+
+    /// Tell the user that their account is locked, with a link to unlock it
+    pub async fn send_unlock_link(state: AppState, user: User) -> Result<bool, AuthenticatorError> {
+        let token = UnlockLink::generate(&state, user.id).await?;
+
+        let unlock_url = format!(
+            "{}/unlock?token={}",
+            state.authenticator_app.base_url, token
+        );
+
+        let mail_subject = format!("Votre compte {} est bloqué", state.authenticator_app.name);
+
+        let mail_body = format!(
+            "Bonjour {},
+
+Après plusieurs tentatives de connexion avec un mauvais mot de passe, votre compte a été bloqué pendant 30 minutes.
+Si c'était vous, vous pouvez le débloquer dès maintenant en cliquant sur le lien suivant :
+{}
+
+Si ce n'était pas vous, quelqu'un essaie de deviner votre mot de passe : nous vous conseillons d'en choisir un plus robuste
+et d'activer la vérification en deux étapes depuis votre profil.
+
+En vous souhaitant une excellente journée !!
+
+L'équipe de Brouclean Softwares",
+            user.name, unlock_url
+        );
+
+        state.mailer.send_mail(
+            format!("{} <{}>", user.name, user.mail),
+            mail_subject,
+            mail_body,
+        )
+    }
+}
+
+/// Accounts are counted without saving the mails that were tried
+fn account_subject(mail: &str) -> String {
+    hash_text(&mail.trim().to_lowercase())
+}
+
+/// Single use link to unlock an account (only its hash is saved)
+struct UnlockLink;
+
+impl UnlockLink {
+    async fn generate(state: &AppState, user_id: Uuid) -> Result<String, AuthenticatorError> {
+        let token = random_token();
+
+        sqlx::query(
+            "DELETE FROM unlock_links
+            WHERE
+                user_id = $1
+                OR expires_at < CURRENT_TIMESTAMP",
+        )
+        .bind(user_id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Deleting unlock links of {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        sqlx::query(
+            "INSERT INTO unlock_links (
+                hashed_token,
+                user_id,
+                expires_at)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(hash_text(&token))
+        .bind(user_id)
+        .bind(OffsetDateTime::now_utc() + Duration::seconds(UNLOCK_LINK_SECONDS_TO_EXPIRE))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Inserting unlock link for {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(token)
+    }
+
+    /// Get the user of the link and delete it so that it can't be used twice
+    async fn consume(state: &AppState, token: &str) -> Result<Uuid, AuthenticatorError> {
+        let (user_id, expires_at): (Uuid, OffsetDateTime) = sqlx::query_as(
+            "DELETE FROM unlock_links
+            WHERE
+                hashed_token = $1
+            RETURNING
+                user_id,
+                expires_at",
+        )
+        .bind(hash_text(token))
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidUnlockLink)?;
+
+        if expires_at < OffsetDateTime::now_utc() {
+            return Err(AuthenticatorError::InvalidUnlockLink);
+        }
+
+        Ok(user_id)
+    }
+}
+
+/// Confirmation before unlocking so that mail scanners opening the link don't consume it
+#[derive(Template)]
+#[template(path = "auth/unlock_page.html")]
+pub struct UnlockPage {
+    token: String,
+    app: App,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockParams {
+    token: Option<String>,
+}
+
+pub async fn get_handler(
+    State(state): State<AppState>,
+    Query(params): Query<UnlockParams>,
+) -> impl IntoResponse {
+    UnlockPage {
+        token: params.token.unwrap_or_default(),
+        app: state.authenticator_app.clone(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    token: String,
+}
+
+pub async fn post_handler(
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<UnlockForm>,
+) -> Result<SigninPage, SigninPage> {
+    let unlock_error = |error: AuthenticatorError| {
+        SigninPage::for_app_with_redirect_and_message(
+            state.authenticator_app.clone(),
+            None,
+            MessageBlock::new(Level::Error, "Déblocage impossible", &error.to_string()),
+        )
+    };
+
+    let user_id = UnlockLink::consume(&state, &form.token)
+        .await
+        .map_err(unlock_error)?;
+
+    let user = User::select_from_id(&state.db_pool, user_id)
+        .await
+        .map_err(unlock_error)?;
+
+    SigninLockout::forget_account(&state, &account_subject(&user.mail))
+        .await
+        .map_err(unlock_error)?;
+
+    Ok(SigninPage::for_app_with_redirect_and_message(
+        state.authenticator_app.clone(),
+        None,
+        MessageBlock::new(
+            Level::Success,
+            "Compte débloqué",
+            "Vous pouvez à nouveau vous connecter",
+        ),
+    ))
+}
@@ -0,0 +1,272 @@
+use askama_axum::{IntoResponse, Template};
+use axum::extract::{Query, State};
+use axum_extra::extract::CookieJar;
+use http::HeaderMap;
+use serde::Deserialize;
+use sqlx::types::{time::OffsetDateTime, Uuid};
+use time::Duration;
+use tracing::log::error;
+
+use crate::{
+    apps::App,
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    users::User,
+    utils::crypto::{hash_text, random_token},
+    AppState,
+};
+
+use super::{
+    assurance::{AuthMethod, Authentication},
+    csrf::CsrfForm,
+    second_factor::set_session_or_ask_second_factor,
+    signin::SigninPage,
+};
+
+const SECONDS_TO_EXPIRE: i64 = 600;
+
+/// Single use sign in link sent by mail (only its hash is saved)
+/// The link remembers the app and the endpoint requested before signing in
+pub struct MagicLink {
+    user_id: Uuid,
+    app_id: i32,
+    requested_endpoint: Option<String>,
+}
+
+impl MagicLink {
+    async fn generate(&self, state: &AppState) -> Result<String, AuthenticatorError> {
+        let token = random_token();
+
+        sqlx::query("DELETE FROM magic_links WHERE expires_at < CURRENT_TIMESTAMP")
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Deleting expired magic links -> {:?}", error);
+                AuthenticatorError::DatabaseError
+            })?;
+
+        sqlx::query(
+            "INSERT INTO magic_links (
+                hashed_token,
+                user_id,
+                app_id,
+                requested_endpoint,
+                expires_at)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(hash_text(&token))
+        .bind(self.user_id)
+        .bind(self.app_id)
+        .bind(&self.requested_endpoint)
+        .bind(OffsetDateTime::now_utc() + Duration::seconds(SECONDS_TO_EXPIRE))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Inserting magic link for {} and app {} -> {:?}",
+                self.user_id, self.app_id, error
+            );
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(token)
+    }
+
+    /// Get the link data and delete it so that it can't be used twice
+    async fn consume(state: &AppState, token: &str) -> Result<Self, AuthenticatorError> {
+        let (user_id, app_id, requested_endpoint, expires_at): (
+            Uuid,
+            i32,
+            Option<String>,
+            OffsetDateTime,
+        ) = sqlx::query_as(
+            "DELETE FROM magic_links
+            WHERE
+                hashed_token = $1
+            RETURNING
+                user_id,
+                app_id,
+                requested_endpoint,
+                expires_at",
+        )
+        .bind(hash_text(token))
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidMagicLink)?;
+
+        if expires_at < OffsetDateTime::now_utc() {
+            return Err(AuthenticatorError::InvalidMagicLink);
+        }
+
+        Ok(Self {
+            user_id,
+            app_id,
+            requested_endpoint,
+        })
+    }
+
+    /// Send the link if the mail is known, without telling it to the requester
+    async fn send(
+        state: AppState,
+        mail: String,
+        app: App,
+        requested_endpoint: Option<String>,
+    ) -> Result<bool, AuthenticatorError> {
+        let user = match User::select_from_mail(&state.db_pool, &mail).await? {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+
+        let token = MagicLink {
+            user_id: user.id,
+            app_id: app.id,
+            requested_endpoint,
+        }
+        .generate(&state)
+        .await?;
+
+        let magic_link_url = format!(
+            "{}/magic_link?token={}",
+            state.authenticator_app.base_url, token
+        );
+
+        let mail_subject = format!("Connexion à {}", app.name);
+
+        let mail_body = format!(
+            "Bonjour {},
+
+Pour vous connecter à l'app {} sans mot de passe, cliquez sur le lien suivant :
+{}
+
+Notez que ce lien n'est valable que 10 minutes et ne peut être utilisé qu'une seule fois.
+Si vous n'êtes pas à l'origine de cette demande, vous pouvez ignorer ce mail.
+
+En vous souhaitant une excellente journée !!
+
+L'équipe de Brouclean Softwares",
+            user.name, app.name, magic_link_url
+        );
+
+        state.mailer.send_mail(
+            format!("{} <{}>", user.name, user.mail),
+            mail_subject,
+            mail_body,
+        )
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkRequestForm {
+    mail: String,
+    app_id: i32,
+    requested_endpoint: Option<String>,
+}
+
+pub async fn request_handler(
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<MagicLinkRequestForm>,
+) -> impl IntoResponse {
+    let app = App::select_app_or_authenticator(&state, form.app_id).await;
+
+    if !app.magic_link_allowed {
+        return SigninPage::for_app_with_redirect_and_message(
+            app,
+            form.requested_endpoint,
+            MessageBlock::new(
+                Level::Error,
+                "Connexion impossible",
+                &AuthenticatorError::MagicLinkNotAllowed.to_string(),
+            ),
+        );
+    }
+
+    // Sent in the background so that the response time doesn't reveal if the mail exists
+    let mail = form.mail.clone();
+    let app_of_mail = app.clone();
+    let requested_endpoint = form.requested_endpoint.clone();
+    let state_of_mail = state.clone();
+    tokio::spawn(async move {
+        let _ = MagicLink::send(state_of_mail, mail, app_of_mail, requested_endpoint).await;
+    });
+
+    SigninPage::for_app_with_redirect_and_message(
+        app,
+        form.requested_endpoint,
+        MessageBlock::new(
+            Level::Success,
+            "Lien envoyé",
+            "Si un compte existe pour cette adresse, vous allez recevoir un lien de connexion par mail",
+        ),
+    )
+}
+
+/// Confirmation before signing in so that mail scanners opening the link don't consume it
+#[derive(Template)]
+#[template(path = "auth/magic_link_page.html")]
+pub struct MagicLinkPage {
+    token: String,
+    app: App,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkParams {
+    token: Option<String>,
+}
+
+pub async fn get_handler(
+    State(state): State<AppState>,
+    Query(params): Query<MagicLinkParams>,
+) -> impl IntoResponse {
+    MagicLinkPage {
+        token: params.token.unwrap_or_default(),
+        app: state.authenticator_app.clone(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkForm {
+    token: String,
+}
+
+pub async fn post_handler(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<MagicLinkForm>,
+) -> Result<impl IntoResponse, SigninPage> {
+    let signin_error = |app: App, error: AuthenticatorError| {
+        SigninPage::for_app_with_redirect_and_message(
+            app,
+            None,
+            MessageBlock::new(Level::Error, "Connexion impossible", &error.to_string()),
+        )
+    };
+
+    let magic_link = MagicLink::consume(&state, &form.token)
+        .await
+        .map_err(|error| signin_error(state.authenticator_app.clone(), error))?;
+
+    let app = App::select_app_or_authenticator(&state, magic_link.app_id).await;
+
+    if !app.magic_link_allowed {
+        return Err(signin_error(app, AuthenticatorError::MagicLinkNotAllowed));
+    }
+
+    let user = User::select_from_id(&state.db_pool, magic_link.user_id)
+        .await
+        .map_err(|error| signin_error(app.clone(), error))?;
+
+    set_session_or_ask_second_factor(
+        cookies,
+        &headers,
+        &state,
+        &user,
+        app.clone(),
+        Authentication::now(vec![AuthMethod::Mail]),
+        magic_link.requested_endpoint,
+    )
+    .await
+    .map_err(|error| signin_error(app, error))
+}
@@ -0,0 +1,361 @@
+use askama_axum::{IntoResponse, Template};
+use axum::extract::State;
+use axum_extra::extract::{cookie::SameSite, CookieJar};
+use http::HeaderMap;
+use serde::Deserialize;
+use sqlx::types::{time::OffsetDateTime, Uuid};
+use time::Duration;
+use tracing::log::error;
+
+use crate::{
+    apps::App,
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    users::User,
+    utils::crypto::{hash_text, random_digits, random_token},
+    AppState,
+};
+
+use super::{
+    assurance::{AuthMethod, Authentication},
+    csrf::CsrfForm,
+    second_factor::set_session_or_ask_second_factor,
+};
+
+const CODE_LENGTH: usize = 6;
+const SECONDS_TO_EXPIRE: i64 = 600;
+const MAX_ATTEMPTS: i32 = 5;
+/// Random value of the browser that asked for the code, the code only works with it
+const MAIL_CODE_BROWSER: &str = "mail_code_browser";
+
+/// What the code sent by mail is used for
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum MailCodePurpose {
+    Signin,
+    Confirmation,
+}
+
+/// Short code sent by mail to type instead of clicking on a link
+/// Only its hash with the browser value is saved, with the hashes of the browser value and of the mail it was sent to
+/// It can only be tried a few times from the browser that asked for it, a new code gives new attempts
+/// (asking for codes is rate limited per account)
+pub struct MailCode;
+
+impl MailCode {
+    /// Give the browser a new random value to bind the next code to
+    pub fn bind_browser(state: &AppState, cookies: CookieJar) -> (CookieJar, String) {
+        let browser_value = random_token();
+
+        let mut cookie = state
+            .cookie_policy
+            .cookie(MAIL_CODE_BROWSER, browser_value.clone());
+        cookie.set_same_site(SameSite::Strict);
+        cookie.set_max_age(Duration::seconds(SECONDS_TO_EXPIRE));
+
+        (cookies.add(cookie), browser_value)
+    }
+
+    /// Save a new code sent to the mail of the user for the purpose, replacing the previous one
+    pub async fn generate(
+        state: &AppState,
+        user: &User,
+        purpose: MailCodePurpose,
+        browser_value: &str,
+    ) -> Result<String, AuthenticatorError> {
+        let code = random_digits(CODE_LENGTH);
+
+        sqlx::query(
+            "INSERT INTO mail_codes (
+                user_id,
+                purpose,
+                hashed_code,
+                hashed_browser,
+                hashed_mail,
+                attempts,
+                expires_at)
+            VALUES ($1, $2, $3, $4, $5, 0, $6)
+            ON CONFLICT (user_id, purpose) DO UPDATE
+            SET
+                hashed_code = $3,
+                hashed_browser = $4,
+                hashed_mail = $5,
+                attempts = 0,
+                expires_at = $6",
+        )
+        .bind(user.id)
+        .bind(purpose)
+        .bind(hash_text(&format!("{}{}", browser_value, code)))
+        .bind(hash_text(browser_value))
+        .bind(Self::hash_mail(&user.mail))
+        .bind(OffsetDateTime::now_utc() + Duration::seconds(SECONDS_TO_EXPIRE))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Saving mail code {:?} for {} -> {:?}",
+                purpose, user.id, error
+            );
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(code)
+    }
+
+    /// Check the code typed in the browser that asked for it, while the user still has the mail it was sent to
+    /// Only the browser that asked for it spends attempts, the code is deleted once used and emptied once its attempts are spent
+    pub async fn verify(
+        state: &AppState,
+        user: &User,
+        purpose: MailCodePurpose,
+        code: &str,
+        cookies: &CookieJar,
+    ) -> Result<(), AuthenticatorError> {
+        let browser_value = cookies
+            .get(&state.cookie_policy.name(MAIL_CODE_BROWSER))
+            .map(|cookie| cookie.value().to_owned())
+            .ok_or(AuthenticatorError::InvalidMailCode)?;
+
+        let (hashed_code, hashed_mail, attempts): (String, String, i32) = sqlx::query_as(
+            "UPDATE mail_codes
+            SET
+                attempts = attempts + 1
+            WHERE
+                user_id = $1
+                AND purpose = $2
+                AND hashed_browser = $3
+                AND expires_at > CURRENT_TIMESTAMP
+            RETURNING
+                hashed_code,
+                hashed_mail,
+                attempts",
+        )
+        .bind(user.id)
+        .bind(purpose)
+        .bind(hash_text(&browser_value))
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidMailCode)?;
+
+        let is_valid = attempts <= MAX_ATTEMPTS
+            && hashed_mail == Self::hash_mail(&user.mail)
+            && hashed_code == hash_text(&format!("{}{}", browser_value, code.trim()));
+
+        if is_valid {
+            Self::delete(state, user.id, purpose).await?;
+            return Ok(());
+        }
+
+        if attempts >= MAX_ATTEMPTS {
+            Self::spend(state, user.id, purpose).await?;
+        }
+
+        Err(AuthenticatorError::InvalidMailCode)
+    }
+
+    fn hash_mail(mail: &str) -> String {
+        hash_text(&mail.trim().to_lowercase())
+    }
+
+    /// The code can't be found anymore until a new one is asked for
+    async fn spend(
+        state: &AppState,
+        user_id: Uuid,
+        purpose: MailCodePurpose,
+    ) -> Result<(), AuthenticatorError> {
+        sqlx::query("UPDATE mail_codes SET hashed_code = '' WHERE user_id = $1 AND purpose = $2")
+            .bind(user_id)
+            .bind(purpose)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!(
+                    "Spending mail code {:?} for {} -> {:?}",
+                    purpose, user_id, error
+                );
+                AuthenticatorError::DatabaseError
+            })?;
+
+        Ok(())
+    }
+
+    /// Codes sent to a former mail of the user can't be used anymore
+    pub async fn delete_all(state: &AppState, user_id: Uuid) -> Result<(), AuthenticatorError> {
+        sqlx::query("DELETE FROM mail_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Deleting mail codes of {} -> {:?}", user_id, error);
+                AuthenticatorError::DatabaseError
+            })?;
+
+        Ok(())
+    }
+
+    async fn delete(
+        state: &AppState,
+        user_id: Uuid,
+        purpose: MailCodePurpose,
+    ) -> Result<(), AuthenticatorError> {
+        sqlx::query("DELETE FROM mail_codes WHERE user_id = $1 AND purpose = $2")
+            .bind(user_id)
+            .bind(purpose)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!(
+                    "Deleting mail code {:?} for {} -> {:?}",
+                    purpose, user_id, error
+                );
+                AuthenticatorError::DatabaseError
+            })?;
+
+        Ok(())
+    }
+
+    /// Send a sign in code if the mail is known, without telling it to the requester
+    async fn send_signin_code(
+        state: AppState,
+        mail: String,
+        app: App,
+        browser_value: String,
+    ) -> Result<bool, AuthenticatorError> {
+        let user = match User::select_from_mail(&state.db_pool, &mail).await? {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+
+        let code = Self::generate(&state, &user, MailCodePurpose::Signin, &browser_value).await?;
+
+        let mail_subject = format!("Votre code de connexion à {} : {}", app.name, code);
+
+        let mail_body = format!(
+            "Bonjour {},
+
+Pour vous connecter à l'app {}, saisissez le code suivant dans la page ouverte dans votre navigateur :
+{}
+
+Notez que ce code n'est valable que 10 minutes.
+Si vous n'êtes pas à l'origine de cette demande, vous pouvez ignorer ce mail.
+
+En vous souhaitant une excellente journée !!
+
+L'équipe de Brouclean Softwares",
+            user.name, app.name, code
+        );
+
+        state.mailer.send_mail(
+            format!("{} <{}>", user.name, user.mail),
+            mail_subject,
+            mail_body,
+        )
+    }
+}
+
+#[derive(Template)]
+#[template(path = "auth/mail_code_page.html")]
+pub struct MailCodePage {
+    mail: String,
+    app: App,
+    requested_endpoint: String,
+    message: MessageBlock,
+}
+
+#[derive(Deserialize)]
+pub struct MailCodeRequestForm {
+    mail: String,
+    app_id: i32,
+    requested_endpoint: Option<String>,
+}
+
+pub async fn request_handler(
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<MailCodeRequestForm>,
+) -> Result<impl IntoResponse, MailCodePage> {
+    let app = App::select_app_or_authenticator(&state, form.app_id).await;
+
+    let mut page = MailCodePage {
+        mail: form.mail.clone(),
+        app: app.clone(),
+        requested_endpoint: form.requested_endpoint.unwrap_or_default(),
+        message: MessageBlock::new(
+            Level::Info,
+            "Code envoyé",
+            "Si un compte existe pour cette adresse, vous allez recevoir un code de connexion par mail",
+        ),
+    };
+
+    if !app.magic_link_allowed {
+        page.message = MessageBlock::new(
+            Level::Error,
+            "Connexion impossible",
+            &AuthenticatorError::MagicLinkNotAllowed.to_string(),
+        );
+
+        return Err(page);
+    }
+
+    let (cookies, browser_value) = MailCode::bind_browser(&state, cookies);
+
+    // Sent in the background so that the response time doesn't reveal if the mail exists
+    let state_of_mail = state.clone();
+    tokio::spawn(async move {
+        let _ = MailCode::send_signin_code(state_of_mail, form.mail, app, browser_value).await;
+    });
+
+    Ok((cookies, page))
+}
+
+#[derive(Deserialize)]
+pub struct MailCodeForm {
+    mail: String,
+    code: String,
+    app_id: i32,
+    requested_endpoint: Option<String>,
+}
+
+pub async fn signin_handler(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<MailCodeForm>,
+) -> Result<impl IntoResponse, MailCodePage> {
+    let app = App::select_app_or_authenticator(&state, form.app_id).await;
+
+    let code_error = |error: AuthenticatorError| MailCodePage {
+        mail: form.mail.clone(),
+        app: app.clone(),
+        requested_endpoint: form.requested_endpoint.clone().unwrap_or_default(),
+        message: MessageBlock::new(Level::Error, "Connexion impossible", &error.to_string()),
+    };
+
+    if !app.magic_link_allowed {
+        return Err(code_error(AuthenticatorError::MagicLinkNotAllowed));
+    }
+
+    let user = User::select_from_mail(&state.db_pool, &form.mail)
+        .await
+        .map_err(code_error)?
+        .ok_or(code_error(AuthenticatorError::InvalidMailCode))?;
+
+    MailCode::verify(&state, &user, MailCodePurpose::Signin, &form.code, &cookies)
+        .await
+        .map_err(code_error)?;
+
+    set_session_or_ask_second_factor(
+        cookies.remove(state.cookie_policy.removal(MAIL_CODE_BROWSER)),
+        &headers,
+        &state,
+        &user,
+        app.clone(),
+        Authentication::now(vec![AuthMethod::Mail]),
+        form.requested_endpoint.clone(),
+    )
+    .await
+    .map_err(code_error)
+}
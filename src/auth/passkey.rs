@@ -0,0 +1,105 @@
+use askama_axum::IntoResponse;
+use axum::{extract::State, Json};
+use axum_extra::extract::CookieJar;
+use http::{HeaderMap, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    apps::App,
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    users::User,
+    AppState,
+};
+
+use super::{
+    assurance::{AuthMethod, Authentication},
+    csrf::CsrfForm,
+    signin::SigninPage,
+    webauthn::{Ceremony, PasskeyAssertion, RelyingParty, WebauthnChallenge, WebauthnCredential},
+    IdSession,
+};
+
+/// Challenge for any discoverable credential, the user is found from the credential (usernameless)
+pub async fn signin_options_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let options = async {
+        let challenge = WebauthnChallenge::generate(&state, Ceremony::Signin, None).await?;
+
+        Ok::<Value, AuthenticatorError>(RelyingParty::of(&state)?.request_options(
+            &challenge,
+            &[],
+            "required",
+        ))
+    }
+    .await
+    .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+
+    Ok(Json(options))
+}
+
+#[derive(Deserialize)]
+pub struct PasskeySigninForm {
+    app_id: i32,
+    requested_endpoint: Option<String>,
+    credential_id: String,
+    client_data: String,
+    authenticator_data: String,
+    signature: String,
+    user_handle: Option<String>,
+}
+
+pub async fn signin_handler(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<PasskeySigninForm>,
+) -> Result<impl IntoResponse, SigninPage> {
+    let app = App::select_app_or_authenticator(&state, form.app_id).await;
+
+    let signin_error = |error: AuthenticatorError| {
+        SigninPage::for_app_with_redirect_and_message(
+            app.clone(),
+            form.requested_endpoint.clone(),
+            MessageBlock::new(Level::Error, "Connexion impossible", &error.to_string()),
+        )
+    };
+
+    let assertion = PasskeyAssertion {
+        credential_id: form.credential_id.clone(),
+        client_data: form.client_data.clone(),
+        authenticator_data: form.authenticator_data.clone(),
+        signature: form.signature.clone(),
+        user_handle: form.user_handle.clone(),
+    };
+
+    let (credential, user_verified) =
+        WebauthnCredential::authenticate(&state, Ceremony::Signin, &assertion)
+            .await
+            .map_err(signin_error)?;
+
+    // Without the PIN or biometrics of the authenticator, anyone holding the device could sign in
+    if !user_verified {
+        return Err(signin_error(AuthenticatorError::InvalidPasskey));
+    }
+
+    let user = User::select_from_id(&state.db_pool, credential.user_id)
+        .await
+        .map_err(signin_error)?;
+
+    // The device and its user verification are two factors
+    IdSession::set_with_redirect_to_endpoint(
+        cookies,
+        &headers,
+        &state,
+        &user,
+        &Authentication::now(vec![AuthMethod::Hwk, AuthMethod::Mfa]),
+        form.requested_endpoint.clone(),
+    )
+    .await
+    .map_err(signin_error)
+}
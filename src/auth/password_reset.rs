@@ -0,0 +1,267 @@
+use askama_axum::{IntoResponse, Template};
+use axum::extract::{Query, State};
+use serde::Deserialize;
+use sqlx::types::{time::OffsetDateTime, Uuid};
+use time::Duration;
+use tracing::log::error;
+
+use crate::{
+    apps::App,
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    users::{password::sign_out_everywhere, User},
+    utils::crypto::{hash_text, random_token},
+    AppState,
+};
+
+use super::{csrf::CsrfForm, signin::SigninPage};
+
+const SECONDS_TO_EXPIRE: i64 = 900;
+
+/// Single use link sent by mail to choose a new password (only its hash is saved)
+pub struct PasswordReset;
+
+impl PasswordReset {
+    /// Replace the previous reset links of the user by a new one
+    async fn generate(state: &AppState, user_id: Uuid) -> Result<String, AuthenticatorError> {
+        let token = random_token();
+
+        sqlx::query(
+            "DELETE FROM password_resets WHERE user_id = $1 OR expires_at < CURRENT_TIMESTAMP",
+        )
+        .bind(user_id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Deleting password resets of {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        sqlx::query(
+            "INSERT INTO password_resets (
+                hashed_token,
+                user_id,
+                expires_at)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(hash_text(&token))
+        .bind(user_id)
+        .bind(OffsetDateTime::now_utc() + Duration::seconds(SECONDS_TO_EXPIRE))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Inserting password reset for {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(token)
+    }
+
+    /// Get the user of the link and delete it so that it can't be used twice
+    async fn consume(state: &AppState, token: &str) -> Result<Uuid, AuthenticatorError> {
+        let (user_id, expires_at): (Uuid, OffsetDateTime) = sqlx::query_as(
+            "DELETE FROM password_resets
+            WHERE
+                hashed_token = $1
+            RETURNING
+                user_id,
+                expires_at",
+        )
+        .bind(hash_text(token))
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidResetLink)?;
+
+        if expires_at < OffsetDateTime::now_utc() {
+            return Err(AuthenticatorError::InvalidResetLink);
+        }
+
+        Ok(user_id)
+    }
+
+    /// Send the link if the mail is known, without telling it to the requester
+    async fn send(state: AppState, mail: String, app: App) -> Result<bool, AuthenticatorError> {
+        let user = match User::select_from_mail(&state.db_pool, &mail).await? {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+
+        let token = Self::generate(&state, user.id).await?;
+
+        let reset_url = format!(
+            "{}/reset_password?app_id={}&token={}",
+            state.authenticator_app.base_url, app.id, token
+        );
+
+        let mail_subject = "Réinitialisez votre mot de passe".to_owned();
+
+        let mail_body = format!(
+            "Bonjour {},
+
+Vous avez demandé à réinitialiser votre mot de passe pour l'app {}.
+
+Pour choisir un nouveau mot de passe, cliquez sur le lien suivant :
+{}
+
+Notez que ce lien n'est valable que 15 minutes et ne peut être utilisé qu'une seule fois.
+Si vous n'êtes pas à l'origine de cette demande, vous pouvez ignorer ce mail.
+
+En vous souhaitant une excellente journée !!
+
+L'équipe de Brouclean Softwares",
+            user.name, app.name, reset_url
+        );
+
+        state.mailer.send_mail(
+            format!("{} <{}>", user.name, user.mail),
+            mail_subject,
+            mail_body,
+        )
+    }
+}
+
+#[derive(Template)]
+#[template(path = "auth/forgot_password_page.html")]
+pub struct ForgotPasswordPage {
+    mail: String,
+    app: App,
+    message: MessageBlock,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordParams {
+    mail: Option<String>,
+    app_id: Option<i32>,
+}
+
+pub async fn forgot_password_get_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ForgotPasswordParams>,
+) -> impl IntoResponse {
+    ForgotPasswordPage {
+        mail: params.mail.unwrap_or_default(),
+        app: App::select_app_or_authenticator(
+            &state,
+            params.app_id.unwrap_or(state.authenticator_app.id),
+        )
+        .await,
+        message: MessageBlock::empty(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordForm {
+    mail: String,
+    app_id: i32,
+}
+
+pub async fn forgot_password_post_handler(
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<ForgotPasswordForm>,
+) -> impl IntoResponse {
+    let app = App::select_app_or_authenticator(&state, form.app_id).await;
+
+    // Sent in the background so that the response time doesn't reveal if the mail exists
+    let mail = form.mail.clone();
+    let app_of_mail = app.clone();
+    let state_of_mail = state.clone();
+    tokio::spawn(async move {
+        let _ = PasswordReset::send(state_of_mail, mail, app_of_mail).await;
+    });
+
+    ForgotPasswordPage {
+        mail: form.mail,
+        app,
+        message: MessageBlock::new(
+            Level::Success,
+            "Demande envoyée",
+            "Si un compte existe pour cette adresse, vous allez recevoir un mail pour réinitialiser votre mot de passe",
+        ),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "auth/reset_password_page.html")]
+pub struct ResetPasswordPage {
+    token: String,
+    app: App,
+    message: MessageBlock,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordParams {
+    token: Option<String>,
+    app_id: Option<i32>,
+}
+
+pub async fn reset_password_get_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ResetPasswordParams>,
+) -> impl IntoResponse {
+    ResetPasswordPage {
+        token: params.token.unwrap_or_default(),
+        app: App::select_app_or_authenticator(
+            &state,
+            params.app_id.unwrap_or(state.authenticator_app.id),
+        )
+        .await,
+        message: MessageBlock::empty(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordForm {
+    token: String,
+    app_id: i32,
+    password: String,
+    confirm_password: String,
+}
+
+pub async fn reset_password_post_handler(
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<ResetPasswordForm>,
+) -> Result<SigninPage, ResetPasswordPage> {
+    let app = App::select_app_or_authenticator(&state, form.app_id).await;
+
+    let reset_error = |error: AuthenticatorError| ResetPasswordPage {
+        token: form.token.clone(),
+        app: app.clone(),
+        message: MessageBlock::new(
+            Level::Error,
+            "Réinitialisation impossible",
+            &error.to_string(),
+        ),
+    };
+
+    // Checked before using the link so that it isn't lost
+    let encrypted_password =
+        User::encrypt_new_password(&form.password, &form.confirm_password).map_err(reset_error)?;
+
+    let user_id = PasswordReset::consume(&state, &form.token)
+        .await
+        .map_err(reset_error)?;
+
+    User::update_encrypted_password(&state.db_pool, &user_id, &encrypted_password)
+        .await
+        .map_err(reset_error)?;
+
+    let user = User::select_from_id(&state.db_pool, user_id)
+        .await
+        .map_err(reset_error)?;
+
+    sign_out_everywhere(&state, &user, None)
+        .await
+        .map_err(reset_error)?;
+
+    Ok(SigninPage::for_app_with_redirect_and_message(
+        app,
+        None,
+        MessageBlock::new(
+            Level::Success,
+            "Mot de passe réinitialisé",
+            "Vous pouvez vous connecter avec votre nouveau mot de passe",
+        ),
+    ))
+}
@@ -0,0 +1,234 @@
+use askama_axum::{IntoResponse, Response, Template};
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Query, State},
+    response::Redirect,
+    RequestPartsExt,
+};
+use axum_extra::extract::CookieJar;
+use core::fmt::Debug;
+use http::{request::Parts, HeaderMap, HeaderValue, Method};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    apps::App,
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    users::User,
+    AppState,
+};
+
+use super::{
+    assurance::{AuthMethod, Authentication},
+    csrf::CsrfForm,
+    lockout::SigninLockout,
+    second_factor::set_session_or_ask_second_factor,
+    session::client_ip,
+    IdSession,
+};
+
+/// Sensitive actions need an authentication more recent than this
+pub const RECENT_AUTHENTICATION_SECONDS: i64 = 600;
+
+/// Session of a user who authenticated recently, for the sensitive actions
+/// (password, mail, second factors, app secrets...)
+/// Otherwise the user is sent to the re-authentication page and comes back afterwards
+pub struct RecentIdSession(pub IdSession);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RecentIdSession
+where
+    AppState: FromRef<S>,
+    S: Send + Sync + Debug,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let id_session = IdSession::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        if id_session.is_recently_authenticated() {
+            return Ok(Self(id_session));
+        }
+
+        let state = parts
+            .extract_with_state::<AppState, _>(state)
+            .await
+            .unwrap();
+
+        Err(redirect_to_reauthentication(
+            &state,
+            &parts.method,
+            &parts.headers,
+            parts.uri.path_and_query().map(|path| path.as_str()),
+        ))
+    }
+}
+
+/// Go through the re-authentication page, then back to the current page
+/// A form can't be posted again, so the user comes back to the page the form was sent from
+pub fn redirect_to_reauthentication(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+    requested_endpoint: Option<&str>,
+) -> Response {
+    let is_htmx = headers.contains_key("HX-Request");
+
+    let return_endpoint = if method == Method::GET {
+        requested_endpoint.map(str::to_owned)
+    } else {
+        let page_header = if is_htmx { "HX-Current-URL" } else { "Referer" };
+
+        headers
+            .get(page_header)
+            .and_then(|page| page.to_str().ok())
+            .and_then(|page| authenticator_endpoint(state, page))
+    };
+
+    let reauth_url = format!(
+        "/reauth?requested_endpoint={}",
+        url_escape::encode_component(&return_endpoint.unwrap_or("/profile".to_owned()))
+    );
+
+    // Htmx swaps the responses in the page, it has to be told to change page instead
+    if is_htmx {
+        match HeaderValue::from_str(&reauth_url) {
+            Ok(reauth_url) => [("HX-Redirect", reauth_url)].into_response(),
+            Err(_) => Redirect::to("/reauth").into_response(),
+        }
+    } else {
+        Redirect::to(&reauth_url).into_response()
+    }
+}
+
+/// Path of a page of the authenticator, nothing for other sites
+fn authenticator_endpoint(state: &AppState, page: &str) -> Option<String> {
+    let page = Url::parse(page).ok()?;
+    let authenticator_url = Url::parse(&state.authenticator_app.base_url).ok()?;
+
+    if page.origin() != authenticator_url.origin() {
+        return None;
+    }
+
+    match page.query() {
+        Some(query) => Some(format!("{}?{}", page.path(), query)),
+        None => Some(page.path().to_owned()),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "auth/reauth_page.html")]
+pub struct ReauthPage {
+    mail: String,
+    app: App,
+    requested_endpoint: String,
+    message: MessageBlock,
+}
+
+impl ReauthPage {
+    fn new(
+        state: &AppState,
+        id_session: &IdSession,
+        requested_endpoint: Option<String>,
+        message: MessageBlock,
+    ) -> Self {
+        Self {
+            mail: id_session.mail.clone(),
+            app: state.authenticator_app.clone(),
+            requested_endpoint: requested_endpoint.unwrap_or("/profile".to_owned()),
+            message,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+    requested_endpoint: Option<String>,
+}
+
+pub async fn get_handler(
+    id_session: IdSession,
+    State(state): State<AppState>,
+    Query(params): Query<QueryParams>,
+) -> impl IntoResponse {
+    ReauthPage::new(
+        &state,
+        &id_session,
+        params.requested_endpoint,
+        MessageBlock::new(
+            Level::Info,
+            "Confirmez votre identité",
+            "Cette action est sensible, veuillez vous authentifier à nouveau pour continuer",
+        ),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ReauthForm {
+    password: String,
+    requested_endpoint: Option<String>,
+}
+
+/// Same checks as a sign in of the user of the session (second factor included),
+/// a new session is then set with a fresh authentication time
+pub async fn post_handler(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    id_session: IdSession,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<ReauthForm>,
+) -> Result<Response, ReauthPage> {
+    let reauth_error = |error: AuthenticatorError| {
+        ReauthPage::new(
+            &state,
+            &id_session,
+            form.requested_endpoint.clone(),
+            MessageBlock::new(
+                Level::Error,
+                "Authentification impossible",
+                &error.to_string(),
+            ),
+        )
+    };
+
+    let user = User::select_from_id(&state.db_pool, id_session.user_id)
+        .await
+        .map_err(reauth_error)?;
+
+    // The password can't be guessed here either
+    let lockout = SigninLockout::new(&user.mail, client_ip(&headers, state.trusted_proxy_hops));
+
+    lockout.check(&state).await.map_err(reauth_error)?;
+
+    if !user.password_match(form.password.clone()).unwrap_or(false) {
+        if lockout.record_failure(&state).await.map_err(reauth_error)? {
+            let state_of_mail = state.clone();
+            tokio::spawn(async move {
+                let _ = SigninLockout::send_unlock_link(state_of_mail, user).await;
+            });
+
+            return Err(reauth_error(AuthenticatorError::AccountLocked));
+        }
+
+        return Err(reauth_error(AuthenticatorError::WrongCredentials));
+    }
+
+    lockout.record_success(&state).await.map_err(reauth_error)?;
+
+    set_session_or_ask_second_factor(
+        cookies,
+        &headers,
+        &state,
+        &user,
+        state.authenticator_app.clone(),
+        Authentication::now(vec![AuthMethod::Pwd]),
+        form.requested_endpoint.clone(),
+    )
+    .await
+    .map_err(reauth_error)
+}
@@ -0,0 +1,224 @@
+use askama_axum::{IntoResponse, Template};
+use axum::extract::{Query, State};
+use axum_extra::extract::CookieJar;
+use http::HeaderMap;
+use rand::Rng;
+use serde::Deserialize;
+use sqlx::types::Uuid;
+use tracing::log::error;
+
+use crate::{
+    apps::App,
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    users::{password::sign_out_everywhere, User},
+    utils::crypto::hash_text,
+    AppState,
+};
+
+use super::{
+    assurance::{AuthMethod, Authentication},
+    csrf::CsrfForm,
+    second_factor::set_session_or_ask_second_factor,
+};
+
+const NUMBER_OF_CODES: usize = 10;
+const CODE_GROUPS: usize = 3;
+const CODE_GROUP_LENGTH: usize = 4;
+/// No characters that can be mistaken for one another once printed (0/o, 1/l/i)
+const CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// Single use codes to get back into the account without the mailbox (only their hash is saved)
+pub struct RecoveryCodes;
+
+impl RecoveryCodes {
+    /// Replace every code of the user by new ones, given back only once
+    pub async fn generate(
+        state: &AppState,
+        user_id: Uuid,
+    ) -> Result<Vec<String>, AuthenticatorError> {
+        let codes: Vec<String> = (0..NUMBER_OF_CODES).map(|_| Self::random_code()).collect();
+
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Deleting recovery codes of {} -> {:?}", user_id, error);
+                AuthenticatorError::DatabaseError
+            })?;
+
+        for code in &codes {
+            sqlx::query("INSERT INTO recovery_codes (user_id, hashed_code) VALUES ($1, $2)")
+                .bind(user_id)
+                .bind(Self::hash(user_id, code))
+                .execute(&state.db_pool)
+                .await
+                .map_err(|error| {
+                    error!("Inserting recovery code for {} -> {:?}", user_id, error);
+                    AuthenticatorError::DatabaseError
+                })?;
+        }
+
+        Ok(codes)
+    }
+
+    pub async fn remaining(state: &AppState, user_id: Uuid) -> Result<i64, AuthenticatorError> {
+        sqlx::query_scalar(
+            "SELECT COUNT(*) FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL",
+        )
+        .bind(user_id)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Counting recovery codes of {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })
+    }
+
+    /// Mark the code as used, it can't be used twice
+    async fn consume(
+        state: &AppState,
+        user_id: Uuid,
+        code: &str,
+    ) -> Result<(), AuthenticatorError> {
+        let used = sqlx::query(
+            "UPDATE recovery_codes
+            SET
+                used_at = CURRENT_TIMESTAMP
+            WHERE
+                user_id = $1
+                AND hashed_code = $2
+                AND used_at IS NULL",
+        )
+        .bind(user_id)
+        .bind(Self::hash(user_id, code))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Using recovery code of {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        if used.rows_affected() == 1 {
+            Ok(())
+        } else {
+            Err(AuthenticatorError::InvalidRecoveryCode)
+        }
+    }
+
+    fn random_code() -> String {
+        let mut rng = rand::thread_rng();
+
+        (0..CODE_GROUPS)
+            .map(|_| {
+                (0..CODE_GROUP_LENGTH)
+                    .map(|_| char::from(CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())]))
+                    .collect::<String>()
+            })
+            .collect::<Vec<String>>()
+            .join("-")
+    }
+
+    /// Salted with the user so that the same code of two users doesn't give the same hash
+    fn hash(user_id: Uuid, code: &str) -> String {
+        let normalized_code: String = code
+            .chars()
+            .filter(|char| char.is_ascii_alphanumeric())
+            .map(|char| char.to_ascii_lowercase())
+            .collect();
+
+        hash_text(&format!("{}{}", user_id, normalized_code))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "auth/recovery_page.html")]
+pub struct RecoveryPage {
+    mail: String,
+    app: App,
+    message: MessageBlock,
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryParams {
+    mail: Option<String>,
+    app_id: Option<i32>,
+}
+
+pub async fn get_handler(
+    State(state): State<AppState>,
+    Query(params): Query<RecoveryParams>,
+) -> impl IntoResponse {
+    RecoveryPage {
+        mail: params.mail.unwrap_or_default(),
+        app: App::select_app_or_authenticator(
+            &state,
+            params.app_id.unwrap_or(state.authenticator_app.id),
+        )
+        .await,
+        message: MessageBlock::empty(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryForm {
+    app_id: i32,
+    mail: String,
+    code: String,
+    password: String,
+    confirm_password: String,
+}
+
+/// The code replaces the reset link sent by mail: the user chooses a new password and is signed in
+/// The mail is kept (it can be changed from the profile) and the second factor is still asked if enabled,
+/// so that a printed code alone doesn't give the whole account
+pub async fn post_handler(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<RecoveryForm>,
+) -> Result<impl IntoResponse, RecoveryPage> {
+    let app = App::select_app_or_authenticator(&state, form.app_id).await;
+
+    let recovery_error = |error: AuthenticatorError| RecoveryPage {
+        mail: form.mail.clone(),
+        app: app.clone(),
+        message: MessageBlock::new(Level::Error, "Récupération impossible", &error.to_string()),
+    };
+
+    // Checked before using the code so that it isn't lost
+    let encrypted_password = User::encrypt_new_password(&form.password, &form.confirm_password)
+        .map_err(recovery_error)?;
+
+    let user = User::select_from_mail(&state.db_pool, &form.mail)
+        .await
+        .map_err(recovery_error)?
+        .ok_or(recovery_error(AuthenticatorError::InvalidRecoveryCode))?;
+
+    RecoveryCodes::consume(&state, user.id, &form.code)
+        .await
+        .map_err(recovery_error)?;
+
+    User::update_encrypted_password(&state.db_pool, &user.id, &encrypted_password)
+        .await
+        .map_err(recovery_error)?;
+
+    sign_out_everywhere(&state, &user, None)
+        .await
+        .map_err(recovery_error)?;
+
+    set_session_or_ask_second_factor(
+        cookies,
+        &headers,
+        &state,
+        &user,
+        app.clone(),
+        Authentication::now(vec![AuthMethod::Recovery]),
+        Some("/profile".to_owned()),
+    )
+    .await
+    .map_err(recovery_error)
+}
@@ -0,0 +1,381 @@
+use askama_axum::{IntoResponse, Response, Template};
+use axum::{extract::State, Json};
+use axum_extra::extract::CookieJar;
+use http::{HeaderMap, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::types::{time::OffsetDateTime, Uuid};
+use time::Duration;
+use tracing::log::error;
+
+use crate::{
+    apps::App,
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    users::User,
+    utils::crypto::{hash_text, random_token},
+    AppState,
+};
+
+use super::{
+    assurance::{AuthMethod, Authentication},
+    csrf::CsrfForm,
+    totp::Totp,
+    webauthn::{Ceremony, PasskeyAssertion, RelyingParty, WebauthnChallenge, WebauthnCredential},
+    IdSession,
+};
+
+const SECONDS_TO_EXPIRE: i64 = 300;
+const MAX_ATTEMPTS: i32 = 5;
+
+/// Sign in waiting for a second factor once the first one has been checked (only its hash is saved)
+/// It remembers the methods already used, the app and the endpoint requested before signing in
+pub struct SecondFactorChallenge {
+    user_id: Uuid,
+    app_id: i32,
+    requested_endpoint: Option<String>,
+    first_authentication: Authentication,
+}
+
+impl SecondFactorChallenge {
+    async fn generate(&self, state: &AppState) -> Result<String, AuthenticatorError> {
+        let token = random_token();
+
+        sqlx::query("DELETE FROM second_factor_challenges WHERE expires_at < CURRENT_TIMESTAMP")
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Deleting expired second factor challenges -> {:?}", error);
+                AuthenticatorError::DatabaseError
+            })?;
+
+        sqlx::query(
+            "INSERT INTO second_factor_challenges (
+                hashed_token,
+                user_id,
+                app_id,
+                requested_endpoint,
+                first_methods,
+                expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(hash_text(&token))
+        .bind(self.user_id)
+        .bind(self.app_id)
+        .bind(&self.requested_endpoint)
+        .bind(self.first_authentication.saved_methods())
+        .bind(OffsetDateTime::now_utc() + Duration::seconds(SECONDS_TO_EXPIRE))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Inserting second factor challenge for {} -> {:?}",
+                self.user_id, error
+            );
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(token)
+    }
+
+    /// Get the challenge and count the attempt, it is deleted after too many attempts
+    async fn attempt(state: &AppState, token: &str) -> Result<Self, AuthenticatorError> {
+        let (user_id, app_id, requested_endpoint, first_methods, attempts): (
+            Uuid,
+            i32,
+            Option<String>,
+            String,
+            i32,
+        ) = sqlx::query_as(
+            "UPDATE second_factor_challenges
+            SET
+                attempts = attempts + 1
+            WHERE
+                hashed_token = $1
+                AND expires_at > CURRENT_TIMESTAMP
+            RETURNING
+                user_id,
+                app_id,
+                requested_endpoint,
+                first_methods,
+                attempts",
+        )
+        .bind(hash_text(token))
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::ExpiredSignin)?;
+
+        if attempts > MAX_ATTEMPTS {
+            Self::delete(state, token).await?;
+            return Err(AuthenticatorError::ExpiredSignin);
+        }
+
+        Ok(Self {
+            user_id,
+            app_id,
+            requested_endpoint,
+            first_authentication: Authentication::from_saved(&first_methods, 0),
+        })
+    }
+
+    /// Sign in with the first methods and the second one once it has been checked
+    async fn complete(
+        self,
+        cookies: CookieJar,
+        headers: &HeaderMap,
+        state: &AppState,
+        token: &str,
+        user: &User,
+        second_method: AuthMethod,
+    ) -> Result<impl IntoResponse, AuthenticatorError> {
+        Self::delete(state, token).await?;
+
+        let mut methods = self.first_authentication.methods;
+        methods.push(second_method);
+
+        IdSession::set_with_redirect_to_endpoint(
+            cookies,
+            headers,
+            state,
+            user,
+            &Authentication::now(methods),
+            self.requested_endpoint,
+        )
+        .await
+    }
+
+    async fn delete(state: &AppState, token: &str) -> Result<(), AuthenticatorError> {
+        sqlx::query("DELETE FROM second_factor_challenges WHERE hashed_token = $1")
+            .bind(hash_text(token))
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Deleting second factor challenge -> {:?}", error);
+                AuthenticatorError::DatabaseError
+            })?;
+
+        Ok(())
+    }
+}
+
+/// Every sign in goes through here once the first factor is checked,
+/// the session is only set if the user has no second factor to give
+pub async fn set_session_or_ask_second_factor(
+    cookies: CookieJar,
+    headers: &HeaderMap,
+    state: &AppState,
+    user: &User,
+    app: App,
+    first_authentication: Authentication,
+    requested_endpoint: Option<String>,
+) -> Result<Response, AuthenticatorError> {
+    let has_totp = Totp::is_enabled(state, user.id).await?;
+    let has_passkeys = WebauthnCredential::exists_for_user(state, user.id).await?;
+
+    if !has_totp && !has_passkeys {
+        return IdSession::set_with_redirect_to_endpoint(
+            cookies,
+            headers,
+            state,
+            user,
+            &first_authentication,
+            requested_endpoint,
+        )
+        .await
+        .map(IntoResponse::into_response);
+    }
+
+    let token = SecondFactorChallenge {
+        user_id: user.id,
+        app_id: app.id,
+        requested_endpoint,
+        first_authentication,
+    }
+    .generate(state)
+    .await?;
+
+    Ok(SecondFactorPage {
+        token,
+        app,
+        has_totp,
+        has_passkeys,
+        message: MessageBlock::empty(),
+    }
+    .into_response())
+}
+
+/// Without a token the page only offers to sign in again
+#[derive(Template)]
+#[template(path = "auth/second_factor_page.html")]
+pub struct SecondFactorPage {
+    token: String,
+    app: App,
+    has_totp: bool,
+    has_passkeys: bool,
+    message: MessageBlock,
+}
+
+impl SecondFactorPage {
+    fn error(app: App, error: AuthenticatorError) -> Self {
+        Self {
+            token: "".to_owned(),
+            app,
+            has_totp: false,
+            has_passkeys: false,
+            message: MessageBlock::new(Level::Error, "Connexion impossible", &error.to_string()),
+        }
+    }
+
+    /// The user can try again with the same sign in
+    async fn retry(
+        state: &AppState,
+        token: String,
+        app: App,
+        user_id: Uuid,
+        error: AuthenticatorError,
+    ) -> Self {
+        Self {
+            token,
+            app,
+            has_totp: Totp::is_enabled(state, user_id).await.unwrap_or(false),
+            has_passkeys: WebauthnCredential::exists_for_user(state, user_id)
+                .await
+                .unwrap_or(false),
+            message: MessageBlock::new(Level::Error, "Connexion impossible", &error.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SecondFactorForm {
+    token: String,
+    code: String,
+}
+
+pub async fn post_handler(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<SecondFactorForm>,
+) -> Result<impl IntoResponse, SecondFactorPage> {
+    let challenge = SecondFactorChallenge::attempt(&state, &form.token)
+        .await
+        .map_err(|error| SecondFactorPage::error(state.authenticator_app.clone(), error))?;
+
+    let app = App::select_app_or_authenticator(&state, challenge.app_id).await;
+
+    let user = User::select_from_id(&state.db_pool, challenge.user_id)
+        .await
+        .map_err(|error| SecondFactorPage::error(app.clone(), error))?;
+
+    if let Err(error) = Totp::verify(&state, &user, &form.code).await {
+        return Err(SecondFactorPage::retry(&state, form.token, app, user.id, error).await);
+    }
+
+    challenge
+        .complete(
+            cookies,
+            &headers,
+            &state,
+            &form.token,
+            &user,
+            AuthMethod::Otp,
+        )
+        .await
+        .map_err(|error| SecondFactorPage::error(app, error))
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyOptionsForm {
+    token: String,
+}
+
+/// Challenge for one of the credentials of the user signing in
+pub async fn passkey_options_handler(
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<PasskeyOptionsForm>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let options = async {
+        let challenge = SecondFactorChallenge::attempt(&state, &form.token).await?;
+
+        let credentials = WebauthnCredential::select_for_user(&state, challenge.user_id).await?;
+
+        let webauthn_challenge =
+            WebauthnChallenge::generate(&state, Ceremony::SecondFactor, Some(challenge.user_id))
+                .await?;
+
+        Ok::<Value, AuthenticatorError>(RelyingParty::of(&state)?.request_options(
+            &webauthn_challenge,
+            &credentials,
+            "discouraged",
+        ))
+    }
+    .await
+    .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+
+    Ok(Json(options))
+}
+
+#[derive(Deserialize)]
+pub struct PasskeySecondFactorForm {
+    token: String,
+    credential_id: String,
+    client_data: String,
+    authenticator_data: String,
+    signature: String,
+    user_handle: Option<String>,
+}
+
+pub async fn passkey_handler(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<PasskeySecondFactorForm>,
+) -> Result<impl IntoResponse, SecondFactorPage> {
+    let challenge = SecondFactorChallenge::attempt(&state, &form.token)
+        .await
+        .map_err(|error| SecondFactorPage::error(state.authenticator_app.clone(), error))?;
+
+    let app = App::select_app_or_authenticator(&state, challenge.app_id).await;
+
+    let user = User::select_from_id(&state.db_pool, challenge.user_id)
+        .await
+        .map_err(|error| SecondFactorPage::error(app.clone(), error))?;
+
+    let assertion = PasskeyAssertion {
+        credential_id: form.credential_id,
+        client_data: form.client_data,
+        authenticator_data: form.authenticator_data,
+        signature: form.signature,
+        user_handle: form.user_handle,
+    };
+
+    let authenticated =
+        WebauthnCredential::authenticate(&state, Ceremony::SecondFactor, &assertion)
+            .await
+            .and_then(|(credential, _)| {
+                if credential.user_id == user.id {
+                    Ok(())
+                } else {
+                    Err(AuthenticatorError::InvalidPasskey)
+                }
+            });
+
+    if let Err(error) = authenticated {
+        return Err(SecondFactorPage::retry(&state, form.token, app, user.id, error).await);
+    }
+
+    challenge
+        .complete(
+            cookies,
+            &headers,
+            &state,
+            &form.token,
+            &user,
+            AuthMethod::Hwk,
+        )
+        .await
+        .map_err(|error| SecondFactorPage::error(app, error))
+}
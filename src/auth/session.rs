@@ -0,0 +1,373 @@
+use http::{header, HeaderMap};
+use shuttle_runtime::SecretStore;
+use sqlx::{
+    types::{time::OffsetDateTime, Uuid},
+    FromRow,
+};
+use time::{format_description, Duration};
+use tracing::log::error;
+
+use crate::{
+    general::AuthenticatorError,
+    utils::crypto::{hash_text, random_token},
+    AppState,
+};
+
+use super::assurance::Authentication;
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+/// Proxies in front of the authenticator, each one appends the address it received the request from
+const DEFAULT_TRUSTED_PROXY_HOPS: usize = 1;
+
+/// Session of a user on a device saved by the authenticator
+/// The cookie only holds an opaque id (only its hash is saved) so that the session can be revoked
+#[derive(Clone, Debug, FromRow)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub user_agent: String,
+    pub ip_address: String,
+    auth_methods: String,
+    auth_time: i64,
+    pub last_seen_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+}
+
+impl Session {
+    pub fn authentication(&self) -> Authentication {
+        Authentication::from_saved(&self.auth_methods, self.auth_time)
+    }
+
+    /// Device guessed from the user agent
+    pub fn device(&self) -> &'static str {
+        let devices = [
+            ("iPhone", "iPhone"),
+            ("iPad", "iPad"),
+            ("Android", "Android"),
+            ("Windows", "Windows"),
+            ("Macintosh", "Mac"),
+            ("Linux", "Linux"),
+        ];
+
+        devices
+            .iter()
+            .find(|(pattern, _)| self.user_agent.contains(pattern))
+            .map(|(_, device)| *device)
+            .unwrap_or("Appareil inconnu")
+    }
+
+    /// Browser guessed from the user agent (Chrome based browsers also announce Chrome and Safari)
+    pub fn browser(&self) -> &'static str {
+        let browsers = [
+            ("Edg/", "Edge"),
+            ("OPR/", "Opera"),
+            ("Firefox/", "Firefox"),
+            ("Chrome/", "Chrome"),
+            ("Safari/", "Safari"),
+        ];
+
+        browsers
+            .iter()
+            .find(|(pattern, _)| self.user_agent.contains(pattern))
+            .map(|(_, browser)| *browser)
+            .unwrap_or("Navigateur inconnu")
+    }
+
+    pub fn last_activity(&self) -> String {
+        format_description::parse("le [day]/[month]/[year] à [hour]:[minute] UTC")
+            .ok()
+            .and_then(|format| self.last_seen_at.format(&format).ok())
+            .unwrap_or_default()
+    }
+
+    /// Save a new session for the device and give back its opaque id
+    pub async fn create(
+        state: &AppState,
+        user_id: Uuid,
+        authentication: &Authentication,
+        headers: &HeaderMap,
+    ) -> Result<String, AuthenticatorError> {
+        let session_id = random_token();
+
+        let expires_at = OffsetDateTime::now_utc()
+            + Duration::seconds(state.authenticator_app.jwt_seconds_to_expire.into());
+
+        sqlx::query(
+            "INSERT INTO sessions (
+                hashed_session_id,
+                user_id,
+                user_agent,
+                ip_address,
+                auth_methods,
+                auth_time,
+                expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(hash_text(&session_id))
+        .bind(user_id)
+        .bind(header_value(headers, header::USER_AGENT.as_str()))
+        .bind(client_ip(headers, state.trusted_proxy_hops))
+        .bind(authentication.saved_methods())
+        .bind(authentication.time)
+        .bind(expires_at)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Inserting session for {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(session_id)
+    }
+
+    /// Replace the id of a valid session of the user and give back the new one
+    pub async fn rotate(
+        state: &AppState,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<String, AuthenticatorError> {
+        let session_id = random_token();
+
+        sqlx::query(
+            "UPDATE sessions
+            SET
+                hashed_session_id = $3
+            WHERE
+                id = $1
+                AND user_id = $2
+                AND expires_at > CURRENT_TIMESTAMP",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(hash_text(&session_id))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Rotating session {} of {} -> {:?}", id, user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(session_id)
+    }
+
+    /// Get the valid session of the cookie and mark it as seen
+    pub async fn select_from_session_id(
+        state: &AppState,
+        session_id: &str,
+    ) -> Result<Self, AuthenticatorError> {
+        let session: Session = sqlx::query_as(
+            "UPDATE sessions
+            SET
+                last_seen_at = CURRENT_TIMESTAMP
+            WHERE
+                hashed_session_id = $1
+                AND expires_at > CURRENT_TIMESTAMP
+            RETURNING
+                id,
+                user_id,
+                user_agent,
+                ip_address,
+                auth_methods,
+                auth_time,
+                last_seen_at,
+                expires_at",
+        )
+        .bind(hash_text(session_id))
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidToken)?;
+
+        Ok(session)
+    }
+
+    /// Active sessions of the user, the most recently used first
+    pub async fn select_for_user(
+        state: &AppState,
+        user_id: Uuid,
+    ) -> Result<Vec<Self>, AuthenticatorError> {
+        let sessions: Vec<Session> = sqlx::query_as(
+            "SELECT
+                id,
+                user_id,
+                user_agent,
+                ip_address,
+                auth_methods,
+                auth_time,
+                last_seen_at,
+                expires_at
+            FROM sessions
+            WHERE
+                user_id = $1
+                AND expires_at > CURRENT_TIMESTAMP
+            ORDER BY
+                last_seen_at DESC",
+        )
+        .bind(user_id)
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Selecting sessions of {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(sessions)
+    }
+
+    /// Revoke a session of the user (ex: on a lost device)
+    pub async fn delete(
+        state: &AppState,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<(), AuthenticatorError> {
+        sqlx::query(
+            "DELETE FROM sessions
+            WHERE
+                id = $1
+                AND user_id = $2",
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Deleting session {} of {} -> {:?}", id, user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(())
+    }
+
+    /// Revoke all the sessions of the user except the kept one if any
+    pub async fn delete_others(
+        state: &AppState,
+        user_id: Uuid,
+        kept_id: Option<Uuid>,
+    ) -> Result<(), AuthenticatorError> {
+        sqlx::query(
+            "DELETE FROM sessions
+            WHERE
+                user_id = $1
+                AND id IS DISTINCT FROM $2",
+        )
+        .bind(user_id)
+        .bind(kept_id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Deleting other sessions of {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(())
+    }
+
+    /// Revoke the session of the cookie
+    pub async fn delete_from_session_id(
+        state: &AppState,
+        session_id: &str,
+    ) -> Result<(), AuthenticatorError> {
+        sqlx::query(
+            "DELETE FROM sessions
+            WHERE
+                hashed_session_id = $1
+                OR expires_at < CURRENT_TIMESTAMP",
+        )
+        .bind(hash_text(session_id))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Deleting session -> {:?}", error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(())
+    }
+}
+
+fn header_value(headers: &HeaderMap, name: &str) -> String {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Number of proxies in front of the authenticator (TRUSTED_PROXY_HOPS, 1 by default)
+pub fn trusted_proxy_hops(secrets: &SecretStore) -> usize {
+    secrets
+        .get("TRUSTED_PROXY_HOPS")
+        .and_then(|hops| hops.trim().parse().ok())
+        .filter(|hops| *hops > 0)
+        .unwrap_or(DEFAULT_TRUSTED_PROXY_HOPS)
+}
+
+/// Address of the client as appended by the first trusted proxy
+/// The addresses on its left are sent by the client and can't be trusted,
+/// so none is given back when the proxies added fewer addresses than expected
+pub fn client_ip(headers: &HeaderMap, trusted_proxy_hops: usize) -> String {
+    let forwarded_for = header_value(headers, FORWARDED_FOR_HEADER);
+    let addresses: Vec<&str> = forwarded_for
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .collect();
+
+    match addresses.len().checked_sub(trusted_proxy_hops) {
+        Some(index) => addresses.get(index).unwrap_or(&"").to_string(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn forwarded_for(addresses: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        if !addresses.is_empty() {
+            headers.insert(
+                FORWARDED_FOR_HEADER,
+                HeaderValue::from_str(&addresses.join(", ")).unwrap(),
+            );
+        }
+
+        headers
+    }
+
+    #[test]
+    fn no_address_without_the_trusted_proxies() {
+        assert_eq!(client_ip(&forwarded_for(&[]), 1), "");
+        assert_eq!(client_ip(&forwarded_for(&[]), 2), "");
+        assert_eq!(client_ip(&forwarded_for(&["203.0.113.7"]), 2), "");
+    }
+
+    #[test]
+    fn the_address_is_the_one_added_by_the_first_trusted_proxy() {
+        assert_eq!(
+            client_ip(&forwarded_for(&["203.0.113.7"]), 1),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            client_ip(&forwarded_for(&["203.0.113.7", "10.0.0.1"]), 2),
+            "203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn addresses_sent_by_the_client_are_ignored() {
+        assert_eq!(
+            client_ip(&forwarded_for(&["198.51.100.1", "203.0.113.7"]), 1),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            client_ip(
+                &forwarded_for(&["198.51.100.1", "203.0.113.7", "10.0.0.1"]),
+                2
+            ),
+            "203.0.113.7"
+        );
+    }
+}
@@ -1,9 +1,7 @@
 use askama_axum::{IntoResponse, Template};
-use axum::{
-    extract::{Query, State},
-    Form,
-};
+use axum::extract::{Query, State};
 use axum_extra::extract::cookie::CookieJar;
+use http::HeaderMap;
 use serde::Deserialize;
 
 use crate::{
@@ -16,7 +14,14 @@ use crate::{
     AppState,
 };
 
-use super::IdSession;
+use super::{
+    assurance::{AuthMethod, Authentication},
+    csrf::CsrfForm,
+    lockout::SigninLockout,
+    second_factor::set_session_or_ask_second_factor,
+    session::client_ip,
+    IdSession,
+};
 
 #[derive(Template)]
 #[template(path = "auth/signin_page.html")]
@@ -119,63 +124,67 @@ pub struct SigninForm {
 
 pub async fn post_handler(
     cookies: CookieJar,
+    headers: HeaderMap,
     State(state): State<AppState>,
-    Form(form): Form<SigninForm>,
+    CsrfForm(form): CsrfForm<SigninForm>,
 ) -> Result<impl IntoResponse, SigninPage> {
     let app_to_connect = App::select_app_or_authenticator(&state, form.app_id).await;
 
-    let user = User::select_from_mail(&state.db_pool, &form.mail)
-        .await
-        .map_err(|error| {
-            SigninPage::for_app_from_form_with_message(
-                app_to_connect.clone(),
-                form.clone(),
-                MessageBlock::new(Level::Error, "Connexion impossible", &error.to_string()),
-            )
-        })?
-        .ok_or(SigninPage::for_app_from_form_with_message(
+    let signin_error = |error: AuthenticatorError| {
+        SigninPage::for_app_from_form_with_message(
             app_to_connect.clone(),
             form.clone(),
-            MessageBlock::new(
-                Level::Error,
-                "Connexion impossible",
-                &AuthenticatorError::WrongCredentials.to_string(),
-            ),
-        ))?;
-
-    let password_is_not_ok = !user
-        .password_match(form.password.clone())
-        .map_err(|error| {
-            SigninPage::for_app_from_form_with_message(
-                app_to_connect.clone(),
-                form.clone(),
-                MessageBlock::new(Level::Error, "Connexion impossible", &error.to_string()),
-            )
-        })?;
-
-    if password_is_not_ok {
-        return Err(SigninPage::for_app_from_form_with_message(
-            app_to_connect,
-            form,
-            MessageBlock::new(
-                Level::Error,
-                "Connexion impossible",
-                &AuthenticatorError::WrongCredentials.to_string(),
-            ),
-        ));
-    }
+            MessageBlock::new(Level::Error, "Connexion impossible", &error.to_string()),
+        )
+    };
 
-    IdSession::set_with_redirect_to_endpoint(
+    let lockout = SigninLockout::new(&form.mail, client_ip(&headers, state.trusted_proxy_hops));
+
+    lockout.check(&state).await.map_err(signin_error)?;
+
+    let user = User::select_from_mail(&state.db_pool, &form.mail)
+        .await
+        .map_err(signin_error)?;
+
+    let password_is_ok = match &user {
+        Some(user) => user
+            .password_match(form.password.clone())
+            .map_err(signin_error)?,
+        None => false,
+    };
+
+    let user = match user {
+        Some(user) if password_is_ok => user,
+        unknown_or_wrong_user => {
+            let is_now_locked = lockout.record_failure(&state).await.map_err(signin_error)?;
+
+            // Unknown mails get locked the same way so that the response doesn't reveal them
+            if is_now_locked {
+                if let Some(user) = unknown_or_wrong_user {
+                    let state_of_mail = state.clone();
+                    tokio::spawn(async move {
+                        let _ = SigninLockout::send_unlock_link(state_of_mail, user).await;
+                    });
+                }
+
+                return Err(signin_error(AuthenticatorError::AccountLocked));
+            }
+
+            return Err(signin_error(AuthenticatorError::WrongCredentials));
+        }
+    };
+
+    lockout.record_success(&state).await.map_err(signin_error)?;
+
+    set_session_or_ask_second_factor(
         cookies,
+        &headers,
         &state,
         &user,
+        app_to_connect.clone(),
+        Authentication::now(vec![AuthMethod::Pwd]),
         form.requested_endpoint.clone(),
     )
-    .map_err(|error| {
-        SigninPage::for_app_from_form_with_message(
-            app_to_connect,
-            form,
-            MessageBlock::new(Level::Error, "Connexion impossible", &error.to_string()),
-        )
-    })
+    .await
+    .map_err(signin_error)
 }
@@ -1,8 +1,11 @@
 use askama_axum::IntoResponse;
+use axum::extract::State;
 use axum_extra::extract::CookieJar;
 
+use crate::AppState;
+
 use super::IdSession;
 
-pub async fn get_handler(cookies: CookieJar) -> impl IntoResponse {
-    IdSession::remove_and_redirect_to(cookies, "/")
+pub async fn get_handler(State(state): State<AppState>, cookies: CookieJar) -> impl IntoResponse {
+    IdSession::remove_and_redirect_to(&state, cookies, "/").await
 }
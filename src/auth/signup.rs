@@ -1,9 +1,7 @@
 use askama_axum::{IntoResponse, Template};
-use axum::{
-    extract::{Query, State},
-    Form,
-};
+use axum::extract::{Query, State};
 use axum_extra::extract::CookieJar;
+use http::HeaderMap;
 use serde::Deserialize;
 
 use crate::{
@@ -13,7 +11,11 @@ use crate::{
     AppState,
 };
 
-use super::IdSession;
+use super::{
+    assurance::{AuthMethod, Authentication},
+    csrf::CsrfForm,
+    IdSession,
+};
 
 #[derive(Template)]
 #[template(path = "auth/signup_page.html")]
@@ -105,8 +107,9 @@ pub struct SignupForm {
 
 pub async fn post_handler(
     cookies: CookieJar,
+    headers: HeaderMap,
     State(state): State<AppState>,
-    Form(form): Form<SignupForm>,
+    CsrfForm(form): CsrfForm<SignupForm>,
 ) -> Result<impl IntoResponse, SignupPage> {
     let app = App::select_app_or_authenticator(&state, form.app_id).await;
 
@@ -130,14 +133,20 @@ pub async fn post_handler(
         )
     })?;
 
-    let _ = ConfirmationMail::from(&state, created_user.clone(), app.clone()).send();
+    let _ = ConfirmationMail::from(&state, created_user.clone(), app.clone())
+        .send()
+        .await;
 
     if let Ok(redirect_with_session) = IdSession::set_with_redirect_to_endpoint(
         cookies,
+        &headers,
         &state,
         &created_user,
+        &Authentication::now(vec![AuthMethod::Pwd]),
         form.requested_endpoint,
-    ) {
+    )
+    .await
+    {
         Ok(redirect_with_session.into_response())
     } else {
         Ok(app.redirect_to().into_response())
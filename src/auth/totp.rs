@@ -0,0 +1,238 @@
+use qrcode::{render::svg, QrCode};
+use rand::RngCore;
+use sqlx::types::{time::OffsetDateTime, Uuid};
+use totp_rs::{Algorithm, TOTP};
+use tracing::log::error;
+
+use crate::{
+    general::AuthenticatorError,
+    users::User,
+    utils::crypto::{cipher_bytes, decipher_bytes},
+    AppState,
+};
+
+const SECRET_LENGTH: usize = 20;
+const DIGITS: usize = 6;
+const SECONDS_PER_STEP: u64 = 30;
+/// Codes of the previous and next steps are also accepted (clock drift of the phone)
+const ACCEPTED_STEPS_DRIFT: u64 = 1;
+
+/// Time based one time password (RFC 6238) generated by an authenticator app of the user
+/// The secret is saved encrypted, and a code can't be used twice
+pub struct Totp;
+
+/// What the user needs to add the authenticator to their app
+#[derive(Clone, Debug)]
+pub struct TotpEnrollment {
+    pub qr_code_svg: String,
+    pub secret: String,
+}
+
+impl Totp {
+    pub async fn is_enabled(state: &AppState, user_id: Uuid) -> Result<bool, AuthenticatorError> {
+        let is_enabled: Option<bool> =
+            sqlx::query_scalar("SELECT is_enabled FROM totp_secrets WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_optional(&state.db_pool)
+                .await
+                .map_err(|error| {
+                    error!("Selecting totp of {} -> {:?}", user_id, error);
+                    AuthenticatorError::DatabaseError
+                })?;
+
+        Ok(is_enabled.unwrap_or(false))
+    }
+
+    /// New secret waiting for a first code to be enabled (an enabled secret is never replaced)
+    pub async fn start_enrollment(
+        state: &AppState,
+        user_id: Uuid,
+    ) -> Result<(), AuthenticatorError> {
+        let mut secret = [0u8; SECRET_LENGTH];
+        rand::thread_rng().fill_bytes(&mut secret);
+
+        sqlx::query(
+            "INSERT INTO totp_secrets (
+                user_id,
+                encrypted_secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET
+                encrypted_secret = $2,
+                last_used_step = 0,
+                created_at = CURRENT_TIMESTAMP
+            WHERE
+                totp_secrets.is_enabled = false",
+        )
+        .bind(user_id)
+        .bind(cipher_bytes(&state.encryption_key, &secret)?)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Saving totp secret of {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(())
+    }
+
+    /// QR code and secret of the enrollment in progress, if any
+    pub async fn pending_enrollment(
+        state: &AppState,
+        user: &User,
+    ) -> Result<Option<TotpEnrollment>, AuthenticatorError> {
+        let totp = match Self::select(state, user, false).await? {
+            Some((totp, _)) => totp,
+            None => return Ok(None),
+        };
+
+        let qr_code_svg = QrCode::new(totp.get_url().as_bytes())
+            .map_err(|error| {
+                error!("Generating totp qr code of {} -> {:?}", user.id, error);
+                AuthenticatorError::CryptoError
+            })?
+            .render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build();
+
+        Ok(Some(TotpEnrollment {
+            qr_code_svg,
+            secret: totp.get_secret_base32(),
+        }))
+    }
+
+    /// The first valid code proves that the app of the user is set up
+    pub async fn confirm_enrollment(
+        state: &AppState,
+        user: &User,
+        code: &str,
+    ) -> Result<(), AuthenticatorError> {
+        let (totp, _) = Self::select(state, user, false)
+            .await?
+            .ok_or(AuthenticatorError::InvalidTotpCode)?;
+
+        let step = Self::matching_step(&totp, code).ok_or(AuthenticatorError::InvalidTotpCode)?;
+
+        sqlx::query(
+            "UPDATE totp_secrets
+            SET
+                is_enabled = true,
+                last_used_step = $2
+            WHERE
+                user_id = $1",
+        )
+        .bind(user.id)
+        .bind(step as i64)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Enabling totp of {} -> {:?}", user.id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(())
+    }
+
+    /// Check a code of the enabled authenticator, it can't be used again afterwards
+    pub async fn verify(
+        state: &AppState,
+        user: &User,
+        code: &str,
+    ) -> Result<(), AuthenticatorError> {
+        let (totp, last_used_step) = Self::select(state, user, true)
+            .await?
+            .ok_or(AuthenticatorError::InvalidTotpCode)?;
+
+        let step = Self::matching_step(&totp, code)
+            .filter(|step| *step as i64 > last_used_step)
+            .ok_or(AuthenticatorError::InvalidTotpCode)?;
+
+        // The condition makes sure that two requests can't use the same code at once
+        let updated = sqlx::query(
+            "UPDATE totp_secrets
+            SET
+                last_used_step = $2
+            WHERE
+                user_id = $1
+                AND last_used_step < $2",
+        )
+        .bind(user.id)
+        .bind(step as i64)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Using totp code of {} -> {:?}", user.id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        if updated.rows_affected() == 1 {
+            Ok(())
+        } else {
+            Err(AuthenticatorError::InvalidTotpCode)
+        }
+    }
+
+    pub async fn disable(state: &AppState, user_id: Uuid) -> Result<(), AuthenticatorError> {
+        sqlx::query("DELETE FROM totp_secrets WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Deleting totp of {} -> {:?}", user_id, error);
+                AuthenticatorError::DatabaseError
+            })?;
+
+        Ok(())
+    }
+
+    async fn select(
+        state: &AppState,
+        user: &User,
+        is_enabled: bool,
+    ) -> Result<Option<(TOTP, i64)>, AuthenticatorError> {
+        let saved: Option<(String, i64)> = sqlx::query_as(
+            "SELECT
+                encrypted_secret,
+                last_used_step
+            FROM totp_secrets
+            WHERE
+                user_id = $1
+                AND is_enabled = $2",
+        )
+        .bind(user.id)
+        .bind(is_enabled)
+        .fetch_optional(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Selecting totp secret of {} -> {:?}", user.id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        let (encrypted_secret, last_used_step) = match saved {
+            Some(saved) => saved,
+            None => return Ok(None),
+        };
+
+        let secret = decipher_bytes(&state.encryption_key, &encrypted_secret)?;
+
+        let totp = TOTP::new_unchecked(
+            Algorithm::SHA1,
+            DIGITS,
+            0,
+            SECONDS_PER_STEP,
+            secret,
+            Some(state.authenticator_app.name.clone()),
+            user.mail.clone(),
+        );
+
+        Ok(Some((totp, last_used_step)))
+    }
+
+    /// Time step of the code, looking around the current one
+    fn matching_step(totp: &TOTP, code: &str) -> Option<u64> {
+        let current_step = OffsetDateTime::now_utc().unix_timestamp() as u64 / SECONDS_PER_STEP;
+
+        (current_step - ACCEPTED_STEPS_DRIFT..=current_step + ACCEPTED_STEPS_DRIFT)
+            .find(|step| totp.check(code.trim(), step * SECONDS_PER_STEP))
+    }
+}
@@ -0,0 +1,803 @@
+use std::io::Cursor;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{
+    types::{time::OffsetDateTime, Uuid},
+    FromRow,
+};
+use time::{format_description, Duration};
+use tracing::log::error;
+use url::Url;
+
+use crate::{
+    general::AuthenticatorError,
+    utils::crypto::{hash_text, random_token},
+    AppState,
+};
+
+const CHALLENGE_SECONDS_TO_EXPIRE: i64 = 300;
+/// Only ECDSA P-256 with SHA-256 keys are accepted (COSE algorithm -7)
+const ES256: i64 = -7;
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// What a WebAuthn challenge has been given for
+#[derive(Clone, Copy, Debug, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum Ceremony {
+    Registration,
+    Signin,
+    SecondFactor,
+}
+
+/// Random challenge signed by the authenticator (only its hash is saved)
+/// A challenge given to a known user can only be used by this user
+pub struct WebauthnChallenge;
+
+impl WebauthnChallenge {
+    /// Anyone can ask for a sign in challenge: the expired ones are deleted at each new one
+    /// and the endpoint is rate limited so that the table stays small
+    pub async fn generate(
+        state: &AppState,
+        ceremony: Ceremony,
+        user_id: Option<Uuid>,
+    ) -> Result<String, AuthenticatorError> {
+        let challenge = random_token();
+
+        sqlx::query("DELETE FROM webauthn_challenges WHERE expires_at < CURRENT_TIMESTAMP")
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Deleting expired webauthn challenges -> {:?}", error);
+                AuthenticatorError::DatabaseError
+            })?;
+
+        sqlx::query(
+            "INSERT INTO webauthn_challenges (
+                hashed_challenge,
+                ceremony,
+                user_id,
+                expires_at)
+            VALUES ($1, $2, $3, $4)",
+        )
+        .bind(hash_text(&challenge))
+        .bind(ceremony)
+        .bind(user_id)
+        .bind(OffsetDateTime::now_utc() + Duration::seconds(CHALLENGE_SECONDS_TO_EXPIRE))
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Inserting webauthn challenge {:?} -> {:?}", ceremony, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(challenge)
+    }
+
+    /// Get the user the challenge was given to and delete it so that it can't be used twice
+    async fn consume(
+        state: &AppState,
+        ceremony: Ceremony,
+        challenge: &str,
+    ) -> Result<Option<Uuid>, AuthenticatorError> {
+        let (user_id, expires_at): (Option<Uuid>, OffsetDateTime) = sqlx::query_as(
+            "DELETE FROM webauthn_challenges
+            WHERE
+                hashed_challenge = $1
+                AND ceremony = $2
+            RETURNING
+                user_id,
+                expires_at",
+        )
+        .bind(hash_text(challenge))
+        .bind(ceremony)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidPasskey)?;
+
+        if expires_at < OffsetDateTime::now_utc() {
+            return Err(AuthenticatorError::InvalidPasskey);
+        }
+
+        Ok(user_id)
+    }
+}
+
+/// The authenticator itself, credentials are bound to its domain and origin
+#[derive(Clone, Debug)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    pub fn of(state: &AppState) -> Result<Self, AuthenticatorError> {
+        let base_url = Url::parse(&state.authenticator_app.base_url)
+            .map_err(|_| AuthenticatorError::AppInvalidUri)?;
+
+        Ok(Self {
+            id: state.authenticator_app.domain()?,
+            name: state.authenticator_app.name.clone(),
+            origin: base_url.origin().ascii_serialization(),
+        })
+    }
+
+    /// Options given to navigator.credentials.create()
+    pub fn creation_options(
+        &self,
+        challenge: &str,
+        user_id: Uuid,
+        mail: &str,
+        name: &str,
+        existing_credentials: &[WebauthnCredential],
+    ) -> serde_json::Value {
+        json!({
+            "challenge": challenge,
+            "rp": { "id": self.id, "name": self.name },
+            "user": {
+                "id": URL_SAFE_NO_PAD.encode(user_id.as_bytes()),
+                "name": mail,
+                "displayName": name,
+            },
+            "pubKeyCredParams": [{ "type": "public-key", "alg": ES256 }],
+            "timeout": CHALLENGE_SECONDS_TO_EXPIRE * 1000,
+            "attestation": "none",
+            "excludeCredentials": Self::descriptors(existing_credentials),
+            "authenticatorSelection": {
+                "residentKey": "preferred",
+                "userVerification": "preferred",
+            },
+        })
+    }
+
+    /// Options given to navigator.credentials.get(), without credentials any discoverable one can be used
+    pub fn request_options(
+        &self,
+        challenge: &str,
+        allowed_credentials: &[WebauthnCredential],
+        user_verification: &str,
+    ) -> serde_json::Value {
+        json!({
+            "challenge": challenge,
+            "rpId": self.id,
+            "timeout": CHALLENGE_SECONDS_TO_EXPIRE * 1000,
+            "userVerification": user_verification,
+            "allowCredentials": Self::descriptors(allowed_credentials),
+        })
+    }
+
+    fn descriptors(credentials: &[WebauthnCredential]) -> Vec<serde_json::Value> {
+        credentials
+            .iter()
+            .map(|credential| json!({ "type": "public-key", "id": credential.credential_id }))
+            .collect()
+    }
+
+    /// Check a navigator.credentials.create() response and give back the challenge and the new key
+    pub fn verify_registration(
+        &self,
+        client_data_json: &str,
+        attestation_object: &str,
+    ) -> Result<(String, NewCredential), AuthenticatorError> {
+        let challenge = self.check_client_data(client_data_json, "webauthn.create")?;
+
+        // Attestation is not requested, the statement is not checked
+        let attestation =
+            ciborium::de::from_reader::<Value, _>(decode(attestation_object)?.as_slice())
+                .map_err(|_| AuthenticatorError::InvalidPasskey)?;
+
+        let authenticator_data = attestation
+            .as_map()
+            .and_then(|entries| {
+                entries
+                    .iter()
+                    .find(|(key, _)| key.as_text() == Some("authData"))
+            })
+            .and_then(|(_, value)| value.as_bytes())
+            .ok_or(AuthenticatorError::InvalidPasskey)?;
+
+        let authenticator_data = self.check_authenticator_data(authenticator_data)?;
+
+        let (credential_id, public_key) = authenticator_data
+            .attested_credential
+            .ok_or(AuthenticatorError::InvalidPasskey)?;
+
+        Ok((
+            challenge,
+            NewCredential {
+                credential_id: URL_SAFE_NO_PAD.encode(credential_id),
+                public_key: URL_SAFE_NO_PAD.encode(public_key),
+                sign_count: authenticator_data.sign_count,
+            },
+        ))
+    }
+
+    /// Check a navigator.credentials.get() response signed by the saved key
+    /// and give back the challenge, the new signature counter and if the user was verified
+    pub fn verify_assertion(
+        &self,
+        public_key: &str,
+        saved_sign_count: u32,
+        assertion: &PasskeyAssertion,
+    ) -> Result<(String, u32, bool), AuthenticatorError> {
+        let challenge = self.check_client_data(&assertion.client_data, "webauthn.get")?;
+
+        let authenticator_data_bytes = decode(&assertion.authenticator_data)?;
+        let authenticator_data = self.check_authenticator_data(&authenticator_data_bytes)?;
+
+        let verifying_key = VerifyingKey::from_sec1_bytes(&decode(public_key)?)
+            .map_err(|_| AuthenticatorError::InvalidPasskey)?;
+
+        let signature = Signature::from_der(&decode(&assertion.signature)?)
+            .map_err(|_| AuthenticatorError::InvalidPasskey)?;
+
+        let signed_data = [
+            authenticator_data_bytes.as_slice(),
+            Sha256::digest(decode(&assertion.client_data)?).as_slice(),
+        ]
+        .concat();
+
+        verifying_key
+            .verify(&signed_data, &signature)
+            .map_err(|_| AuthenticatorError::InvalidPasskey)?;
+
+        // A counter that doesn't increase reveals a cloned authenticator (0 = no counter)
+        if authenticator_data.sign_count != 0 && authenticator_data.sign_count <= saved_sign_count {
+            return Err(AuthenticatorError::InvalidPasskey);
+        }
+
+        Ok((
+            challenge,
+            authenticator_data.sign_count,
+            authenticator_data.flags & FLAG_USER_VERIFIED != 0,
+        ))
+    }
+
+    fn check_client_data(
+        &self,
+        client_data_json: &str,
+        expected_type: &str,
+    ) -> Result<String, AuthenticatorError> {
+        let client_data: ClientData = serde_json::from_slice(&decode(client_data_json)?)
+            .map_err(|_| AuthenticatorError::InvalidPasskey)?;
+
+        if client_data.ceremony_type != expected_type || client_data.origin != self.origin {
+            return Err(AuthenticatorError::InvalidPasskey);
+        }
+
+        Ok(client_data.challenge)
+    }
+
+    fn check_authenticator_data(
+        &self,
+        bytes: &[u8],
+    ) -> Result<AuthenticatorData, AuthenticatorError> {
+        let authenticator_data = AuthenticatorData::parse(bytes)?;
+
+        if authenticator_data.rp_id_hash != Sha256::digest(self.id.as_bytes()).as_slice()
+            || authenticator_data.flags & FLAG_USER_PRESENT == 0
+        {
+            return Err(AuthenticatorError::InvalidPasskey);
+        }
+
+        Ok(authenticator_data)
+    }
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony_type: String,
+    challenge: String,
+    origin: String,
+}
+
+/// https://www.w3.org/TR/webauthn-2/#sctn-authenticator-data
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    /// Credential id and public key (SEC1 encoded), only given at registration
+    attested_credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl AuthenticatorData {
+    fn parse(bytes: &[u8]) -> Result<Self, AuthenticatorError> {
+        if bytes.len() < 37 {
+            return Err(AuthenticatorError::InvalidPasskey);
+        }
+
+        let flags = bytes[32];
+        let sign_count = u32::from_be_bytes([bytes[33], bytes[34], bytes[35], bytes[36]]);
+
+        let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+            // aaguid (16 bytes) then the length of the credential id (2 bytes)
+            let credential_data = bytes.get(37..).ok_or(AuthenticatorError::InvalidPasskey)?;
+
+            if credential_data.len() < 18 {
+                return Err(AuthenticatorError::InvalidPasskey);
+            }
+
+            let id_length = u16::from_be_bytes([credential_data[16], credential_data[17]]) as usize;
+            let credential_id = credential_data
+                .get(18..18 + id_length)
+                .ok_or(AuthenticatorError::InvalidPasskey)?;
+
+            let cose_key = ciborium::de::from_reader::<Value, _>(Cursor::new(
+                &credential_data[18 + id_length..],
+            ))
+            .map_err(|_| AuthenticatorError::InvalidPasskey)?;
+
+            Some((credential_id.to_vec(), sec1_public_key(&cose_key)?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            rp_id_hash: bytes[..32].to_vec(),
+            flags,
+            sign_count,
+            attested_credential,
+        })
+    }
+}
+
+/// Uncompressed SEC1 point of an EC2 P-256 COSE key (RFC 9053)
+fn sec1_public_key(cose_key: &Value) -> Result<Vec<u8>, AuthenticatorError> {
+    let entries = cose_key
+        .as_map()
+        .ok_or(AuthenticatorError::InvalidPasskey)?;
+
+    let entry = |label: i64| {
+        entries
+            .iter()
+            .find(|(key, _)| key.as_integer() == Some(label.into()))
+            .map(|(_, value)| value)
+    };
+
+    let integer = |label: i64| {
+        entry(label)
+            .and_then(Value::as_integer)
+            .and_then(|value| i64::try_from(value).ok())
+    };
+
+    // kty = EC2, alg = ES256, crv = P-256
+    if integer(1) != Some(2) || integer(3) != Some(ES256) || integer(-1) != Some(1) {
+        return Err(AuthenticatorError::InvalidPasskey);
+    }
+
+    let x = entry(-2)
+        .and_then(Value::as_bytes)
+        .ok_or(AuthenticatorError::InvalidPasskey)?;
+    let y = entry(-3)
+        .and_then(Value::as_bytes)
+        .ok_or(AuthenticatorError::InvalidPasskey)?;
+
+    let public_key = [&[0x04], x.as_slice(), y.as_slice()].concat();
+
+    VerifyingKey::from_sec1_bytes(&public_key).map_err(|_| AuthenticatorError::InvalidPasskey)?;
+
+    Ok(public_key)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, AuthenticatorError> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| AuthenticatorError::InvalidPasskey)
+}
+
+/// Key created by an authenticator, checked but not saved yet
+pub struct NewCredential {
+    credential_id: String,
+    public_key: String,
+    sign_count: u32,
+}
+
+/// Response of navigator.credentials.get() (base64url encoded)
+pub struct PasskeyAssertion {
+    pub credential_id: String,
+    pub client_data: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+/// Passkey or security key of a user
+#[derive(Clone, Debug, FromRow)]
+pub struct WebauthnCredential {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    credential_id: String,
+    public_key: String,
+    sign_count: i64,
+    pub created_at: OffsetDateTime,
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+impl WebauthnCredential {
+    pub fn last_use(&self) -> String {
+        match self.last_used_at {
+            Some(last_used_at) => format_description::parse("le [day]/[month]/[year]")
+                .ok()
+                .and_then(|format| last_used_at.format(&format).ok())
+                .unwrap_or_default(),
+            None => "jamais".to_owned(),
+        }
+    }
+
+    pub async fn select_for_user(
+        state: &AppState,
+        user_id: Uuid,
+    ) -> Result<Vec<Self>, AuthenticatorError> {
+        sqlx::query_as(
+            "SELECT
+                id,
+                user_id,
+                name,
+                credential_id,
+                public_key,
+                sign_count,
+                created_at,
+                last_used_at
+            FROM webauthn_credentials
+            WHERE
+                user_id = $1
+            ORDER BY created_at",
+        )
+        .bind(user_id)
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Selecting webauthn credentials of {} -> {:?}",
+                user_id, error
+            );
+            AuthenticatorError::DatabaseError
+        })
+    }
+
+    pub async fn exists_for_user(
+        state: &AppState,
+        user_id: Uuid,
+    ) -> Result<bool, AuthenticatorError> {
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM webauthn_credentials WHERE user_id = $1)")
+            .bind(user_id)
+            .fetch_one(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!(
+                    "Checking webauthn credentials of {} -> {:?}",
+                    user_id, error
+                );
+                AuthenticatorError::DatabaseError
+            })
+    }
+
+    async fn select_from_credential_id(
+        state: &AppState,
+        credential_id: &str,
+    ) -> Result<Self, AuthenticatorError> {
+        sqlx::query_as(
+            "SELECT
+                id,
+                user_id,
+                name,
+                credential_id,
+                public_key,
+                sign_count,
+                created_at,
+                last_used_at
+            FROM webauthn_credentials
+            WHERE
+                credential_id = $1",
+        )
+        .bind(credential_id)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidPasskey)
+    }
+
+    /// Check the response of the authenticator and save the new credential for the user
+    pub async fn register(
+        state: &AppState,
+        user_id: Uuid,
+        name: &str,
+        client_data: &str,
+        attestation_object: &str,
+    ) -> Result<(), AuthenticatorError> {
+        let (challenge, new_credential) =
+            RelyingParty::of(state)?.verify_registration(client_data, attestation_object)?;
+
+        if WebauthnChallenge::consume(state, Ceremony::Registration, &challenge).await?
+            != Some(user_id)
+        {
+            return Err(AuthenticatorError::InvalidPasskey);
+        }
+
+        sqlx::query(
+            "INSERT INTO webauthn_credentials (
+                user_id,
+                name,
+                credential_id,
+                public_key,
+                sign_count)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(user_id)
+        .bind(name.trim())
+        .bind(&new_credential.credential_id)
+        .bind(&new_credential.public_key)
+        .bind(new_credential.sign_count as i64)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Inserting webauthn credential for {} -> {:?}",
+                user_id, error
+            );
+            AuthenticatorError::InvalidPasskey
+        })?;
+
+        Ok(())
+    }
+
+    /// Check a signed challenge and give back the credential that signed it and if the user was verified
+    /// For a known user (second factor) the credential has to be one of theirs
+    pub async fn authenticate(
+        state: &AppState,
+        ceremony: Ceremony,
+        assertion: &PasskeyAssertion,
+    ) -> Result<(Self, bool), AuthenticatorError> {
+        let credential = Self::select_from_credential_id(state, &assertion.credential_id).await?;
+
+        let user_handle_matches = match &assertion.user_handle {
+            Some(user_handle) if !user_handle.is_empty() => {
+                decode(user_handle)? == credential.user_id.as_bytes()
+            }
+            _ => true,
+        };
+
+        if !user_handle_matches {
+            return Err(AuthenticatorError::InvalidPasskey);
+        }
+
+        let (challenge, sign_count, user_verified) = RelyingParty::of(state)?.verify_assertion(
+            &credential.public_key,
+            credential.sign_count as u32,
+            assertion,
+        )?;
+
+        match WebauthnChallenge::consume(state, ceremony, &challenge).await? {
+            Some(user_id) if user_id != credential.user_id => {
+                return Err(AuthenticatorError::InvalidPasskey)
+            }
+            _ => {}
+        }
+
+        sqlx::query(
+            "UPDATE webauthn_credentials
+            SET
+                sign_count = $2,
+                last_used_at = CURRENT_TIMESTAMP
+            WHERE
+                id = $1",
+        )
+        .bind(credential.id)
+        .bind(sign_count as i64)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Updating webauthn credential {} -> {:?}",
+                credential.id, error
+            );
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok((credential, user_verified))
+    }
+
+    pub async fn delete(
+        state: &AppState,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<(), AuthenticatorError> {
+        sqlx::query("DELETE FROM webauthn_credentials WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Deleting webauthn credential {} -> {:?}", id, error);
+                AuthenticatorError::DatabaseError
+            })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    use super::*;
+
+    /// Authenticator in memory signing like a passkey would
+    struct SoftwareAuthenticator {
+        key: SigningKey,
+        credential_id: Vec<u8>,
+        sign_count: u32,
+    }
+
+    impl SoftwareAuthenticator {
+        fn new() -> Self {
+            Self {
+                key: SigningKey::from_slice(&[7u8; 32]).unwrap(),
+                credential_id: vec![1, 2, 3, 4],
+                sign_count: 0,
+            }
+        }
+
+        fn client_data(ceremony_type: &str, challenge: &str, origin: &str) -> String {
+            URL_SAFE_NO_PAD.encode(
+                json!({ "type": ceremony_type, "challenge": challenge, "origin": origin })
+                    .to_string(),
+            )
+        }
+
+        fn authenticator_data(&self, rp_id: &str, flags: u8, attested: bool) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend(self.sign_count.to_be_bytes());
+
+            if attested {
+                let point = self.key.verifying_key().to_encoded_point(false);
+                let cose_key = Value::Map(vec![
+                    (1.into(), 2.into()),
+                    (3.into(), ES256.into()),
+                    ((-1).into(), 1.into()),
+                    ((-2).into(), Value::Bytes(point.x().unwrap().to_vec())),
+                    ((-3).into(), Value::Bytes(point.y().unwrap().to_vec())),
+                ]);
+
+                data.extend([0u8; 16]);
+                data.extend((self.credential_id.len() as u16).to_be_bytes());
+                data.extend(&self.credential_id);
+                ciborium::ser::into_writer(&cose_key, &mut data).unwrap();
+            }
+
+            data
+        }
+
+        fn create(&self, rp: &RelyingParty, challenge: &str) -> (String, String) {
+            let attestation = Value::Map(vec![
+                ("fmt".into(), "none".into()),
+                ("attStmt".into(), Value::Map(vec![])),
+                (
+                    "authData".into(),
+                    Value::Bytes(self.authenticator_data(
+                        &rp.id,
+                        FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_CREDENTIAL,
+                        true,
+                    )),
+                ),
+            ]);
+
+            let mut attestation_object = vec![];
+            ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+
+            (
+                Self::client_data("webauthn.create", challenge, &rp.origin),
+                URL_SAFE_NO_PAD.encode(attestation_object),
+            )
+        }
+
+        fn get(&mut self, rp_id: &str, origin: &str, challenge: &str) -> PasskeyAssertion {
+            self.sign_count += 1;
+
+            let client_data = Self::client_data("webauthn.get", challenge, origin);
+            let authenticator_data =
+                self.authenticator_data(rp_id, FLAG_USER_PRESENT | FLAG_USER_VERIFIED, false);
+
+            let signed_data = [
+                authenticator_data.as_slice(),
+                Sha256::digest(decode(&client_data).unwrap()).as_slice(),
+            ]
+            .concat();
+            let signature: Signature = self.key.sign(&signed_data);
+
+            PasskeyAssertion {
+                credential_id: URL_SAFE_NO_PAD.encode(&self.credential_id),
+                client_data,
+                authenticator_data: URL_SAFE_NO_PAD.encode(authenticator_data),
+                signature: URL_SAFE_NO_PAD.encode(signature.to_der()),
+                user_handle: None,
+            }
+        }
+    }
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty {
+            id: "auth.example.com".to_owned(),
+            name: "whoami".to_owned(),
+            origin: "https://auth.example.com".to_owned(),
+        }
+    }
+
+    fn registered(authenticator: &SoftwareAuthenticator) -> NewCredential {
+        let rp = relying_party();
+        let (client_data, attestation_object) = authenticator.create(&rp, "registration");
+
+        let (challenge, credential) = rp
+            .verify_registration(&client_data, &attestation_object)
+            .unwrap();
+        assert_eq!(challenge, "registration");
+
+        credential
+    }
+
+    #[test]
+    fn registered_credential_verifies_its_assertions() {
+        let rp = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new();
+        let credential = registered(&authenticator);
+
+        assert_eq!(
+            credential.credential_id,
+            URL_SAFE_NO_PAD.encode(&authenticator.credential_id)
+        );
+
+        let assertion = authenticator.get(&rp.id, &rp.origin, "signin");
+        let (challenge, sign_count, user_verified) = rp
+            .verify_assertion(&credential.public_key, credential.sign_count, &assertion)
+            .unwrap();
+
+        assert_eq!(challenge, "signin");
+        assert_eq!(sign_count, 1);
+        assert!(user_verified);
+    }
+
+    #[test]
+    fn assertion_for_another_site_is_rejected() {
+        let rp = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new();
+        let credential = registered(&authenticator);
+
+        let phished = authenticator.get(&rp.id, "https://auth.example.com.evil.io", "signin");
+        assert!(rp
+            .verify_assertion(&credential.public_key, 0, &phished)
+            .is_err());
+
+        let other_rp_id = authenticator.get("evil.io", &rp.origin, "signin");
+        assert!(rp
+            .verify_assertion(&credential.public_key, 0, &other_rp_id)
+            .is_err());
+    }
+
+    #[test]
+    fn tampered_or_replayed_assertion_is_rejected() {
+        let rp = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new();
+        let credential = registered(&authenticator);
+
+        let mut tampered = authenticator.get(&rp.id, &rp.origin, "signin");
+        tampered.client_data =
+            SoftwareAuthenticator::client_data("webauthn.get", "other", &rp.origin);
+        assert!(rp
+            .verify_assertion(&credential.public_key, 0, &tampered)
+            .is_err());
+
+        let assertion = authenticator.get(&rp.id, &rp.origin, "signin");
+        assert!(rp
+            .verify_assertion(&credential.public_key, authenticator.sign_count, &assertion)
+            .is_err());
+    }
+}
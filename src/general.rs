@@ -4,6 +4,7 @@ use serde::Deserialize;
 
 pub mod message;
 pub mod navbar;
+pub mod rate_limit;
 pub mod whoami;
 
 #[derive(Debug, Deserialize)]
@@ -27,6 +28,20 @@ pub enum AuthenticatorError {
     AppNotFound,
     AppInvalidUri,
     InvalidDate,
+    InvalidAuthorizationCode,
+    GrantNotFound,
+    ResourceNotFound,
+    InvalidResetLink,
+    InvalidMagicLink,
+    MagicLinkNotAllowed,
+    InvalidMailCode,
+    InvalidTotpCode,
+    ExpiredSignin,
+    InvalidPasskey,
+    InvalidRecoveryCode,
+    SigninDelayed,
+    AccountLocked,
+    InvalidUnlockLink,
 }
 
 impl fmt::Display for AuthenticatorError {
@@ -59,6 +74,34 @@ impl fmt::Display for AuthenticatorError {
             AuthenticatorError::AppInvalidUri => "L'Url de l'application est invalide",
             AuthenticatorError::InvalidDate => "Date invalide",
             AuthenticatorError::Unauthorized => "Vous n'avez pas les droits",
+            AuthenticatorError::InvalidAuthorizationCode => "Le code d'autorisation est invalide",
+            AuthenticatorError::GrantNotFound => "L'app n'a pas été autorisée",
+            AuthenticatorError::ResourceNotFound => "L'API est introuvable",
+            AuthenticatorError::InvalidResetLink => {
+                "Le lien de réinitialisation est invalide ou a expiré"
+            }
+            AuthenticatorError::InvalidMagicLink => "Le lien de connexion est invalide ou a expiré",
+            AuthenticatorError::MagicLinkNotAllowed => {
+                "L'app n'autorise pas la connexion sans mot de passe par mail"
+            }
+            AuthenticatorError::InvalidMailCode => "Le code est invalide ou a expiré",
+            AuthenticatorError::InvalidTotpCode => {
+                "Le code de l'application d'authentification est invalide"
+            }
+            AuthenticatorError::ExpiredSignin => "La connexion a expiré, veuillez recommencer",
+            AuthenticatorError::InvalidPasskey => "La clé d'accès est invalide ou inconnue",
+            AuthenticatorError::InvalidRecoveryCode => {
+                "Le code de récupération est invalide ou a déjà été utilisé"
+            }
+            AuthenticatorError::SigninDelayed => {
+                "Trop de tentatives de connexion, veuillez patienter quelques instants avant de réessayer"
+            }
+            AuthenticatorError::AccountLocked => {
+                "Le compte est bloqué pour 30 minutes après trop de tentatives, si ce compte existe un mail a été envoyé pour le débloquer"
+            }
+            AuthenticatorError::InvalidUnlockLink => {
+                "Le lien de déblocage est invalide ou a expiré"
+            }
         };
 
         write!(f, "{}", message)
@@ -0,0 +1,518 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use askama::Template;
+use askama_axum::{IntoResponse, Response};
+use axum::{
+    async_trait,
+    body::{to_bytes, Body},
+    extract::Request,
+};
+use http::{header, HeaderValue, StatusCode};
+use rand::Rng;
+use shuttle_runtime::SecretStore;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tower::{Layer, Service};
+use tracing::log::error;
+use url::form_urlencoded;
+
+use crate::{
+    auth::session::{client_ip, trusted_proxy_hops},
+    utils::crypto::hash_text,
+};
+
+use super::AuthenticatorError;
+
+/// Public endpoints that can be abused (guessing passwords, sending mails, saving challenges...)
+/// Paths are prefixes: "/passkey_signin" also limits "/passkey_signin_options"
+const LIMITED_PATHS: [&str; 12] = [
+    "/signin",
+    "/signup",
+    "/send_confirm",
+    "/magic_link",
+    "/mail_code",
+    "/second_factor",
+    "/passkey_signin",
+    "/recovery",
+    "/unlock",
+    "/forgot_password",
+    "/reset_password",
+    "/openid/",
+];
+/// Forms are read to find the account and the app, bigger bodies are refused
+const MAX_FORM_BYTES: usize = 64 * 1024;
+/// The memory store is cleaned of the ended windows once it holds this many windows
+const MAX_MEMORY_WINDOWS: usize = 10_000;
+
+/// Requests allowed for a key during a window, set in the secrets as "limit/seconds"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    limit: i64,
+    window_seconds: i64,
+}
+
+impl Quota {
+    fn from_secret(secrets: &SecretStore, name: &str, default: Quota) -> Self {
+        secrets
+            .get(name)
+            .and_then(|quota| {
+                let (limit, window_seconds) = quota.split_once('/')?;
+
+                Some(Quota {
+                    limit: limit.trim().parse().ok()?,
+                    window_seconds: window_seconds.trim().parse().ok()?,
+                })
+            })
+            .filter(|quota| quota.window_seconds > 0)
+            .unwrap_or(default)
+    }
+}
+
+/// Counts of requests per key in fixed windows
+#[async_trait]
+pub trait RateLimitStore: Send + Sync + Debug {
+    /// Count a request of the key, gives back the requests of the window it is in
+    /// The window can be forgotten once ended
+    async fn hit(
+        &self,
+        key: &str,
+        window_start: i64,
+        window_end: i64,
+    ) -> Result<i64, AuthenticatorError>;
+}
+
+/// Counts kept by this instance only (lost at restart)
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    /// Hits and end of the window of each key
+    windows: Mutex<HashMap<(String, i64), (i64, i64)>>,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn hit(
+        &self,
+        key: &str,
+        window_start: i64,
+        window_end: i64,
+    ) -> Result<i64, AuthenticatorError> {
+        let mut windows = self
+            .windows
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let window_key = (key.to_owned(), window_start);
+
+        if windows.len() >= MAX_MEMORY_WINDOWS && !windows.contains_key(&window_key) {
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            windows.retain(|_, (_, end)| *end > now);
+
+            // Every window is still running: the one ending first makes room for the new key
+            if windows.len() >= MAX_MEMORY_WINDOWS {
+                let oldest_key = windows
+                    .iter()
+                    .min_by_key(|(_, (_, end))| *end)
+                    .map(|(key, _)| key.clone());
+
+                if let Some(oldest_key) = oldest_key {
+                    windows.remove(&oldest_key);
+                }
+            }
+        }
+
+        let (hits, _) = windows.entry(window_key).or_insert((0, window_end));
+        *hits += 1;
+
+        Ok(*hits)
+    }
+}
+
+/// Counts shared by every instance of the authenticator
+#[derive(Debug)]
+pub struct PostgresStore {
+    db_pool: PgPool,
+}
+
+#[async_trait]
+impl RateLimitStore for PostgresStore {
+    async fn hit(
+        &self,
+        key: &str,
+        window_start: i64,
+        window_end: i64,
+    ) -> Result<i64, AuthenticatorError> {
+        // Ended windows are cleaned from time to time rather than at each request
+        if rand::thread_rng().gen_ratio(1, 100) {
+            sqlx::query("DELETE FROM rate_limit_windows WHERE window_end <= $1")
+                .bind(OffsetDateTime::now_utc().unix_timestamp())
+                .execute(&self.db_pool)
+                .await
+                .map_err(|error| {
+                    error!("Deleting old rate limit windows -> {:?}", error);
+                    AuthenticatorError::DatabaseError
+                })?;
+        }
+
+        let hits: i64 = sqlx::query_scalar(
+            "INSERT INTO rate_limit_windows (
+                key,
+                window_start,
+                window_end,
+                hits)
+            VALUES ($1, $2, $3, 1)
+            ON CONFLICT (key, window_start) DO UPDATE
+            SET
+                hits = rate_limit_windows.hits + 1
+            RETURNING
+                hits",
+        )
+        .bind(key)
+        .bind(window_start)
+        .bind(window_end)
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Counting request of {} -> {:?}", key, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(hits)
+    }
+}
+
+/// Limits of the requests to the public endpoints, per client ip, per account and per app
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    per_ip: Quota,
+    per_account: Quota,
+    per_app: Quota,
+    trusted_proxy_hops: usize,
+}
+
+impl RateLimiter {
+    pub fn from_secrets(secrets: &SecretStore, db_pool: &PgPool) -> Self {
+        let store: Arc<dyn RateLimitStore> = match secrets.get("RATE_LIMIT_STORE").as_deref() {
+            Some("postgres") => Arc::new(PostgresStore {
+                db_pool: db_pool.clone(),
+            }),
+            _ => Arc::new(MemoryStore::default()),
+        };
+
+        Self {
+            store,
+            per_ip: Quota::from_secret(
+                secrets,
+                "RATE_LIMIT_PER_IP",
+                Quota {
+                    limit: 60,
+                    window_seconds: 60,
+                },
+            ),
+            per_account: Quota::from_secret(
+                secrets,
+                "RATE_LIMIT_PER_ACCOUNT",
+                Quota {
+                    limit: 10,
+                    window_seconds: 600,
+                },
+            ),
+            per_app: Quota::from_secret(
+                secrets,
+                "RATE_LIMIT_PER_APP",
+                Quota {
+                    limit: 300,
+                    window_seconds: 60,
+                },
+            ),
+            trusted_proxy_hops: trusted_proxy_hops(secrets),
+        }
+    }
+
+    /// Seconds to wait if one of the keys of the request is over its quota
+    async fn retry_after(&self, keys: &RequestKeys) -> Result<Option<i64>, AuthenticatorError> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+
+        let limited_keys = [
+            (&keys.ip, "ip", self.per_ip),
+            (&keys.account, "account", self.per_account),
+            (&keys.app, "app", self.per_app),
+        ];
+
+        let mut retry_after = None;
+
+        for (value, kind, quota) in limited_keys {
+            let Some(value) = value else {
+                continue;
+            };
+
+            let window_start = now - now.rem_euclid(quota.window_seconds);
+
+            let window_end = window_start + quota.window_seconds;
+
+            let hits = self
+                .store
+                .hit(&format!("{}:{}", kind, value), window_start, window_end)
+                .await?;
+
+            // The next keys aren't counted once a key is over its quota,
+            // so that a limited client can't fill the store with made up accounts
+            if hits > quota.limit {
+                retry_after = Some(window_end - now);
+                break;
+            }
+        }
+
+        Ok(retry_after)
+    }
+}
+
+/// What the requests are counted for
+#[derive(Debug, Default)]
+struct RequestKeys {
+    ip: Option<String>,
+    /// Hash of the mail or id of the user
+    account: Option<String>,
+    app: Option<String>,
+}
+
+impl RequestKeys {
+    fn add_params(&mut self, params: &[u8]) {
+        for (name, value) in form_urlencoded::parse(params) {
+            match name.as_ref() {
+                "mail" if !value.trim().is_empty() => {
+                    self.account = Some(hash_text(&value.trim().to_lowercase()))
+                }
+                "user_id" if !value.is_empty() => self.account = Some(value.into_owned()),
+                "app_id" | "client_id" if !value.is_empty() => self.app = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Tower layer limiting the requests to the public endpoints
+#[derive(Clone, Debug)]
+pub struct RateLimitLayer {
+    limiter: RateLimiter,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: RateLimiter,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // The ready service is used, a clone takes its place for the next requests
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let path = request.uri().path();
+
+            if !LIMITED_PATHS
+                .iter()
+                .any(|limited| path.starts_with(limited))
+            {
+                return inner.call(request).await;
+            }
+
+            let (request, keys) = match request_keys(request, limiter.trusted_proxy_hops).await {
+                Ok(request_and_keys) => request_and_keys,
+                Err(response) => return Ok(response),
+            };
+
+            match limiter.retry_after(&keys).await {
+                Ok(Some(retry_after)) => Ok(too_many_requests(&request, retry_after)),
+
+                // The endpoints stay available if the counts can't be saved
+                Ok(None) | Err(_) => inner.call(request).await,
+            }
+        })
+    }
+}
+
+/// Keys from the query and the form, the body is given back to the request once read
+async fn request_keys(
+    request: Request,
+    trusted_proxy_hops: usize,
+) -> Result<(Request, RequestKeys), Response> {
+    let mut keys = RequestKeys::default();
+
+    let ip = client_ip(request.headers(), trusted_proxy_hops);
+    if !ip.is_empty() {
+        keys.ip = Some(ip);
+    }
+
+    if let Some(query) = request.uri().query() {
+        keys.add_params(query.as_bytes());
+    }
+
+    let is_form = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+
+    let request = match is_form {
+        true => {
+            let (parts, body) = request.into_parts();
+
+            let form = to_bytes(body, MAX_FORM_BYTES)
+                .await
+                .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+
+            keys.add_params(&form);
+
+            Request::from_parts(parts, Body::from(form))
+        }
+        false => request,
+    };
+
+    // The client isn't authenticated yet on the openid endpoints:
+    // anyone could use up the quota of an app by sending its client_id
+    if request.uri().path().starts_with("/openid/") {
+        keys.app = None;
+    }
+
+    Ok((request, keys))
+}
+
+#[derive(Template)]
+#[template(path = "general/rate_limit_page.html")]
+pub struct RateLimitPage {
+    retry_after: i64,
+}
+
+/// 429 with the seconds to wait, and a page for the browsers
+fn too_many_requests(request: &Request, retry_after: i64) -> Response {
+    let wants_html = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+
+    let mut response = if wants_html {
+        (StatusCode::TOO_MANY_REQUESTS, RateLimitPage { retry_after }).into_response()
+    } else {
+        (StatusCode::TOO_MANY_REQUESTS, "Too many requests").into_response()
+    };
+
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    const DEFAULT: Quota = Quota {
+        limit: 60,
+        window_seconds: 60,
+    };
+
+    fn quota(secret: Option<&str>) -> Quota {
+        let secrets =
+            SecretStore::new(BTreeMap::from_iter(secret.map(|secret| {
+                ("RATE_LIMIT_PER_IP".to_owned(), secret.to_owned().into())
+            })));
+
+        Quota::from_secret(&secrets, "RATE_LIMIT_PER_IP", DEFAULT)
+    }
+
+    #[test]
+    fn quotas_are_read_as_limit_per_seconds() {
+        let expected_quota = Quota {
+            limit: 5,
+            window_seconds: 30,
+        };
+
+        assert_eq!(quota(Some("5/30")), expected_quota);
+        assert_eq!(quota(Some(" 5 / 30 ")), expected_quota);
+        assert_eq!(
+            quota(Some("0/30")),
+            Quota {
+                limit: 0,
+                window_seconds: 30
+            }
+        );
+    }
+
+    #[test]
+    fn missing_or_invalid_quotas_give_the_default() {
+        for secret in [
+            None,
+            Some(""),
+            Some("5"),
+            Some("a/30"),
+            Some("5/b"),
+            Some("5/0"),
+            Some("5/-1"),
+            Some("5/30/1"),
+        ] {
+            assert_eq!(quota(secret), DEFAULT, "{:?}", secret);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_full_memory_store_keeps_counting_new_keys() {
+        let store = MemoryStore::default();
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+
+        for index in 0..MAX_MEMORY_WINDOWS {
+            store
+                .hit(&format!("account:{}", index), now, now + 600)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(store.hit("ip:203.0.113.7", now, now + 60).await.unwrap(), 1);
+        assert_eq!(store.hit("ip:203.0.113.7", now, now + 60).await.unwrap(), 2);
+        assert_eq!(store.windows.lock().unwrap().len(), MAX_MEMORY_WINDOWS);
+    }
+}
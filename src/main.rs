@@ -9,13 +9,16 @@ use std::convert::Infallible;
 use std::fmt::Debug;
 
 use apps::App;
+use auth::cookies::CookiePolicy;
 use axum::{
     async_trait,
     extract::{FromRef, FromRequestParts},
     http::request::Parts,
+    middleware,
     routing::{get, post},
     Router,
 };
+use general::rate_limit::{RateLimitLayer, RateLimiter};
 use shuttle_runtime::{CustomError, SecretStore};
 use sqlx::PgPool;
 use tower_http::services::{ServeDir, ServeFile};
@@ -29,6 +32,10 @@ pub struct AppState {
     authenticator_app: App,
     db_pool: PgPool,
     mailer: AppMailer,
+    pairwise_salt: String,
+    encryption_key: String,
+    cookie_policy: CookiePolicy,
+    trusted_proxy_hops: usize,
 }
 
 /// Implement FromRequestParts
@@ -61,11 +68,17 @@ async fn http_server(
         .await
         .map_err(CustomError::new)?;
 
+    let rate_limiter = RateLimiter::from_secrets(&secrets, &db_pool);
+
     let state = AppState {
         owner_mail: secrets.get("OWNER_MAIL").unwrap(),
         authenticator_app: App::init_authenticator_app(&secrets),
         db_pool,
         mailer: AppMailer::new(&secrets),
+        pairwise_salt: secrets.get("PAIRWISE_SALT").unwrap(),
+        encryption_key: secrets.get("ENCRYPTION_KEY").unwrap(),
+        cookie_policy: CookiePolicy::from_secrets(&secrets),
+        trusted_proxy_hops: auth::session::trusted_proxy_hops(&secrets),
     };
 
     let router = Router::new()
@@ -79,8 +92,58 @@ async fn http_server(
             get(auth::signin::get_handler).post(auth::signin::post_handler),
         )
         .route("/signout", get(auth::signout::get_handler))
+        .route(
+            "/magic_link_request",
+            post(auth::magic_link::request_handler),
+        )
+        .route(
+            "/magic_link",
+            get(auth::magic_link::get_handler).post(auth::magic_link::post_handler),
+        )
+        .route("/mail_code_request", post(auth::mail_code::request_handler))
+        .route("/mail_code", post(auth::mail_code::signin_handler))
+        .route("/second_factor", post(auth::second_factor::post_handler))
+        .route(
+            "/second_factor_passkey_options",
+            post(auth::second_factor::passkey_options_handler),
+        )
+        .route(
+            "/second_factor_passkey",
+            post(auth::second_factor::passkey_handler),
+        )
+        .route(
+            "/passkey_signin_options",
+            post(auth::passkey::signin_options_handler),
+        )
+        .route("/passkey_signin", post(auth::passkey::signin_handler))
+        .route(
+            "/unlock",
+            get(auth::lockout::get_handler).post(auth::lockout::post_handler),
+        )
+        .route(
+            "/reauth",
+            get(auth::reauth::get_handler).post(auth::reauth::post_handler),
+        )
+        .route(
+            "/recovery",
+            get(auth::recovery::get_handler).post(auth::recovery::post_handler),
+        )
+        .route(
+            "/forgot_password",
+            get(auth::password_reset::forgot_password_get_handler)
+                .post(auth::password_reset::forgot_password_post_handler),
+        )
+        .route(
+            "/reset_password",
+            get(auth::password_reset::reset_password_get_handler)
+                .post(auth::password_reset::reset_password_post_handler),
+        )
         .route("/send_confirm", get(users::confirm::send_confirm_handler))
         .route("/confirm_mail", get(users::confirm::confirm_mail_handler))
+        .route(
+            "/confirm_mail_code",
+            post(users::confirm::confirm_code_handler),
+        )
         .route(
             "/profile",
             get(users::profile::get_handler).post(users::profile::update_profile_handler),
@@ -90,17 +153,67 @@ async fn http_server(
             post(users::profile::profile_delete_handler),
         )
         .route("/password", post(users::profile::update_password_handler))
+        .route("/totp_enroll", post(users::two_factor::totp_enroll_handler))
+        .route(
+            "/totp_confirm",
+            post(users::two_factor::totp_confirm_handler),
+        )
+        .route(
+            "/totp_disable",
+            post(users::two_factor::totp_disable_handler),
+        )
+        .route(
+            "/passkey_register_options",
+            post(users::passkeys::register_options_handler),
+        )
+        .route("/passkey_register", post(users::passkeys::register_handler))
+        .route("/passkey_delete", post(users::passkeys::delete_handler))
+        .route(
+            "/recovery_codes",
+            post(users::recovery_codes::generate_handler),
+        )
+        .route(
+            "/session_revoke",
+            post(users::profile::session_revoke_handler),
+        )
+        .route(
+            "/other_sessions_revoke",
+            post(users::profile::other_sessions_revoke_handler),
+        )
         .route("/owned_apps", get(apps::my_apps::get_handler))
         .route(
             "/app",
             get(apps::app::get_handler).post(apps::app::post_handler),
         )
+        .route(
+            "/resource",
+            get(apps::resource::get_handler).post(apps::resource::post_handler),
+        )
         .route(
             "/openid/authorize",
             get(openid::authorize::get_handler).post(openid::authorize::post_handler),
         )
+        .route("/openid/token", post(openid::token::post_handler))
+        .route(
+            "/openid/introspect",
+            post(openid::introspection::post_handler),
+        )
+        .route("/openid/error", get(openid::error::get_handler))
+        .route(
+            "/openid/check_session",
+            get(openid::session::check_session_handler),
+        )
+        .route(
+            "/openid/userinfo",
+            get(openid::userinfo::handler).post(openid::userinfo::handler),
+        )
         .nest_service("/assets", ServeDir::new("assets"))
         .nest_service("/favicon.ico", ServeFile::new("assets/favicon.ico"))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::csrf::token_middleware,
+        ))
+        .layer(RateLimitLayer::new(rate_limiter))
         .with_state(state);
 
     Ok(router.into())
@@ -1,46 +1,330 @@
 use askama_axum::IntoResponse;
-use axum::response::Redirect;
-use http::{StatusCode, Uri};
+use axum::{response::Redirect, Json};
+use http::{header, HeaderName, StatusCode};
+use serde::Serialize;
+use url::Url;
+
+use crate::AppState;
+
+use self::{dpop::DPOP_NONCE_HEADER, error::ErrorPage};
 
 pub mod authorize;
+pub mod claims;
+pub mod code;
+pub mod dpop;
+pub mod error;
+pub mod grant;
+pub mod introspection;
+pub mod pkce;
+pub mod refresh;
+pub mod session;
+pub mod token;
+pub mod userinfo;
+
+/// Error codes of the OAuth 2.0 and OpenID Connect endpoints
+/// https://www.rfc-editor.org/rfc/rfc6749.html#section-4.1.2.1
+/// https://www.rfc-editor.org/rfc/rfc6749.html#section-5.2
+/// https://openid.net/specs/openid-connect-core-1_0.html#AuthError
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    InvalidRequest,
+    UnauthorizedClient,
+    AccessDenied,
+    UnsupportedResponseType,
+    InvalidScope,
+    ServerError,
+    LoginRequired,
+    InteractionRequired,
+    RequestNotSupported,
+    RequestUriNotSupported,
+    RegistrationNotSupported,
+    InvalidClient,
+    InvalidGrant,
+    UnsupportedGrantType,
+    InvalidTarget,
+    InvalidToken,
+    InsufficientScope,
+    InvalidDpopProof,
+    UseDpopNonce,
+}
+
+impl ErrorCode {
+    pub fn all() -> Vec<Self> {
+        vec![
+            ErrorCode::InvalidRequest,
+            ErrorCode::UnauthorizedClient,
+            ErrorCode::AccessDenied,
+            ErrorCode::UnsupportedResponseType,
+            ErrorCode::InvalidScope,
+            ErrorCode::ServerError,
+            ErrorCode::LoginRequired,
+            ErrorCode::InteractionRequired,
+            ErrorCode::RequestNotSupported,
+            ErrorCode::RequestUriNotSupported,
+            ErrorCode::RegistrationNotSupported,
+            ErrorCode::InvalidClient,
+            ErrorCode::InvalidGrant,
+            ErrorCode::UnsupportedGrantType,
+            ErrorCode::InvalidTarget,
+            ErrorCode::InvalidToken,
+            ErrorCode::InsufficientScope,
+            ErrorCode::InvalidDpopProof,
+            ErrorCode::UseDpopNonce,
+        ]
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::all().into_iter().find(|code| code.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::UnauthorizedClient => "unauthorized_client",
+            ErrorCode::AccessDenied => "access_denied",
+            ErrorCode::UnsupportedResponseType => "unsupported_response_type",
+            ErrorCode::InvalidScope => "invalid_scope",
+            ErrorCode::ServerError => "server_error",
+            ErrorCode::LoginRequired => "login_required",
+            ErrorCode::InteractionRequired => "interaction_required",
+            ErrorCode::RequestNotSupported => "request_not_supported",
+            ErrorCode::RequestUriNotSupported => "request_uri_not_supported",
+            ErrorCode::RegistrationNotSupported => "registration_not_supported",
+            ErrorCode::InvalidClient => "invalid_client",
+            ErrorCode::InvalidGrant => "invalid_grant",
+            ErrorCode::UnsupportedGrantType => "unsupported_grant_type",
+            ErrorCode::InvalidTarget => "invalid_target",
+            ErrorCode::InvalidToken => "invalid_token",
+            ErrorCode::InsufficientScope => "insufficient_scope",
+            ErrorCode::InvalidDpopProof => "invalid_dpop_proof",
+            ErrorCode::UseDpopNonce => "use_dpop_nonce",
+        }
+    }
+
+    /// Explanation given to the users on the error page
+    pub fn explanation(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "La demande de l'app est incomplète ou mal formée",
+            ErrorCode::UnauthorizedClient => {
+                "L'app est inconnue ou n'est pas autorisée à faire cette demande"
+            }
+            ErrorCode::AccessDenied => "L'accès a été refusé",
+            ErrorCode::UnsupportedResponseType => "Le type de réponse demandé n'est pas supporté",
+            ErrorCode::InvalidScope => "Les autorisations demandées par l'app sont invalides",
+            ErrorCode::ServerError => "Un problème est survenu, veuillez réessayer plus tard",
+            ErrorCode::LoginRequired => "Vous devez vous connecter",
+            ErrorCode::InteractionRequired => "Une action de votre part est nécessaire",
+            ErrorCode::RequestNotSupported => "Les demandes signées ne sont pas supportées",
+            ErrorCode::RequestUriNotSupported => {
+                "Les demandes passées par référence ne sont pas supportées"
+            }
+            ErrorCode::RegistrationNotSupported => {
+                "L'enregistrement dynamique des apps n'est pas supporté"
+            }
+            ErrorCode::InvalidClient => "L'authentification de l'app a échoué",
+            ErrorCode::InvalidGrant => "L'autorisation donnée à l'app est invalide ou expirée",
+            ErrorCode::UnsupportedGrantType => "Ce type d'autorisation n'est pas supporté",
+            ErrorCode::InvalidTarget => "L'API demandée est inconnue ou non autorisée",
+            ErrorCode::InvalidToken => "Le token est invalide ou expiré",
+            ErrorCode::InsufficientScope => "Le token ne donne pas accès à cette ressource",
+            ErrorCode::InvalidDpopProof => "La preuve de possession de clé est invalide",
+            ErrorCode::UseDpopNonce => "La preuve de possession de clé doit contenir un nonce",
+        }
+    }
 
+    fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidClient | ErrorCode::InvalidToken => StatusCode::UNAUTHORIZED,
+            ErrorCode::InsufficientScope => StatusCode::FORBIDDEN,
+            ErrorCode::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// How the error is given back
+/// Redirect = to the trusted redirect uri of the app (authorize endpoint)
+/// Json = in the body (back-channel endpoints)
+/// Challenge = in the WWW-Authenticate header (protected resources)
+/// Page = to the user when the redirect uri can't be trusted
 #[derive(Debug)]
-pub enum OpenIdConnectError {
-    InvalidRequest(Option<Uri>),
-    InvalidScope(Uri),
-    UnauthorizedClient(Uri),
-    UnsupportedResponseType(Uri),
+enum ErrorResponse {
+    Redirect {
+        redirect_uri: String,
+        state: Option<String>,
+    },
+    Json {
+        dpop_nonce: Option<String>,
+    },
+    Challenge {
+        scheme: &'static str,
+    },
+    Page,
+}
+
+#[derive(Debug)]
+pub struct OpenIdConnectError {
+    code: ErrorCode,
+    description: String,
+    error_uri: Option<String>,
+    response: ErrorResponse,
+}
+
+/// Error parameters of the redirection or of the json body
+/// The description must only contain printable ascii characters (without " and \)
+#[derive(Serialize)]
+struct ErrorParams<'a> {
+    error: &'a str,
+    error_description: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_uri: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<&'a str>,
+}
+
+impl OpenIdConnectError {
+    fn new(code: ErrorCode, description: &str, response: ErrorResponse) -> Self {
+        Self {
+            code,
+            description: description.to_owned(),
+            error_uri: None,
+            response,
+        }
+    }
+
+    pub fn json(code: ErrorCode, description: &str) -> Self {
+        Self::new(code, description, ErrorResponse::Json { dpop_nonce: None })
+    }
+
+    pub fn page(code: ErrorCode, description: &str) -> Self {
+        Self::new(code, description, ErrorResponse::Page)
+    }
+
+    pub fn challenge(code: ErrorCode, description: &str, scheme: &'static str) -> Self {
+        Self::new(code, description, ErrorResponse::Challenge { scheme })
+    }
+
+    /// The DPoP proof must be sent again with the given nonce
+    pub fn use_dpop_nonce(nonce: String) -> Self {
+        Self::new(
+            ErrorCode::UseDpopNonce,
+            "The DPoP proof must contain the nonce given in the DPoP-Nonce header",
+            ErrorResponse::Json {
+                dpop_nonce: Some(nonce),
+            },
+        )
+    }
+
+    /// Same error given by a protected resource
+    pub fn into_challenge(self, scheme: &'static str) -> Self {
+        Self {
+            response: ErrorResponse::Challenge { scheme },
+            ..self
+        }
+    }
+
+    /// Link to the page of the authenticator explaining the error
+    pub fn with_error_uri(mut self, state: &AppState) -> Self {
+        self.error_uri = Some(
+            state
+                .authenticator_app
+                .url_to_endpoint(&format!("/openid/error?error={}", self.code.name())),
+        );
+
+        self
+    }
+
+    fn params<'a>(&'a self, state: Option<&'a str>) -> ErrorParams<'a> {
+        ErrorParams {
+            error: self.code.name(),
+            error_description: &self.description,
+            error_uri: self.error_uri.as_deref(),
+            state,
+        }
+    }
+}
+
+/// Trusted redirect uri of the app to which the authorize errors are sent
+#[derive(Clone, Debug)]
+pub struct ErrorRedirect {
+    redirect_uri: Url,
+    state: Option<String>,
+}
+
+impl ErrorRedirect {
+    pub fn new(redirect_uri: Url, state: Option<String>) -> Self {
+        Self {
+            redirect_uri,
+            state,
+        }
+    }
+
+    pub fn error(&self, code: ErrorCode, description: &str) -> OpenIdConnectError {
+        OpenIdConnectError::new(
+            code,
+            description,
+            ErrorResponse::Redirect {
+                redirect_uri: self.redirect_uri.to_string(),
+                state: self.state.clone(),
+            },
+        )
+    }
 }
 
 impl IntoResponse for OpenIdConnectError {
     fn into_response(self) -> askama_axum::Response {
-        match self {
-            OpenIdConnectError::InvalidRequest(Some(redirect_uri)) => Redirect::to(&format!(
-                "{}?error=invalid_request",
-                redirect_uri.to_string()
-            ))
-            .into_response(),
-
-            OpenIdConnectError::InvalidRequest(None) => {
-                (StatusCode::BAD_REQUEST, "invalid_request").into_response()
+        match &self.response {
+            ErrorResponse::Redirect {
+                redirect_uri,
+                state,
+            } => {
+                let separator = if redirect_uri.contains('?') { "&" } else { "?" };
+
+                let params =
+                    serde_urlencoded::to_string(self.params(state.as_deref())).unwrap_or_default();
+
+                Redirect::to(&format!("{}{}{}", redirect_uri, separator, params)).into_response()
+            }
+
+            ErrorResponse::Json { dpop_nonce } => {
+                let mut response = (self.code.status(), Json(self.params(None))).into_response();
+
+                if let Some(nonce) = dpop_nonce.as_ref().and_then(|nonce| nonce.parse().ok()) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(DPOP_NONCE_HEADER), nonce);
+                }
+
+                if self.code == ErrorCode::InvalidClient {
+                    response.headers_mut().insert(
+                        header::WWW_AUTHENTICATE,
+                        header::HeaderValue::from_static("Basic"),
+                    );
+                }
+
+                response
             }
 
-            OpenIdConnectError::InvalidScope(redirect_uri) => {
-                Redirect::to(&format!("{}?error=invalid_scope", redirect_uri.to_string()))
-                    .into_response()
+            ErrorResponse::Challenge { scheme } => {
+                let mut challenge = format!(
+                    "{} error=\"{}\", error_description=\"{}\"",
+                    scheme,
+                    self.code.name(),
+                    self.description
+                );
+
+                if let Some(error_uri) = &self.error_uri {
+                    challenge.push_str(&format!(", error_uri=\"{}\"", error_uri));
+                }
+
+                (self.code.status(), [(header::WWW_AUTHENTICATE, challenge)]).into_response()
             }
 
-            OpenIdConnectError::UnauthorizedClient(redirect_uri) => Redirect::to(&format!(
-                "{}?error=unauthorized_client",
-                redirect_uri.to_string()
-            ))
-            .into_response(),
-
-            OpenIdConnectError::UnsupportedResponseType(redirect_uri) => Redirect::to(&format!(
-                "{}?error=unsupported_response_type",
-                redirect_uri.to_string()
-            ))
-            .into_response(),
+            ErrorResponse::Page => (
+                self.code.status(),
+                ErrorPage::from_code(self.code, &self.description),
+            )
+                .into_response(),
         }
     }
 }
@@ -1,81 +1,243 @@
 use askama_axum::IntoResponse;
 use axum::{
     extract::{Query, State},
+    response::Redirect,
     Form,
 };
+use axum_extra::extract::CookieJar;
 use http::Uri;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use url::Url;
 
 use crate::{
-    apps::App,
+    apps::{api_resource::ApiResource, App},
     auth::{
+        assurance::AuthLevel,
         signin::{self, SigninPage},
-        IdSession,
+        IdSession, BROWSER_STATE,
     },
+    general::message::{Level, MessageBlock},
+    users::User,
     AppState,
 };
 
-use super::OpenIdConnectError;
+use super::{
+    claims::ClaimsRequest, code::AuthorizationCode, grant::Grant, pkce::Pkce,
+    session::session_state, ErrorCode, ErrorRedirect, OpenIdConnectError,
+};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct AuthenticationRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
     scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     response_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     redirect_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    claims: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acr_values: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resource: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_challenge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_challenge_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registration: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthenticationResponse {
+    code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    session_state: String,
 }
 
 pub async fn get_handler(
     id_session: Option<IdSession>,
+    cookies: CookieJar,
     request_uri: Uri,
     State(state): State<AppState>,
     Query(query): Query<AuthenticationRequest>,
 ) -> Result<impl IntoResponse, OpenIdConnectError> {
-    authorize_handler(id_session, state, query, request_uri).await
+    authorize_handler(id_session, &cookies, state.clone(), query, request_uri)
+        .await
+        .map_err(|error| error.with_error_uri(&state))
 }
 
 pub async fn post_handler(
     id_session: Option<IdSession>,
+    cookies: CookieJar,
     request_uri: Uri,
     State(state): State<AppState>,
     Form(form): Form<AuthenticationRequest>,
 ) -> Result<impl IntoResponse, OpenIdConnectError> {
-    authorize_handler(id_session, state, form, request_uri).await
+    authorize_handler(id_session, &cookies, state.clone(), form, request_uri)
+        .await
+        .map_err(|error| error.with_error_uri(&state))
 }
 
 pub async fn authorize_handler(
     id_session: Option<IdSession>,
+    cookies: &CookieJar,
     state: AppState,
     auth_request: AuthenticationRequest,
     request_uri: Uri,
 ) -> Result<impl IntoResponse, OpenIdConnectError> {
     let redirect_uri = validate_redirect_uri(auth_request.redirect_uri.clone())?;
 
-    let response_type =
-        validate_response_type(auth_request.response_type.clone(), redirect_uri.clone())?;
+    let app_to_connect_to =
+        validate_client_id(&state, auth_request.client_id.clone(), &redirect_uri).await?;
 
-    let scope = validate_scope(auth_request.scope.clone(), redirect_uri.clone())?;
+    // From now on the redirect uri is trusted and errors are sent back to the app
+    let error_redirect = ErrorRedirect::new(redirect_uri.clone(), auth_request.state.clone());
 
-    let app_to_connect_to =
-        validate_client_id(&state, auth_request.client_id.clone(), redirect_uri.clone()).await?;
+    validate_supported_parameters(&auth_request, &error_redirect)?;
 
-    let already_connected = id_session.is_some();
+    validate_response_type(auth_request.response_type.clone(), &error_redirect)?;
 
-    if already_connected {
-        Ok(app_to_connect_to
-            .redirect_to_endpoint(Some(format!(
-                "{}?result=ok",
-                redirect_uri.path().to_string()
-            )))
-            .into_response())
-    } else {
-        let authorize_request_endpoint = authorize_request_endpoint_with_params(
-            request_uri,
+    let scope = validate_scope(auth_request.scope.clone(), &error_redirect)?;
+
+    validate_resource(
+        &state,
+        auth_request.resource.as_deref(),
+        &app_to_connect_to,
+        &error_redirect,
+    )
+    .await?;
+
+    let claims = validate_claims(auth_request.claims.clone(), &error_redirect)?;
+
+    let code_challenge = Pkce::validate_challenge(
+        auth_request.code_challenge.as_deref(),
+        auth_request.code_challenge_method.as_deref(),
+        app_to_connect_to.requires_pkce(),
+    )
+    .map_err(|description| error_redirect.error(ErrorCode::InvalidRequest, description))?;
+
+    let prompt_is_none = auth_request
+        .prompt
+        .as_deref()
+        .is_some_and(|prompt| prompt.split_whitespace().any(|value| value == "none"));
+
+    let essential_acr_values = claims.essential_acr_values();
+
+    let acr_values = essential_acr_values
+        .clone()
+        .or(auth_request.acr_values.clone())
+        .unwrap_or_default();
+
+    if id_session.is_none() && prompt_is_none {
+        return Err(error_redirect.error(ErrorCode::LoginRequired, "The user is not signed in"));
+    }
+
+    if let Some(id_session) = &id_session {
+        if !id_session.authentication.satisfies(&acr_values) {
+            let can_step_up = can_step_up(&state, id_session, &acr_values).await;
+
+            if !can_step_up && essential_acr_values.is_some() {
+                return Err(error_redirect.error(
+                    ErrorCode::AccessDenied,
+                    "The essential acr values can't be satisfied for the user",
+                ));
+            }
+
+            if can_step_up && prompt_is_none {
+                return Err(error_redirect.error(
+                    ErrorCode::InteractionRequired,
+                    "The user must authenticate again with a stronger method",
+                ));
+            }
+
+            if can_step_up {
+                return Ok(SigninPage::for_app_with_redirect_and_message(
+                    app_to_connect_to,
+                    Some(authorize_request_endpoint_with_params(
+                        request_uri,
+                        &auth_request,
+                    )),
+                    MessageBlock::new(
+                        Level::Info,
+                        "Authentification renforcée",
+                        "Cette app demande une authentification plus forte, veuillez vous reconnecter",
+                    ),
+                )
+                .into_response());
+            }
+        }
+    }
+
+    if let Some(id_session) = id_session {
+        Grant {
+            user_id: id_session.user_id,
+            app_id: app_to_connect_to.id,
+            scope: scope.clone(),
+            claims: claims.clone(),
+        }
+        .save(&state)
+        .await
+        .map_err(|_| error_redirect.error(ErrorCode::ServerError, "The grant can't be saved"))?;
+
+        let code = AuthorizationCode {
+            app_id: app_to_connect_to.id,
+            user_id: id_session.user_id,
+            redirect_uri: auth_request.redirect_uri.clone().unwrap_or_default(),
             scope,
-            response_type,
-            app_to_connect_to.id.to_string(),
-            redirect_uri.to_string(),
-        );
+            claims,
+            nonce: auth_request.nonce.clone(),
+            resource: auth_request.resource.clone(),
+            code_challenge,
+            authentication: id_session.authentication,
+        }
+        .generate(&state)
+        .await
+        .map_err(|_| error_redirect.error(ErrorCode::ServerError, "The code can't be generated"))?;
+
+        let authentication_response = serde_urlencoded::to_string(AuthenticationResponse {
+            code,
+            state: auth_request.state,
+            session_state: session_state(
+                app_to_connect_to.id,
+                &redirect_uri,
+                cookies
+                    .get(&state.cookie_policy.name(BROWSER_STATE))
+                    .map(|cookie| cookie.value())
+                    .unwrap_or_default(),
+            ),
+        })
+        .map_err(|_| {
+            error_redirect.error(ErrorCode::ServerError, "The response can't be encoded")
+        })?;
+
+        let separator = if redirect_uri.query().is_some() {
+            "&"
+        } else {
+            "?"
+        };
+
+        Ok(Redirect::to(&format!(
+            "{}{}{}",
+            redirect_uri, separator, authentication_response
+        ))
+        .into_response())
+    } else {
+        let authorize_request_endpoint =
+            authorize_request_endpoint_with_params(request_uri, &auth_request);
 
         Ok(SigninPage::for_app_from_query(
             app_to_connect_to.clone(),
@@ -89,86 +251,173 @@ pub async fn authorize_handler(
     }
 }
 
-fn validate_redirect_uri(redirect_uri: Option<String>) -> Result<Uri, OpenIdConnectError> {
+/// The user can reach a level that meets the acr values by signing in again
+async fn can_step_up(state: &AppState, id_session: &IdSession, acr_values: &str) -> bool {
+    match User::select_from_id(&state.db_pool, id_session.user_id).await {
+        Ok(user) => AuthLevel::available_for(state, &user)
+            .await
+            .satisfies(acr_values),
+        Err(_) => false,
+    }
+}
+
+/// Errors are shown to the user as long as the redirect uri can't be trusted
+fn validate_redirect_uri(redirect_uri: Option<String>) -> Result<Url, OpenIdConnectError> {
     match redirect_uri {
-        Some(redirect_uri) => redirect_uri
-            .parse::<Uri>()
-            .map_err(|_| OpenIdConnectError::InvalidRequest(None)),
+        Some(redirect_uri) => Url::parse(&redirect_uri).map_err(|_| {
+            OpenIdConnectError::page(ErrorCode::InvalidRequest, "The redirect_uri is invalid")
+        }),
+
+        None => Err(OpenIdConnectError::page(
+            ErrorCode::InvalidRequest,
+            "The redirect_uri is missing",
+        )),
+    }
+}
+
+async fn validate_client_id(
+    state: &AppState,
+    client_id: Option<String>,
+    redirect_uri: &Url,
+) -> Result<App, OpenIdConnectError> {
+    let unknown_client =
+        || OpenIdConnectError::page(ErrorCode::UnauthorizedClient, "The client_id is unknown");
+
+    let app_id: i32 = client_id
+        .ok_or(OpenIdConnectError::page(
+            ErrorCode::InvalidRequest,
+            "The client_id is missing",
+        ))?
+        .parse()
+        .map_err(|_| unknown_client())?;
+
+    let app = App::select_from_app_id(state, app_id)
+        .await
+        .map_err(|_| unknown_client())?;
+
+    if app.is_authenticator_app() {
+        return Err(unknown_client());
+    }
+
+    if !app.accepts_redirect_uri(redirect_uri) {
+        return Err(OpenIdConnectError::page(
+            ErrorCode::InvalidRequest,
+            "The redirect_uri is not registered for the client",
+        ));
+    }
+
+    Ok(app)
+}
+
+/// Request objects and dynamic registration are not supported
+fn validate_supported_parameters(
+    auth_request: &AuthenticationRequest,
+    error_redirect: &ErrorRedirect,
+) -> Result<(), OpenIdConnectError> {
+    if auth_request.request.is_some() {
+        return Err(error_redirect.error(
+            ErrorCode::RequestNotSupported,
+            "The request parameter is not supported",
+        ));
+    }
+
+    if auth_request.request_uri.is_some() {
+        return Err(error_redirect.error(
+            ErrorCode::RequestUriNotSupported,
+            "The request_uri parameter is not supported",
+        ));
+    }
 
-        None => Err(OpenIdConnectError::InvalidRequest(None)),
+    if auth_request.registration.is_some() {
+        return Err(error_redirect.error(
+            ErrorCode::RegistrationNotSupported,
+            "The registration parameter is not supported",
+        ));
     }
+
+    Ok(())
 }
 
 fn validate_response_type(
     response_type: Option<String>,
-    redirect_uri: Uri,
+    error_redirect: &ErrorRedirect,
 ) -> Result<String, OpenIdConnectError> {
     match response_type {
         Some(response_type) => {
-            if response_type.contains("code") {
+            if response_type == "code" {
                 Ok(response_type)
             } else {
-                Err(OpenIdConnectError::UnsupportedResponseType(redirect_uri))
+                Err(error_redirect.error(
+                    ErrorCode::UnsupportedResponseType,
+                    "Only the code response_type is supported",
+                ))
             }
         }
 
-        None => Err(OpenIdConnectError::UnsupportedResponseType(redirect_uri)),
+        None => {
+            Err(error_redirect.error(ErrorCode::InvalidRequest, "The response_type is missing"))
+        }
     }
 }
 
-fn validate_scope(scope: Option<String>, redirect_uri: Uri) -> Result<String, OpenIdConnectError> {
+fn validate_scope(
+    scope: Option<String>,
+    error_redirect: &ErrorRedirect,
+) -> Result<String, OpenIdConnectError> {
     match scope {
         Some(scope) => {
-            if scope.contains("openid") {
+            if scope.split_whitespace().any(|value| value == "openid") {
                 Ok(scope)
             } else {
-                Err(OpenIdConnectError::InvalidScope(redirect_uri))
+                Err(error_redirect
+                    .error(ErrorCode::InvalidScope, "The openid scope value is missing"))
             }
         }
 
-        None => Err(OpenIdConnectError::InvalidScope(redirect_uri)),
+        None => Err(error_redirect.error(ErrorCode::InvalidScope, "The scope is missing")),
     }
 }
 
-async fn validate_client_id(
+/// Essential claims not granted by the scope are left out rather than refused
+/// https://openid.net/specs/openid-connect-core-1_0.html#IndividualClaimsRequests
+fn validate_claims(
+    claims: Option<String>,
+    error_redirect: &ErrorRedirect,
+) -> Result<ClaimsRequest, OpenIdConnectError> {
+    ClaimsRequest::parse(&claims.unwrap_or_default()).map_err(|_| {
+        error_redirect.error(ErrorCode::InvalidRequest, "The claims parameter is invalid")
+    })
+}
+
+/// The requested API resource must exist and permit the app to call it
+async fn validate_resource(
     state: &AppState,
-    client_id: Option<String>,
-    redirect_uri: Uri,
-) -> Result<App, OpenIdConnectError> {
-    match client_id {
-        Some(client_id) => {
-            let app_id: i32 = client_id
-                .parse()
-                .map_err(|_| OpenIdConnectError::UnauthorizedClient(redirect_uri.clone()))?;
-
-            let app = App::select_from_app_id(state, app_id)
-                .await
-                .map_err(|_| OpenIdConnectError::UnauthorizedClient(redirect_uri.clone()))?;
-
-            if app.redirect_url() != redirect_uri.to_string() {
-                Err(OpenIdConnectError::UnauthorizedClient(redirect_uri.clone()))
-            } else {
-                Ok(app)
-            }
-        }
+    resource: Option<&str>,
+    app: &App,
+    error_redirect: &ErrorRedirect,
+) -> Result<(), OpenIdConnectError> {
+    match resource {
+        Some(resource) => ApiResource::select_permitted(state, resource, app)
+            .await
+            .map(|_| ())
+            .map_err(|_| {
+                error_redirect.error(
+                    ErrorCode::InvalidTarget,
+                    "The resource is unknown or not permitted for the client",
+                )
+            }),
 
-        None => Err(OpenIdConnectError::UnauthorizedClient(redirect_uri)),
+        None => Ok(()),
     }
 }
 
 fn authorize_request_endpoint_with_params(
     request_uri: Uri,
-    scope: String,
-    response_type: String,
-    client_id: String,
-    redirect_uri: String,
+    auth_request: &AuthenticationRequest,
 ) -> String {
     format!(
-        "{}?scope={}&response_type={}&client_id={}&redirect_uri={}",
+        "{}?{}",
         request_uri.path(),
-        url_escape::encode_component(&scope),
-        url_escape::encode_component(&response_type),
-        client_id,
-        url_escape::encode_component(&redirect_uri),
+        serde_urlencoded::to_string(auth_request).unwrap_or_default()
     )
 }
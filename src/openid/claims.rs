@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{users::User, utils::time::HtmlDate};
+
+/// User claims that can be released to apps
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserClaim {
+    Name,
+    Picture,
+    Birthdate,
+    Email,
+    EmailVerified,
+}
+
+impl UserClaim {
+    pub fn all() -> Vec<Self> {
+        vec![
+            UserClaim::Name,
+            UserClaim::Picture,
+            UserClaim::Birthdate,
+            UserClaim::Email,
+            UserClaim::EmailVerified,
+        ]
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::all().into_iter().find(|claim| claim.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UserClaim::Name => "name",
+            UserClaim::Picture => "picture",
+            UserClaim::Birthdate => "birthdate",
+            UserClaim::Email => "email",
+            UserClaim::EmailVerified => "email_verified",
+        }
+    }
+
+    /// Scope that must be granted to release the claim
+    pub fn scope(&self) -> &'static str {
+        match self {
+            UserClaim::Name | UserClaim::Picture | UserClaim::Birthdate => "profile",
+            UserClaim::Email | UserClaim::EmailVerified => "email",
+        }
+    }
+
+    pub fn is_granted_by(&self, scope: &str) -> bool {
+        scope
+            .split_whitespace()
+            .any(|granted| granted == self.scope())
+    }
+
+    pub fn value(&self, user: &User) -> Value {
+        match self {
+            UserClaim::Name => Value::from(user.name.clone()),
+            UserClaim::Picture => Value::from(user.avatar_url.clone()),
+            UserClaim::Birthdate => Value::from(HtmlDate::from(user.birthday).to_string()),
+            UserClaim::Email => Value::from(user.mail.clone()),
+            UserClaim::EmailVerified => Value::from(user.mail_is_confirmed),
+        }
+    }
+}
+
+/// Where the claims will be released
+pub enum ClaimsTarget {
+    IdToken,
+    Userinfo,
+}
+
+/// Individual claim request
+/// essential = the app needs the claim to work properly
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ClaimRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub essential: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<Value>>,
+}
+
+impl ClaimRequest {
+    pub fn is_essential(&self) -> bool {
+        self.essential.unwrap_or(false)
+    }
+
+    /// Requested string values of the claim
+    pub fn requested_values(&self) -> Vec<String> {
+        self.value
+            .iter()
+            .chain(self.values.iter().flatten())
+            .filter_map(|value| value.as_str().map(|value| value.to_owned()))
+            .collect()
+    }
+}
+
+/// The claims request parameter of the authentication request
+/// https://openid.net/specs/openid-connect-core-1_0.html#ClaimsParameter
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ClaimsRequest {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub userinfo: HashMap<String, Option<ClaimRequest>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub id_token: HashMap<String, Option<ClaimRequest>>,
+}
+
+impl ClaimsRequest {
+    pub fn parse(claims: &str) -> Result<Self, serde_json::Error> {
+        if claims.is_empty() {
+            return Ok(Self::default());
+        }
+
+        serde_json::from_str(claims)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    fn requested(&self, target: &ClaimsTarget) -> &HashMap<String, Option<ClaimRequest>> {
+        match target {
+            ClaimsTarget::IdToken => &self.id_token,
+            ClaimsTarget::Userinfo => &self.userinfo,
+        }
+    }
+
+    /// Acr values requested as essential for the id token
+    pub fn essential_acr_values(&self) -> Option<String> {
+        match self.id_token.get("acr") {
+            Some(Some(request)) if request.is_essential() => {
+                Some(request.requested_values().join(" "))
+            }
+            _ => None,
+        }
+    }
+
+    /// Claims released to the target
+    /// Scope claims are returned by userinfo, the id token only gets the claims requested individually
+    pub fn released_claims(&self, target: ClaimsTarget, scope: &str) -> Vec<UserClaim> {
+        let mut claims: Vec<UserClaim> = match target {
+            ClaimsTarget::IdToken => vec![],
+            ClaimsTarget::Userinfo => UserClaim::all()
+                .into_iter()
+                .filter(|claim| claim.is_granted_by(scope))
+                .collect(),
+        };
+
+        for name in self.requested(&target).keys() {
+            if let Some(claim) = UserClaim::from_name(name) {
+                if claim.is_granted_by(scope) && !claims.contains(&claim) {
+                    claims.push(claim);
+                }
+            }
+        }
+
+        claims
+    }
+
+    /// Claims of the user as a json object
+    pub fn user_claims(user: &User, claims: &[UserClaim]) -> Map<String, Value> {
+        claims
+            .iter()
+            .map(|claim| (claim.name().to_owned(), claim.value(user)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims_request(claims: &str) -> ClaimsRequest {
+        ClaimsRequest::parse(claims).unwrap()
+    }
+
+    #[test]
+    fn userinfo_releases_the_claims_of_the_granted_scope() {
+        let claims =
+            ClaimsRequest::default().released_claims(ClaimsTarget::Userinfo, "openid email");
+
+        assert_eq!(claims, vec![UserClaim::Email, UserClaim::EmailVerified]);
+    }
+
+    #[test]
+    fn id_token_only_releases_the_requested_claims() {
+        let request =
+            claims_request(r#"{"id_token": {"email": null, "name": {"essential": true}}}"#);
+
+        assert_eq!(
+            ClaimsRequest::default().released_claims(ClaimsTarget::IdToken, "openid profile email"),
+            vec![]
+        );
+
+        let mut claims = request.released_claims(ClaimsTarget::IdToken, "openid profile email");
+        claims.sort_by_key(UserClaim::name);
+        assert_eq!(claims, vec![UserClaim::Email, UserClaim::Name]);
+    }
+
+    #[test]
+    fn requested_claims_outside_the_scope_are_left_out() {
+        let request = claims_request(
+            r#"{
+                "id_token": {"email": {"essential": true}, "birthdate": null},
+                "userinfo": {"picture": {"essential": true}, "unknown": null}
+            }"#,
+        );
+
+        assert_eq!(
+            request.released_claims(ClaimsTarget::IdToken, "openid profile"),
+            vec![UserClaim::Birthdate]
+        );
+        assert_eq!(
+            request.released_claims(ClaimsTarget::Userinfo, "openid email"),
+            vec![UserClaim::Email, UserClaim::EmailVerified]
+        );
+    }
+
+    #[test]
+    fn essential_acr_values_are_read_from_the_id_token_request() {
+        let request =
+            claims_request(r#"{"id_token": {"acr": {"essential": true, "values": ["2", "1"]}}}"#);
+        assert_eq!(request.essential_acr_values(), Some("2 1".to_owned()));
+
+        let request = claims_request(r#"{"id_token": {"acr": {"essential": true, "value": "2"}}}"#);
+        assert_eq!(request.essential_acr_values(), Some("2".to_owned()));
+    }
+
+    #[test]
+    fn voluntary_or_userinfo_acr_values_are_not_essential() {
+        for claims in [
+            "",
+            r#"{"id_token": {"acr": null}}"#,
+            r#"{"id_token": {"acr": {"values": ["2"]}}}"#,
+            r#"{"id_token": {"acr": {"essential": false, "value": "2"}}}"#,
+            r#"{"userinfo": {"acr": {"essential": true, "value": "2"}}}"#,
+        ] {
+            assert_eq!(
+                claims_request(claims).essential_acr_values(),
+                None,
+                "{}",
+                claims
+            );
+        }
+    }
+}
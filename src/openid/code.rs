@@ -0,0 +1,142 @@
+use sqlx::{
+    types::{time::OffsetDateTime, Uuid},
+    FromRow,
+};
+use time::Duration;
+use tracing::log::error;
+
+use crate::{
+    auth::assurance::Authentication,
+    general::AuthenticatorError,
+    utils::crypto::{hash_text, random_token},
+    AppState,
+};
+
+use super::claims::ClaimsRequest;
+
+const SECONDS_TO_EXPIRE: i64 = 300;
+
+/// Single use code given to the app to get its tokens
+#[derive(Clone, Debug)]
+pub struct AuthorizationCode {
+    pub app_id: i32,
+    pub user_id: Uuid,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub claims: ClaimsRequest,
+    pub nonce: Option<String>,
+    pub resource: Option<String>,
+    pub code_challenge: Option<String>,
+    pub authentication: Authentication,
+}
+
+#[derive(FromRow)]
+struct AuthorizationCodeRow {
+    app_id: i32,
+    user_id: Uuid,
+    redirect_uri: String,
+    scope: String,
+    claims: String,
+    nonce: Option<String>,
+    resource: Option<String>,
+    code_challenge: Option<String>,
+    auth_time: i64,
+    auth_methods: String,
+    expires_at: OffsetDateTime,
+}
+
+impl AuthorizationCode {
+    /// Save the code and give it back (only its hash is saved)
+    pub async fn generate(&self, state: &AppState) -> Result<String, AuthenticatorError> {
+        let code = random_token();
+
+        let expires_at = OffsetDateTime::now_utc() + Duration::seconds(SECONDS_TO_EXPIRE);
+
+        sqlx::query(
+            "INSERT INTO authorization_codes (
+                hashed_code,
+                app_id,
+                user_id,
+                redirect_uri,
+                scope,
+                claims,
+                nonce,
+                resource,
+                code_challenge,
+                auth_time,
+                auth_methods,
+                expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+        )
+        .bind(hash_text(&code))
+        .bind(self.app_id)
+        .bind(self.user_id)
+        .bind(&self.redirect_uri)
+        .bind(&self.scope)
+        .bind(self.claims.to_json())
+        .bind(&self.nonce)
+        .bind(&self.resource)
+        .bind(&self.code_challenge)
+        .bind(self.authentication.time)
+        .bind(self.authentication.saved_methods())
+        .bind(expires_at)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Inserting authorization code for {} and app {} -> {:?}",
+                self.user_id, self.app_id, error
+            );
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(code)
+    }
+
+    /// Get the code data and delete it so that it can't be used twice
+    pub async fn consume(
+        state: &AppState,
+        code: &str,
+        app_id: i32,
+    ) -> Result<Self, AuthenticatorError> {
+        let row: AuthorizationCodeRow = sqlx::query_as(
+            "DELETE FROM authorization_codes 
+            WHERE 
+                hashed_code = $1 
+                AND app_id = $2
+            RETURNING
+                app_id,
+                user_id,
+                redirect_uri,
+                scope,
+                claims,
+                nonce,
+                resource,
+                code_challenge,
+                auth_time,
+                auth_methods,
+                expires_at",
+        )
+        .bind(hash_text(code))
+        .bind(app_id)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidAuthorizationCode)?;
+
+        if row.expires_at < OffsetDateTime::now_utc() {
+            return Err(AuthenticatorError::InvalidAuthorizationCode);
+        }
+
+        Ok(Self {
+            app_id: row.app_id,
+            user_id: row.user_id,
+            redirect_uri: row.redirect_uri,
+            scope: row.scope,
+            claims: ClaimsRequest::parse(&row.claims).unwrap_or_default(),
+            nonce: row.nonce,
+            resource: row.resource,
+            code_challenge: row.code_challenge,
+            authentication: Authentication::from_saved(&row.auth_methods, row.auth_time),
+        })
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::HashSet;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::{HeaderMap, Method};
+use jsonwebtoken::{decode, decode_header, jwk::Jwk, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::types::time::OffsetDateTime;
+use time::Duration;
+use tracing::log::error;
+
+use crate::{
+    general::AuthenticatorError,
+    utils::{
+        crypto::{hash_text, secrets_match, sign_text},
+        jwt::Confirmation,
+    },
+    AppState,
+};
+
+use super::{ErrorCode, OpenIdConnectError};
+
+pub const DPOP_HEADER: &str = "dpop";
+pub const DPOP_NONCE_HEADER: &str = "dpop-nonce";
+const PROOF_TYPE: &str = "dpop+jwt";
+const SECONDS_TO_EXPIRE: i64 = 300;
+
+/// Claims of a DPoP proof
+/// htm = HTTP method of the request
+/// htu = HTTP URI of the request without query and fragment
+/// ath = access token hash -> hash of the access token sent with the proof
+#[derive(Debug, Deserialize)]
+struct ProofClaims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    nonce: Option<String>,
+    ath: Option<String>,
+}
+
+/// Validated DPoP proof of possession of a key
+/// https://www.rfc-editor.org/rfc/rfc9449.html
+#[derive(Clone, Debug)]
+pub struct DpopProof {
+    pub jkt: String,
+}
+
+impl DpopProof {
+    /// Validate the DPoP header of the request if any
+    /// The token endpoint requires a nonce given by the authenticator
+    pub async fn from_headers(
+        state: &AppState,
+        headers: &HeaderMap,
+        method: &Method,
+        endpoint: &str,
+        access_token: Option<&str>,
+        nonce_is_required: bool,
+    ) -> Result<Option<Self>, OpenIdConnectError> {
+        let mut proofs = headers.get_all(DPOP_HEADER).iter();
+
+        let proof = match (proofs.next(), proofs.next()) {
+            (None, _) => return Ok(None),
+            (Some(proof), None) => proof
+                .to_str()
+                .map_err(|_| invalid_proof("The DPoP proof is not a valid header value"))?,
+            (Some(_), Some(_)) => return Err(invalid_proof("Only one DPoP proof is allowed")),
+        };
+
+        let (jkt, claims) = verify_proof(proof)?;
+
+        let request_uri = state.authenticator_app.url_to_endpoint(endpoint);
+
+        if !claims.matches(
+            method,
+            &request_uri,
+            access_token,
+            OffsetDateTime::now_utc().unix_timestamp(),
+        ) {
+            return Err(invalid_proof(
+                "The DPoP proof does not match the request or has expired",
+            ));
+        }
+
+        if nonce_is_required
+            && !claims
+                .nonce
+                .as_deref()
+                .is_some_and(|nonce| nonce_is_valid(state, nonce))
+        {
+            return Err(OpenIdConnectError::use_dpop_nonce(generate_nonce(state)));
+        }
+
+        save_jti(state, &jkt, &claims.jti)
+            .await
+            .map_err(|_| invalid_proof("The DPoP proof has already been used"))?;
+
+        Ok(Some(Self { jkt }))
+    }
+
+    pub fn confirmation(&self) -> Confirmation {
+        Confirmation {
+            jkt: self.jkt.clone(),
+        }
+    }
+}
+
+impl ProofClaims {
+    /// The proof was made for this request, recently, and with the access token sent with it
+    fn matches(
+        &self,
+        method: &Method,
+        request_uri: &str,
+        access_token: Option<&str>,
+        now: i64,
+    ) -> bool {
+        self.htm == method.as_str()
+            && without_query(&self.htu) == request_uri
+            && (self.iat - now).abs() <= SECONDS_TO_EXPIRE
+            && self.ath == access_token.map(hash_text)
+    }
+}
+
+/// Thumbprint of the key and claims of a proof signed with the jwk of its header
+fn verify_proof(proof: &str) -> Result<(String, ProofClaims), OpenIdConnectError> {
+    let header =
+        decode_header(proof).map_err(|_| invalid_proof("The DPoP proof header is invalid"))?;
+
+    let jwk = match (header.typ.as_deref(), header.jwk) {
+        (Some(PROOF_TYPE), Some(jwk)) if is_asymmetric(header.alg) => jwk,
+        _ => {
+            return Err(invalid_proof(
+                "The DPoP proof must be a dpop+jwt signed with an asymmetric jwk",
+            ))
+        }
+    };
+
+    if has_private_key(proof) {
+        return Err(invalid_proof("The DPoP proof jwk is not a public key"));
+    }
+
+    let jkt = thumbprint(&jwk).ok_or(invalid_proof("The DPoP proof jwk is not supported"))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.required_spec_claims = HashSet::new();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+
+    let claims = decode::<ProofClaims>(
+        proof,
+        &DecodingKey::from_jwk(&jwk)
+            .map_err(|_| invalid_proof("The DPoP proof jwk is not supported"))?,
+        &validation,
+    )
+    .map_err(|_| invalid_proof("The DPoP proof signature or claims are invalid"))?
+    .claims;
+
+    Ok((jkt, claims))
+}
+
+/// The parsed jwk leaves out the private members, so they are looked for in the raw header
+fn has_private_key(proof: &str) -> bool {
+    let header = proof
+        .split('.')
+        .next()
+        .and_then(|header| URL_SAFE_NO_PAD.decode(header).ok())
+        .and_then(|header| serde_json::from_slice::<Value>(&header).ok());
+
+    match header {
+        Some(header) => header["jwk"].get("d").is_some(),
+        None => true,
+    }
+}
+
+fn invalid_proof(description: &str) -> OpenIdConnectError {
+    OpenIdConnectError::json(ErrorCode::InvalidDpopProof, description)
+}
+
+fn is_asymmetric(alg: Algorithm) -> bool {
+    !matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+fn without_query(uri: &str) -> &str {
+    uri.split(['?', '#']).next().unwrap_or(uri)
+}
+
+/// JWK thumbprint of a public key
+/// https://www.rfc-editor.org/rfc/rfc7638.html
+fn thumbprint(jwk: &Jwk) -> Option<String> {
+    let jwk = serde_json::to_value(jwk).ok()?;
+
+    let required_members: &[&str] = match jwk.get("kty")?.as_str()? {
+        "EC" => &["crv", "kty", "x", "y"],
+        "RSA" => &["e", "kty", "n"],
+        "OKP" => &["crv", "kty", "x"],
+        _ => return None,
+    };
+
+    let members = required_members
+        .iter()
+        .map(|member| match jwk.get(*member) {
+            Some(Value::String(value)) => {
+                Some(format!("\"{}\":{}", member, Value::from(value.as_str())))
+            }
+            _ => None,
+        })
+        .collect::<Option<Vec<String>>>()?;
+
+    Some(hash_text(&format!("{{{}}}", members.join(","))))
+}
+
+/// Nonce given by the authenticator so that proofs can't be generated in advance
+pub fn generate_nonce(state: &AppState) -> String {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    format!("{}.{}", now, nonce_signature(state, now))
+}
+
+fn nonce_is_valid(state: &AppState, nonce: &str) -> bool {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    match nonce.split_once('.') {
+        Some((issued_at, signature)) => match issued_at.parse::<i64>() {
+            Ok(issued_at) => {
+                (0..=SECONDS_TO_EXPIRE).contains(&(now - issued_at))
+                    && secrets_match(signature, &nonce_signature(state, issued_at))
+            }
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+fn nonce_signature(state: &AppState, issued_at: i64) -> String {
+    sign_text(&state.authenticator_app.jwt_secret, &issued_at.to_string())
+}
+
+/// The same key can't use the same proof id twice
+fn replay_key(jkt: &str, jti: &str) -> String {
+    hash_text(&format!("{}:{}", jkt, jti))
+}
+
+/// Save the proof id so that the proof can't be replayed
+async fn save_jti(state: &AppState, jkt: &str, jti: &str) -> Result<(), AuthenticatorError> {
+    sqlx::query("DELETE FROM dpop_proofs WHERE expires_at < CURRENT_TIMESTAMP")
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Deleting expired DPoP proofs -> {:?}", error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+    sqlx::query(
+        "INSERT INTO dpop_proofs (
+            hashed_jti,
+            expires_at)
+        VALUES ($1, $2)",
+    )
+    .bind(replay_key(jkt, jti))
+    .bind(OffsetDateTime::now_utc() + Duration::seconds(2 * SECONDS_TO_EXPIRE))
+    .execute(&state.db_pool)
+    .await
+    .map_err(|_| AuthenticatorError::InvalidToken)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+    use serde_json::json;
+
+    use super::*;
+
+    const REQUEST_URI: &str = "https://auth.example.com/openid/token";
+    const NOW: i64 = 1_800_000_000;
+
+    fn key() -> SigningKey {
+        SigningKey::from_slice(&[7u8; 32]).unwrap()
+    }
+
+    fn public_jwk(key: &SigningKey) -> Value {
+        let point = key.verifying_key().to_encoded_point(false);
+
+        json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": URL_SAFE_NO_PAD.encode(point.x().unwrap()),
+            "y": URL_SAFE_NO_PAD.encode(point.y().unwrap()),
+        })
+    }
+
+    /// Proof signed like a DPoP client would
+    fn proof(header: Value, claims: Value) -> String {
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature: Signature = key().sign(signing_input.as_bytes());
+
+        format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+
+    fn header() -> Value {
+        json!({ "typ": PROOF_TYPE, "alg": "ES256", "jwk": public_jwk(&key()) })
+    }
+
+    fn request_claims() -> Value {
+        json!({ "jti": "proof-1", "htm": "POST", "htu": REQUEST_URI, "iat": NOW })
+    }
+
+    fn with(mut value: Value, name: &str, member: Value) -> Value {
+        value[name] = member;
+        value
+    }
+
+    fn verified_claims(claims: Value) -> ProofClaims {
+        verify_proof(&proof(header(), claims)).unwrap().1
+    }
+
+    #[test]
+    fn valid_proofs_give_the_thumbprint_of_their_key() {
+        let (jkt, claims) = verify_proof(&proof(header(), request_claims())).unwrap();
+
+        let jwk = public_jwk(&key());
+        let canonical_jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            jwk["x"], jwk["y"]
+        );
+
+        assert_eq!(jkt, hash_text(&canonical_jwk));
+        assert!(claims.matches(&Method::POST, REQUEST_URI, None, NOW));
+    }
+
+    #[test]
+    fn proofs_must_be_dpop_jwts_signed_with_their_public_key() {
+        for header in [
+            with(header(), "typ", json!("JWT")),
+            with(header(), "typ", Value::Null),
+            with(header(), "alg", json!("HS256")),
+            with(header(), "jwk", Value::Null),
+            with(
+                header(),
+                "jwk",
+                with(public_jwk(&key()), "d", json!("c2VjcmV0")),
+            ),
+        ] {
+            assert!(
+                verify_proof(&proof(header.clone(), request_claims())).is_err(),
+                "{}",
+                header
+            );
+        }
+
+        let other_key = SigningKey::from_slice(&[8u8; 32]).unwrap();
+        let signed_by_other_key = proof(
+            with(header(), "jwk", public_jwk(&other_key)),
+            request_claims(),
+        );
+        assert!(verify_proof(&signed_by_other_key).is_err());
+
+        let tampered_proof = proof(header(), request_claims()).replacen('.', ".e30", 1);
+        assert!(verify_proof(&tampered_proof).is_err());
+    }
+
+    #[test]
+    fn thumbprints_only_use_the_required_members() {
+        let jwk = public_jwk(&key());
+        let jkt = thumbprint(&serde_json::from_value(jwk.clone()).unwrap());
+
+        let with_optional_members = with(
+            with(jwk.clone(), "kid", json!("key-1")),
+            "alg",
+            json!("ES256"),
+        );
+        assert_eq!(
+            thumbprint(&serde_json::from_value(with_optional_members).unwrap()),
+            jkt
+        );
+
+        let other_jwk = public_jwk(&SigningKey::from_slice(&[8u8; 32]).unwrap());
+        assert_ne!(thumbprint(&serde_json::from_value(other_jwk).unwrap()), jkt);
+    }
+
+    #[test]
+    fn proofs_must_match_the_method_and_uri_of_the_request() {
+        let claims = verified_claims(with(
+            request_claims(),
+            "htu",
+            json!(format!("{}?a=b#c", REQUEST_URI)),
+        ));
+        assert!(claims.matches(&Method::POST, REQUEST_URI, None, NOW));
+        assert!(!claims.matches(&Method::GET, REQUEST_URI, None, NOW));
+        assert!(!claims.matches(
+            &Method::POST,
+            "https://auth.example.com/openid/userinfo",
+            None,
+            NOW
+        ));
+
+        let claims = verified_claims(with(
+            request_claims(),
+            "htu",
+            json!("https://evil.example.com/openid/token"),
+        ));
+        assert!(!claims.matches(&Method::POST, REQUEST_URI, None, NOW));
+    }
+
+    #[test]
+    fn proofs_must_be_recent() {
+        let claims = verified_claims(request_claims());
+
+        assert!(claims.matches(&Method::POST, REQUEST_URI, None, NOW + SECONDS_TO_EXPIRE));
+        assert!(claims.matches(&Method::POST, REQUEST_URI, None, NOW - SECONDS_TO_EXPIRE));
+        assert!(!claims.matches(
+            &Method::POST,
+            REQUEST_URI,
+            None,
+            NOW + SECONDS_TO_EXPIRE + 1
+        ));
+        assert!(!claims.matches(
+            &Method::POST,
+            REQUEST_URI,
+            None,
+            NOW - SECONDS_TO_EXPIRE - 1
+        ));
+    }
+
+    #[test]
+    fn proofs_must_hash_the_access_token_sent_with_them() {
+        let access_token = "access-token";
+        let claims = verified_claims(with(
+            request_claims(),
+            "ath",
+            json!(hash_text(access_token)),
+        ));
+
+        assert!(claims.matches(&Method::POST, REQUEST_URI, Some(access_token), NOW));
+        assert!(!claims.matches(&Method::POST, REQUEST_URI, Some("other-token"), NOW));
+        assert!(!claims.matches(&Method::POST, REQUEST_URI, None, NOW));
+
+        let claims = verified_claims(request_claims());
+        assert!(!claims.matches(&Method::POST, REQUEST_URI, Some(access_token), NOW));
+    }
+
+    #[test]
+    fn replayed_proofs_have_the_same_key() {
+        let (jkt, claims) = verify_proof(&proof(header(), request_claims())).unwrap();
+        let (replayed_jkt, replayed_claims) =
+            verify_proof(&proof(header(), request_claims())).unwrap();
+
+        assert_eq!(
+            replay_key(&jkt, &claims.jti),
+            replay_key(&replayed_jkt, &replayed_claims.jti)
+        );
+        assert_ne!(replay_key(&jkt, &claims.jti), replay_key(&jkt, "proof-2"));
+        assert_ne!(replay_key("ab", "c"), replay_key("a", "bc"));
+    }
+}
@@ -0,0 +1,41 @@
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::extract::Query;
+use serde::Deserialize;
+
+use super::ErrorCode;
+
+/// Error shown to the user when it can't be sent back to the app
+/// Also describes the error codes for the error_uri
+#[derive(Template)]
+#[template(path = "openid/error_page.html")]
+pub struct ErrorPage {
+    code: String,
+    explanation: String,
+    description: String,
+}
+
+impl ErrorPage {
+    pub fn from_code(code: ErrorCode, description: &str) -> Self {
+        Self {
+            code: code.name().to_owned(),
+            explanation: code.explanation().to_owned(),
+            description: description.to_owned(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+    error: Option<String>,
+}
+
+pub async fn get_handler(Query(params): Query<QueryParams>) -> impl IntoResponse {
+    let code = params
+        .error
+        .as_deref()
+        .and_then(ErrorCode::from_name)
+        .unwrap_or(ErrorCode::InvalidRequest);
+
+    ErrorPage::from_code(code, "")
+}
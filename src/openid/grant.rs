@@ -0,0 +1,122 @@
+use sqlx::{
+    types::{time::OffsetDateTime, Uuid},
+    FromRow,
+};
+use tracing::log::error;
+
+use crate::{general::AuthenticatorError, AppState};
+
+use super::claims::ClaimsRequest;
+
+/// Authorization given by a user to an app
+/// Saved at each authorization, without a consent screen: apps are registered by the owner of the authenticator
+#[derive(Clone, Debug)]
+pub struct Grant {
+    pub user_id: Uuid,
+    pub app_id: i32,
+    pub scope: String,
+    pub claims: ClaimsRequest,
+}
+
+/// Grant listed to the user with the name of the app
+#[derive(Clone, Debug, FromRow)]
+pub struct AppGrant {
+    pub app_name: String,
+    pub scope: String,
+    pub updated_at: OffsetDateTime,
+}
+
+impl Grant {
+    pub async fn save(&self, state: &AppState) -> Result<(), AuthenticatorError> {
+        sqlx::query(
+            "INSERT INTO grants (
+                user_id,
+                app_id,
+                scope,
+                claims)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, app_id) DO UPDATE
+            SET
+                scope = $3,
+                claims = $4,
+                updated_at = CURRENT_TIMESTAMP",
+        )
+        .bind(self.user_id)
+        .bind(self.app_id)
+        .bind(&self.scope)
+        .bind(self.claims.to_json())
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Saving grant of {} for app {} -> {:?}",
+                self.user_id, self.app_id, error
+            );
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(())
+    }
+
+    pub async fn select(
+        state: &AppState,
+        user_id: Uuid,
+        app_id: i32,
+    ) -> Result<Self, AuthenticatorError> {
+        let (scope, claims): (String, String) = sqlx::query_as(
+            "SELECT 
+                scope,
+                claims
+            FROM grants 
+            WHERE 
+                user_id = $1
+                AND app_id = $2",
+        )
+        .bind(user_id)
+        .bind(app_id)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Selecting grant of {} for app {} -> {:?}",
+                user_id, app_id, error
+            );
+            AuthenticatorError::GrantNotFound
+        })?;
+
+        Ok(Self {
+            user_id,
+            app_id,
+            scope,
+            claims: ClaimsRequest::parse(&claims).unwrap_or_default(),
+        })
+    }
+
+    /// Apps the user has given access to
+    pub async fn select_for_user(
+        state: &AppState,
+        user_id: Uuid,
+    ) -> Result<Vec<AppGrant>, AuthenticatorError> {
+        let grants: Vec<AppGrant> = sqlx::query_as(
+            "SELECT
+                apps.name AS app_name,
+                grants.scope,
+                grants.updated_at
+            FROM grants
+            INNER JOIN apps ON apps.id = grants.app_id
+            WHERE
+                grants.user_id = $1
+            ORDER BY
+                apps.name",
+        )
+        .bind(user_id)
+        .fetch_all(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!("Selecting grants of {} -> {:?}", user_id, error);
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(grants)
+    }
+}
@@ -0,0 +1,153 @@
+use askama_axum::IntoResponse;
+use axum::{extract::State, Form, Json};
+use http::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    apps::{subject::Subject, App},
+    general::AuthenticatorError,
+    utils::jwt::{AccessClaims, Confirmation, TokenFactory},
+    AppState,
+};
+
+use super::{
+    grant::Grant, refresh::RefreshToken, token::authenticate_client, ErrorCode, OpenIdConnectError,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct IntrospectionRequest {
+    token: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// State of a token for the app calling the endpoint
+/// token_type = DPoP when the token is bound to the key of cnf.jkt:
+/// the resource server must then check the DPoP proof sent with the token against it
+/// https://www.rfc-editor.org/rfc/rfc7662.html#section-2.2
+/// https://www.rfc-editor.org/rfc/rfc9449.html#section-6.2
+#[derive(Debug, Default, Serialize)]
+pub struct IntrospectionResponse {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cnf: Option<Confirmation>,
+}
+
+pub async fn post_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Form(request): Form<IntrospectionRequest>,
+) -> Result<impl IntoResponse, OpenIdConnectError> {
+    let app = authenticate_client(
+        &state,
+        &headers,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )
+    .await
+    .and_then(|app| match app.is_native() {
+        true => Err(OpenIdConnectError::json(
+            ErrorCode::InvalidClient,
+            "Native apps can't introspect tokens",
+        )),
+        false => Ok(app),
+    })
+    .map_err(|error| error.with_error_uri(&state))?;
+
+    // Unknown, expired or revoked tokens and tokens of other apps are only inactive
+    let introspection = match TokenFactory::unverified_claims::<AccessClaims>(&request.token) {
+        Ok(_) => introspect_access_token(&state, &app, request.token).await,
+        Err(_) => introspect_refresh_token(&state, &app, &request.token).await,
+    }
+    .unwrap_or_default();
+
+    Ok(([(header::CACHE_CONTROL, "no-store")], Json(introspection)))
+}
+
+/// Access token of the calling app (as client) or giving access to it (as resource)
+async fn introspect_access_token(
+    state: &AppState,
+    app: &App,
+    token: String,
+) -> Result<IntrospectionResponse, AuthenticatorError> {
+    let unverified_claims = TokenFactory::unverified_claims::<AccessClaims>(&token)?;
+
+    let token_factory = TokenFactory::for_audience(state, &unverified_claims.aud).await?;
+
+    let claims = token_factory.extract_access_token(token)?.claims;
+
+    if token_factory.app_id() != app.id && claims.client_id != app.id.to_string() {
+        return Err(AuthenticatorError::InvalidToken);
+    }
+
+    let user_id = token_factory.access_token_user_id(&claims).await?;
+
+    let client_id: i32 = claims
+        .client_id
+        .parse()
+        .map_err(|_| AuthenticatorError::InvalidToken)?;
+
+    Grant::select(state, user_id, client_id).await?;
+
+    Ok(IntrospectionResponse {
+        active: true,
+        token_type: Some(token_type(&claims.cnf)),
+        scope: Some(claims.scope),
+        client_id: Some(claims.client_id),
+        sub: Some(claims.sub),
+        aud: Some(claims.aud),
+        exp: Some(claims.exp),
+        iat: Some(claims.iat),
+        cnf: claims.cnf,
+    })
+}
+
+/// Refresh token given to the calling app
+async fn introspect_refresh_token(
+    state: &AppState,
+    app: &App,
+    token: &str,
+) -> Result<IntrospectionResponse, AuthenticatorError> {
+    let refresh_token = RefreshToken::select(state, token).await?;
+
+    if refresh_token.app_id != app.id {
+        return Err(AuthenticatorError::InvalidToken);
+    }
+
+    Grant::select(state, refresh_token.user_id, app.id).await?;
+
+    let cnf = refresh_token.jkt.map(|jkt| Confirmation { jkt });
+
+    Ok(IntrospectionResponse {
+        active: true,
+        token_type: Some(token_type(&cnf)),
+        scope: Some(refresh_token.scope),
+        client_id: Some(app.id.to_string()),
+        sub: Some(Subject::for_user(state, app, &refresh_token.user_id).await?),
+        aud: None,
+        exp: Some(refresh_token.expires_at.unix_timestamp()),
+        iat: None,
+        cnf,
+    })
+}
+
+fn token_type(cnf: &Option<Confirmation>) -> String {
+    match cnf {
+        Some(_) => "DPoP".to_owned(),
+        None => "Bearer".to_owned(),
+    }
+}
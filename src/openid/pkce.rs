@@ -0,0 +1,95 @@
+use crate::utils::crypto::hash_text;
+
+const S256_METHOD: &str = "S256";
+const MIN_LENGTH: usize = 43;
+const MAX_LENGTH: usize = 128;
+
+/// Proof Key for Code Exchange
+/// The code can only be exchanged by the app that knows the verifier of the challenge
+/// https://www.rfc-editor.org/rfc/rfc7636.html
+pub struct Pkce;
+
+impl Pkce {
+    /// Check the challenge of the authorization request (only S256 is supported)
+    pub fn validate_challenge(
+        code_challenge: Option<&str>,
+        code_challenge_method: Option<&str>,
+        is_required: bool,
+    ) -> Result<Option<String>, &'static str> {
+        let code_challenge = match (code_challenge, code_challenge_method) {
+            (None, None) if is_required => return Err("The code_challenge is required"),
+            (None, None) => return Ok(None),
+            (None, Some(_)) => return Err("The code_challenge is missing"),
+            (Some(code_challenge), Some(S256_METHOD)) => code_challenge,
+            (Some(_), _) => return Err("The code_challenge_method must be S256"),
+        };
+
+        if !is_valid_value(code_challenge) {
+            return Err("The code_challenge is invalid");
+        }
+
+        Ok(Some(code_challenge.to_owned()))
+    }
+
+    /// Check the verifier sent to the token endpoint against the challenge of the code
+    pub fn verify(code_challenge: Option<&str>, code_verifier: Option<&str>) -> bool {
+        match (code_challenge, code_verifier) {
+            (Some(code_challenge), Some(code_verifier)) => {
+                is_valid_value(code_verifier) && hash_text(code_verifier) == code_challenge
+            }
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// 43 to 128 unreserved characters
+fn is_valid_value(value: &str) -> bool {
+    (MIN_LENGTH..=MAX_LENGTH).contains(&value.len())
+        && value
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || matches!(char, '-' | '.' | '_' | '~'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// https://www.rfc-editor.org/rfc/rfc7636.html#appendix-B
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    #[test]
+    fn only_s256_challenges_are_accepted() {
+        assert_eq!(
+            Pkce::validate_challenge(Some(CHALLENGE), Some("S256"), true),
+            Ok(Some(CHALLENGE.to_owned()))
+        );
+        assert!(Pkce::validate_challenge(Some(CHALLENGE), Some("plain"), false).is_err());
+        assert!(Pkce::validate_challenge(Some(CHALLENGE), None, false).is_err());
+        assert!(Pkce::validate_challenge(None, Some("S256"), false).is_err());
+        assert!(Pkce::validate_challenge(Some("too-short"), Some("S256"), false).is_err());
+        assert!(Pkce::validate_challenge(Some(&"a".repeat(129)), Some("S256"), false).is_err());
+        assert!(Pkce::validate_challenge(Some(&"+".repeat(43)), Some("S256"), false).is_err());
+    }
+
+    #[test]
+    fn challenges_are_only_optional_when_not_required() {
+        assert_eq!(Pkce::validate_challenge(None, None, false), Ok(None));
+        assert!(Pkce::validate_challenge(None, None, true).is_err());
+    }
+
+    #[test]
+    fn the_verifier_must_match_the_challenge() {
+        assert!(Pkce::verify(Some(CHALLENGE), Some(VERIFIER)));
+        assert!(Pkce::verify(None, None));
+
+        assert!(!Pkce::verify(
+            Some(CHALLENGE),
+            Some(&VERIFIER.replace('d', "e"))
+        ));
+        assert!(!Pkce::verify(Some(CHALLENGE), None));
+        assert!(!Pkce::verify(None, Some(VERIFIER)));
+        assert!(!Pkce::verify(Some(CHALLENGE), Some(CHALLENGE)));
+    }
+}
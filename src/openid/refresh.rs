@@ -0,0 +1,197 @@
+use sqlx::{
+    types::{time::OffsetDateTime, Uuid},
+    FromRow,
+};
+use time::Duration;
+use tracing::log::error;
+
+use crate::{
+    auth::assurance::Authentication,
+    general::AuthenticatorError,
+    utils::crypto::{hash_text, random_token},
+    AppState,
+};
+
+const SECONDS_TO_EXPIRE: i64 = 30 * 86400;
+
+/// Token given to the app to get new access tokens (scope offline_access)
+/// Each use gives a new refresh token, bound to the same DPoP key if any
+#[derive(Clone, Debug)]
+pub struct RefreshToken {
+    pub app_id: i32,
+    pub user_id: Uuid,
+    pub scope: String,
+    pub resource: Option<String>,
+    pub jkt: Option<String>,
+    pub authentication: Authentication,
+    pub expires_at: OffsetDateTime,
+}
+
+#[derive(FromRow)]
+struct RefreshTokenRow {
+    app_id: i32,
+    user_id: Uuid,
+    scope: String,
+    resource: Option<String>,
+    jkt: Option<String>,
+    auth_time: i64,
+    auth_methods: String,
+    expires_at: OffsetDateTime,
+}
+
+impl From<RefreshTokenRow> for RefreshToken {
+    fn from(row: RefreshTokenRow) -> Self {
+        Self {
+            app_id: row.app_id,
+            user_id: row.user_id,
+            scope: row.scope,
+            resource: row.resource,
+            jkt: row.jkt,
+            authentication: Authentication::from_saved(&row.auth_methods, row.auth_time),
+            expires_at: row.expires_at,
+        }
+    }
+}
+
+impl RefreshToken {
+    pub fn new(
+        app_id: i32,
+        user_id: Uuid,
+        scope: &str,
+        resource: Option<String>,
+        jkt: Option<String>,
+        authentication: &Authentication,
+    ) -> Self {
+        Self {
+            app_id,
+            user_id,
+            scope: scope.to_owned(),
+            resource,
+            jkt,
+            authentication: authentication.clone(),
+            expires_at: OffsetDateTime::now_utc() + Duration::seconds(SECONDS_TO_EXPIRE),
+        }
+    }
+
+    /// The app asked to keep access to the user
+    pub fn is_requested_by(scope: &str) -> bool {
+        scope
+            .split_whitespace()
+            .any(|value| value == "offline_access")
+    }
+
+    /// Save the token and give it back (only its hash is saved)
+    pub async fn generate(&self, state: &AppState) -> Result<String, AuthenticatorError> {
+        let token = random_token();
+
+        sqlx::query(
+            "INSERT INTO refresh_tokens (
+                hashed_token,
+                app_id,
+                user_id,
+                scope,
+                resource,
+                jkt,
+                auth_time,
+                auth_methods,
+                expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(hash_text(&token))
+        .bind(self.app_id)
+        .bind(self.user_id)
+        .bind(&self.scope)
+        .bind(&self.resource)
+        .bind(&self.jkt)
+        .bind(self.authentication.time)
+        .bind(self.authentication.saved_methods())
+        .bind(self.expires_at)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|error| {
+            error!(
+                "Inserting refresh token for {} and app {} -> {:?}",
+                self.user_id, self.app_id, error
+            );
+            AuthenticatorError::DatabaseError
+        })?;
+
+        Ok(token)
+    }
+
+    /// Get the token data and delete it so that it can't be used twice
+    pub async fn consume(
+        state: &AppState,
+        token: &str,
+        app_id: i32,
+    ) -> Result<Self, AuthenticatorError> {
+        let row: RefreshTokenRow = sqlx::query_as(
+            "DELETE FROM refresh_tokens
+            WHERE
+                hashed_token = $1
+                AND app_id = $2
+            RETURNING
+                app_id,
+                user_id,
+                scope,
+                resource,
+                jkt,
+                auth_time,
+                auth_methods,
+                expires_at",
+        )
+        .bind(hash_text(token))
+        .bind(app_id)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidToken)?;
+
+        if row.expires_at < OffsetDateTime::now_utc() {
+            return Err(AuthenticatorError::InvalidToken);
+        }
+
+        Ok(row.into())
+    }
+
+    /// Read the token without using it (introspection)
+    pub async fn select(state: &AppState, token: &str) -> Result<Self, AuthenticatorError> {
+        let row: RefreshTokenRow = sqlx::query_as(
+            "SELECT
+                app_id,
+                user_id,
+                scope,
+                resource,
+                jkt,
+                auth_time,
+                auth_methods,
+                expires_at
+            FROM refresh_tokens
+            WHERE
+                hashed_token = $1
+                AND expires_at > CURRENT_TIMESTAMP",
+        )
+        .bind(hash_text(token))
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|_| AuthenticatorError::InvalidToken)?;
+
+        Ok(row.into())
+    }
+
+    /// Revoke every refresh token of the user (password change)
+    pub async fn delete_for_user(
+        state: &AppState,
+        user_id: Uuid,
+    ) -> Result<(), AuthenticatorError> {
+        sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&state.db_pool)
+            .await
+            .map_err(|error| {
+                error!("Deleting refresh tokens of {} -> {:?}", user_id, error);
+                AuthenticatorError::DatabaseError
+            })?;
+
+        Ok(())
+    }
+}
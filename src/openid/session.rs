@@ -0,0 +1,44 @@
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::extract::State;
+use url::Url;
+
+use crate::{
+    auth::BROWSER_STATE,
+    utils::crypto::{hash_text, random_token},
+    AppState,
+};
+
+const SALT_LENGTH: usize = 16;
+
+/// Session state given to the app with the authorization response
+/// The check session iframe computes it again to know if the session has changed
+/// https://openid.net/specs/openid-connect-session-1_0.html#CreatingUpdatingSessions
+pub fn session_state(client_id: i32, redirect_uri: &Url, browser_state: &str) -> String {
+    let salt: String = random_token().chars().take(SALT_LENGTH).collect();
+
+    let origin = redirect_uri.origin().ascii_serialization();
+
+    format!(
+        "{}.{}",
+        hash_text(&format!(
+            "{} {} {} {}",
+            client_id, origin, browser_state, salt
+        )),
+        salt
+    )
+}
+
+/// Iframe embedded by the apps to be notified when the session changes
+/// https://openid.net/specs/openid-connect-session-1_0.html#OPiframe
+#[derive(Template)]
+#[template(path = "openid/check_session_iframe.html")]
+pub struct CheckSessionIframe {
+    browser_state_cookie: String,
+}
+
+pub async fn check_session_handler(State(state): State<AppState>) -> impl IntoResponse {
+    CheckSessionIframe {
+        browser_state_cookie: state.cookie_policy.name(BROWSER_STATE),
+    }
+}
@@ -0,0 +1,728 @@
+use askama_axum::IntoResponse;
+use axum::{extract::State, Form, Json};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header, HeaderMap, HeaderName, Method};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+
+use time::OffsetDateTime;
+
+use crate::{
+    apps::{api_resource::ApiResource, App},
+    auth::assurance::Authentication,
+    general::AuthenticatorError,
+    users::User,
+    utils::{
+        crypto::secrets_match,
+        jwt::{AccessClaims, Actor, Confirmation, Token, TokenFactory},
+    },
+    AppState,
+};
+
+use super::{
+    claims::ClaimsTarget,
+    code::AuthorizationCode,
+    dpop::{generate_nonce, DpopProof, DPOP_NONCE_HEADER},
+    grant::Grant,
+    pkce::Pkce,
+    refresh::RefreshToken,
+    ErrorCode, OpenIdConnectError,
+};
+
+const TOKEN_ENDPOINT: &str = "/openid/token";
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    grant_type: Option<String>,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    scope: Option<String>,
+    subject_token: Option<String>,
+    subject_token_type: Option<String>,
+    requested_token_type: Option<String>,
+    audience: Option<String>,
+    resource: Option<String>,
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    access_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issued_token_type: Option<String>,
+    token_type: String,
+    expires_in: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    scope: String,
+}
+
+pub async fn post_handler(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Form(token_request): Form<TokenRequest>,
+) -> Result<impl IntoResponse, OpenIdConnectError> {
+    token_handler(&state, &headers, token_request)
+        .await
+        .map_err(|error| error.with_error_uri(&state))
+}
+
+async fn token_handler(
+    state: &AppState,
+    headers: &HeaderMap,
+    token_request: TokenRequest,
+) -> Result<impl IntoResponse, OpenIdConnectError> {
+    let app = authenticate_client(
+        state,
+        headers,
+        token_request.client_id.as_deref(),
+        token_request.client_secret.as_deref(),
+    )
+    .await?;
+
+    let dpop_proof =
+        DpopProof::from_headers(state, headers, &Method::POST, TOKEN_ENDPOINT, None, true).await?;
+
+    let token_response = match token_request.grant_type.as_deref() {
+        Some("authorization_code") => {
+            authorization_code_grant(state, &app, token_request, dpop_proof.as_ref()).await
+        }
+        Some("refresh_token") => {
+            refresh_token_grant(state, &app, token_request, dpop_proof.as_ref()).await
+        }
+        Some(TOKEN_EXCHANGE_GRANT_TYPE) if app.is_native() => Err(OpenIdConnectError::json(
+            ErrorCode::UnauthorizedClient,
+            "Native apps can't exchange tokens",
+        )),
+        Some(TOKEN_EXCHANGE_GRANT_TYPE) => {
+            token_exchange_grant(state, &app, token_request, dpop_proof.as_ref()).await
+        }
+        Some(_) => Err(OpenIdConnectError::json(
+            ErrorCode::UnsupportedGrantType,
+            "The grant_type is not supported",
+        )),
+        None => Err(OpenIdConnectError::json(
+            ErrorCode::InvalidRequest,
+            "The grant_type is missing",
+        )),
+    }?;
+
+    Ok((
+        [
+            (header::CACHE_CONTROL, "no-store".to_owned()),
+            (
+                HeaderName::from_static(DPOP_NONCE_HEADER),
+                generate_nonce(state),
+            ),
+        ],
+        Json(token_response),
+    ))
+}
+
+/// Client id and secret (if any) from the basic authorization header or else from the form
+fn client_credentials(
+    headers: &HeaderMap,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Option<(String, Option<String>)> {
+    let basic_credentials = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|credentials| STANDARD.decode(credentials).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| {
+            credentials
+                .split_once(':')
+                .map(|(id, secret)| (id.to_owned(), Some(secret.to_owned())))
+        });
+
+    basic_credentials
+        .or(client_id.map(|id| (id.to_owned(), client_secret.map(str::to_owned))))
+        .map(|(id, secret)| (id, secret.filter(|secret| !secret.is_empty())))
+}
+
+/// App calling the endpoint
+/// Web apps authenticate with their secret, native apps are public clients and only give their id:
+/// the secret signs their tokens so it can't be shipped in the app, their codes are bound with PKCE instead
+pub async fn authenticate_client(
+    state: &AppState,
+    headers: &HeaderMap,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<App, OpenIdConnectError> {
+    let invalid_client =
+        || OpenIdConnectError::json(ErrorCode::InvalidClient, "The client authentication failed");
+
+    let (client_id, client_secret) =
+        client_credentials(headers, client_id, client_secret).ok_or(OpenIdConnectError::json(
+            ErrorCode::InvalidClient,
+            "The client credentials are missing",
+        ))?;
+
+    let app_id: i32 = client_id.parse().map_err(|_| invalid_client())?;
+
+    let app = App::select_from_app_id(state, app_id)
+        .await
+        .map_err(|_| invalid_client())?;
+
+    let is_authenticated = match (app.is_native(), client_secret) {
+        (true, None) => true,
+        (false, Some(client_secret)) => secrets_match(&app.jwt_secret, &client_secret),
+        _ => false,
+    };
+
+    if app.is_authenticator_app() || !is_authenticated {
+        return Err(invalid_client());
+    }
+
+    Ok(app)
+}
+
+async fn authorization_code_grant(
+    state: &AppState,
+    app: &App,
+    token_request: TokenRequest,
+    dpop_proof: Option<&DpopProof>,
+) -> Result<TokenResponse, OpenIdConnectError> {
+    let code = token_request.code.ok_or(OpenIdConnectError::json(
+        ErrorCode::InvalidRequest,
+        "The code is missing",
+    ))?;
+
+    let authorization_code = AuthorizationCode::consume(state, &code, app.id)
+        .await
+        .map_err(|_| {
+            OpenIdConnectError::json(
+                ErrorCode::InvalidGrant,
+                "The code is invalid, expired or already used",
+            )
+        })?;
+
+    if token_request.redirect_uri != Some(authorization_code.redirect_uri.clone()) {
+        return Err(OpenIdConnectError::json(
+            ErrorCode::InvalidGrant,
+            "The redirect_uri does not match the authorization request",
+        ));
+    }
+
+    // Native apps are only authenticated by the verifier
+    if app.requires_pkce() && authorization_code.code_challenge.is_none() {
+        return Err(OpenIdConnectError::json(
+            ErrorCode::InvalidGrant,
+            "The code was not requested with a code_challenge",
+        ));
+    }
+
+    if !Pkce::verify(
+        authorization_code.code_challenge.as_deref(),
+        token_request.code_verifier.as_deref(),
+    ) {
+        return Err(OpenIdConnectError::json(
+            ErrorCode::InvalidGrant,
+            "The code_verifier does not match the code_challenge",
+        ));
+    }
+
+    let user = User::select_from_id(&state.db_pool, authorization_code.user_id)
+        .await
+        .map_err(|_| {
+            OpenIdConnectError::json(ErrorCode::InvalidGrant, "The user no longer exists")
+        })?;
+
+    let released_claims = authorization_code
+        .claims
+        .released_claims(ClaimsTarget::IdToken, &authorization_code.scope);
+
+    let resource = match (token_request.resource, authorization_code.resource) {
+        (Some(requested), Some(authorized)) if requested != authorized => {
+            return Err(OpenIdConnectError::json(
+                ErrorCode::InvalidTarget,
+                "The resource does not match the authorization request",
+            ))
+        }
+        (Some(resource), _) | (None, Some(resource)) => Some(
+            ApiResource::select_permitted(state, &resource, app)
+                .await
+                .map_err(|_| invalid_target())?,
+        ),
+        (None, None) => None,
+    };
+
+    let resource_identifier = resource
+        .as_ref()
+        .map(|resource| resource.identifier.clone());
+
+    let access_token = generate_access_token(
+        state,
+        app,
+        &user,
+        &authorization_code.scope,
+        resource,
+        &authorization_code.authentication,
+        dpop_proof,
+    )
+    .await?;
+
+    let id_token = TokenFactory::for_app(state, app)
+        .generate_id_token_with_claims(
+            &user,
+            &released_claims,
+            authorization_code.nonce,
+            &authorization_code.authentication,
+            app.jwt_seconds_to_expire,
+        )
+        .await
+        .map_err(|_| token_generation_failed())?;
+
+    let refresh_token = match RefreshToken::is_requested_by(&authorization_code.scope) {
+        true => Some(
+            RefreshToken::new(
+                app.id,
+                user.id,
+                &authorization_code.scope,
+                resource_identifier,
+                dpop_proof.map(|dpop_proof| dpop_proof.jkt.clone()),
+                &authorization_code.authentication,
+            )
+            .generate(state)
+            .await
+            .map_err(|_| token_generation_failed())?,
+        ),
+        false => None,
+    };
+
+    Ok(TokenResponse {
+        access_token: access_token.token,
+        issued_token_type: None,
+        token_type: token_type(dpop_proof),
+        expires_in: app.jwt_seconds_to_expire,
+        id_token: Some(id_token.token),
+        refresh_token,
+        scope: access_token.claims.scope,
+    })
+}
+
+/// New access token (and refresh token) from a refresh token of the app
+/// A refresh token bound to a DPoP key can only be used with a proof of the same key
+/// https://www.rfc-editor.org/rfc/rfc9449.html#section-5
+async fn refresh_token_grant(
+    state: &AppState,
+    app: &App,
+    token_request: TokenRequest,
+    dpop_proof: Option<&DpopProof>,
+) -> Result<TokenResponse, OpenIdConnectError> {
+    let token = token_request.refresh_token.ok_or(OpenIdConnectError::json(
+        ErrorCode::InvalidRequest,
+        "The refresh_token is missing",
+    ))?;
+
+    let refresh_token = RefreshToken::consume(state, &token, app.id)
+        .await
+        .map_err(|_| {
+            OpenIdConnectError::json(
+                ErrorCode::InvalidGrant,
+                "The refresh_token is invalid, expired or already used",
+            )
+        })?;
+
+    if refresh_token.jkt.is_some()
+        && refresh_token.jkt.as_ref() != dpop_proof.map(|dpop_proof| &dpop_proof.jkt)
+    {
+        return Err(OpenIdConnectError::json(
+            ErrorCode::InvalidGrant,
+            "The refresh_token is bound to another DPoP key",
+        ));
+    }
+
+    let scope = match token_request.scope {
+        Some(scope) if !scope_is_included(&scope, &refresh_token.scope) => {
+            return Err(OpenIdConnectError::json(
+                ErrorCode::InvalidScope,
+                "The scope exceeds the scope of the refresh_token",
+            ))
+        }
+        Some(scope) => scope,
+        None => refresh_token.scope.clone(),
+    };
+
+    // The user may have revoked the app since
+    Grant::select(state, refresh_token.user_id, app.id)
+        .await
+        .map_err(|_| {
+            OpenIdConnectError::json(ErrorCode::InvalidGrant, "The grant has been revoked")
+        })?;
+
+    let user = User::select_from_id(&state.db_pool, refresh_token.user_id)
+        .await
+        .map_err(|_| {
+            OpenIdConnectError::json(ErrorCode::InvalidGrant, "The user no longer exists")
+        })?;
+
+    let resource = match &refresh_token.resource {
+        Some(resource) => Some(
+            ApiResource::select_permitted(state, resource, app)
+                .await
+                .map_err(|_| invalid_target())?,
+        ),
+        None => None,
+    };
+
+    let access_token = generate_access_token(
+        state,
+        app,
+        &user,
+        &scope,
+        resource,
+        &refresh_token.authentication,
+        dpop_proof,
+    )
+    .await?;
+
+    let renewed_refresh_token = RefreshToken::new(
+        app.id,
+        user.id,
+        &refresh_token.scope,
+        refresh_token.resource.clone(),
+        refresh_token
+            .jkt
+            .clone()
+            .or(dpop_proof.map(|dpop_proof| dpop_proof.jkt.clone())),
+        &refresh_token.authentication,
+    )
+    .generate(state)
+    .await
+    .map_err(|_| token_generation_failed())?;
+
+    Ok(TokenResponse {
+        access_token: access_token.token,
+        issued_token_type: None,
+        token_type: token_type(dpop_proof),
+        expires_in: app.jwt_seconds_to_expire,
+        id_token: None,
+        refresh_token: Some(renewed_refresh_token),
+        scope: access_token.claims.scope,
+    })
+}
+
+/// Access token to call the authenticator or the API resource, bound to the key of the proof
+async fn generate_access_token(
+    state: &AppState,
+    app: &App,
+    user: &User,
+    scope: &str,
+    resource: Option<ApiResource>,
+    authentication: &Authentication,
+    dpop_proof: Option<&DpopProof>,
+) -> Result<Token<AccessClaims>, OpenIdConnectError> {
+    match resource {
+        Some(resource) => {
+            let resource_app = App::select_from_app_id(state, resource.app_id)
+                .await
+                .map_err(|_| invalid_target())?;
+
+            TokenFactory::for_resource(state, &resource_app, &resource)
+                .generate_access_token(
+                    user,
+                    app,
+                    &resource.granted_scope(scope),
+                    authentication,
+                    dpop_proof.map(DpopProof::confirmation),
+                )
+                .await
+        }
+        None => {
+            TokenFactory::for_authenticator(state)
+                .generate_access_token(
+                    user,
+                    app,
+                    scope,
+                    authentication,
+                    dpop_proof.map(DpopProof::confirmation),
+                )
+                .await
+        }
+    }
+    .map_err(|_| token_generation_failed())
+}
+
+/// Exchange a token of the user obtained by the app for a token to call the audience app or API resource
+/// https://www.rfc-editor.org/rfc/rfc8693.html
+async fn token_exchange_grant(
+    state: &AppState,
+    app: &App,
+    token_request: TokenRequest,
+    dpop_proof: Option<&DpopProof>,
+) -> Result<TokenResponse, OpenIdConnectError> {
+    let subject_token = token_request.subject_token.ok_or(OpenIdConnectError::json(
+        ErrorCode::InvalidRequest,
+        "The subject_token is missing",
+    ))?;
+
+    match token_request.subject_token_type.as_deref() {
+        Some(ACCESS_TOKEN_TYPE) | Some(JWT_TOKEN_TYPE) => (),
+        _ => {
+            return Err(OpenIdConnectError::json(
+                ErrorCode::InvalidRequest,
+                "The subject_token_type is not supported",
+            ))
+        }
+    }
+
+    match token_request.requested_token_type.as_deref() {
+        None | Some(ACCESS_TOKEN_TYPE) => (),
+        _ => {
+            return Err(OpenIdConnectError::json(
+                ErrorCode::InvalidRequest,
+                "The requested_token_type is not supported",
+            ))
+        }
+    }
+
+    let audience_token_factory = exchange_target(
+        state,
+        app,
+        token_request.resource.as_deref(),
+        token_request.audience.as_deref(),
+    )
+    .await?;
+
+    let subject = SubjectToken::from_access_token(state, app, subject_token)
+        .await
+        .map_err(|_| {
+            OpenIdConnectError::json(
+                ErrorCode::InvalidGrant,
+                "The subject_token is invalid or expired",
+            )
+        })?;
+
+    // A token bound to a key can only be exchanged by the holder of the key
+    if subject.cnf.is_some() && subject.cnf != dpop_proof.map(DpopProof::confirmation) {
+        return Err(OpenIdConnectError::json(
+            ErrorCode::InvalidGrant,
+            "The subject_token is bound to another DPoP key",
+        ));
+    }
+
+    let scope = match token_request.scope {
+        Some(scope) if !scope_is_included(&scope, &subject.scope) => {
+            return Err(OpenIdConnectError::json(
+                ErrorCode::InvalidScope,
+                "The scope exceeds the scope of the subject_token",
+            ))
+        }
+        Some(scope) => scope,
+        None => subject.scope,
+    };
+
+    let user = User::select_from_id(&state.db_pool, subject.user_id)
+        .await
+        .map_err(|_| {
+            OpenIdConnectError::json(ErrorCode::InvalidGrant, "The user no longer exists")
+        })?;
+
+    let mut exchanged_claims = audience_token_factory
+        .access_claims(&user, app, &scope, &subject.authentication)
+        .await
+        .map_err(|_| token_generation_failed())?;
+
+    exchanged_claims.exp = exchanged_claims.exp.min(subject.exp);
+    exchanged_claims.cnf = dpop_proof.map(DpopProof::confirmation);
+    exchanged_claims.act = Some(Actor {
+        sub: app.id.to_string(),
+        act: subject.act.map(Box::new),
+    });
+
+    let exchanged_token = audience_token_factory
+        .encode_access_token(exchanged_claims)
+        .map_err(|_| token_generation_failed())?;
+
+    let seconds_to_expire = exchanged_token.claims.exp - OffsetDateTime::now_utc().unix_timestamp();
+
+    Ok(TokenResponse {
+        access_token: exchanged_token.token,
+        issued_token_type: Some(ACCESS_TOKEN_TYPE.to_owned()),
+        token_type: token_type(dpop_proof),
+        expires_in: i32::try_from(seconds_to_expire).unwrap_or(0),
+        id_token: None,
+        refresh_token: None,
+        scope,
+    })
+}
+
+/// Factory of the app or API resource targeted by a token exchange
+/// The app must be allowed to exchange tokens for the target app
+async fn exchange_target(
+    state: &AppState,
+    app: &App,
+    resource: Option<&str>,
+    audience: Option<&str>,
+) -> Result<TokenFactory, OpenIdConnectError> {
+    let (target_app, resource) = match (resource, audience) {
+        (Some(resource), _) => {
+            let resource = ApiResource::select_permitted(state, resource, app)
+                .await
+                .map_err(|_| invalid_target())?;
+
+            let resource_app = App::select_from_app_id(state, resource.app_id)
+                .await
+                .map_err(|_| invalid_target())?;
+
+            (resource_app, Some(resource))
+        }
+
+        (None, Some(audience)) => {
+            let audience_id: i32 = audience.parse().map_err(|_| invalid_target())?;
+
+            let audience_app = App::select_from_app_id(state, audience_id)
+                .await
+                .map_err(|_| invalid_target())?;
+
+            (audience_app, None)
+        }
+
+        (None, None) => {
+            return Err(OpenIdConnectError::json(
+                ErrorCode::InvalidRequest,
+                "The resource or the audience is missing",
+            ))
+        }
+    };
+
+    if !app.can_exchange_tokens_for(&target_app) {
+        return Err(invalid_target());
+    }
+
+    match resource {
+        Some(resource) => Ok(TokenFactory::for_resource(state, &target_app, &resource)),
+        None => Ok(TokenFactory::for_app(state, &target_app)),
+    }
+}
+
+/// Validated subject token of a token exchange
+struct SubjectToken {
+    user_id: Uuid,
+    scope: String,
+    authentication: Authentication,
+    act: Option<Actor>,
+    cnf: Option<Confirmation>,
+    exp: i64,
+}
+
+impl SubjectToken {
+    /// Access token obtained by the app (as client) for another app or API resource
+    /// Tokens of the app itself are signed with its own secret, so it could forge them
+    /// The scope is limited to the one the user still grants to the app
+    async fn from_access_token(
+        state: &AppState,
+        app: &App,
+        token: String,
+    ) -> Result<Self, AuthenticatorError> {
+        let unverified_claims = TokenFactory::unverified_claims::<AccessClaims>(&token)?;
+
+        let token_factory = TokenFactory::for_audience(state, &unverified_claims.aud).await?;
+
+        if !is_issued_to(token_factory.app_id(), &unverified_claims.client_id, app.id) {
+            return Err(AuthenticatorError::InvalidToken);
+        }
+
+        let claims = token_factory.extract_access_token(token)?.claims;
+
+        let user_id = token_factory.access_token_user_id(&claims).await?;
+
+        let grant = Grant::select(state, user_id, app.id).await?;
+
+        Ok(Self {
+            user_id,
+            scope: granted_scope(&claims.scope, &grant.scope),
+            authentication: Authentication {
+                methods: claims.amr,
+                time: claims.auth_time,
+            },
+            act: claims.act,
+            cnf: claims.cnf,
+            exp: claims.exp,
+        })
+    }
+}
+
+/// The token has been issued to the app (its client) and signed by another app
+fn is_issued_to(signing_app_id: i32, client_id: &str, app_id: i32) -> bool {
+    signing_app_id != app_id && client_id == app_id.to_string()
+}
+
+/// Scope values of the token still granted by the user
+fn granted_scope(token_scope: &str, granted_scope: &str) -> String {
+    token_scope
+        .split_whitespace()
+        .filter(|value| scope_is_included(value, granted_scope))
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn invalid_target() -> OpenIdConnectError {
+    OpenIdConnectError::json(
+        ErrorCode::InvalidTarget,
+        "The resource or audience is unknown or not permitted for the client",
+    )
+}
+
+fn token_generation_failed() -> OpenIdConnectError {
+    OpenIdConnectError::json(ErrorCode::ServerError, "The tokens can't be generated")
+}
+
+/// DPoP when the access token is bound to the key of the proof
+fn token_type(dpop_proof: Option<&DpopProof>) -> String {
+    match dpop_proof {
+        Some(_) => "DPoP".to_owned(),
+        None => "Bearer".to_owned(),
+    }
+}
+
+/// Check that every requested scope value has been granted
+fn scope_is_included(requested_scope: &str, granted_scope: &str) -> bool {
+    requested_scope.split_whitespace().all(|requested| {
+        granted_scope
+            .split_whitespace()
+            .any(|granted| granted == requested)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_signed_by_the_app_itself_are_refused() {
+        // Audience is the calling app: signed with the secret it holds
+        assert!(!is_issued_to(7, "7", 7));
+        // Issued to another client for the calling app
+        assert!(!is_issued_to(7, "3", 7));
+        // Issued to another client for another app
+        assert!(!is_issued_to(5, "3", 7));
+        assert!(!is_issued_to(5, "", 7));
+    }
+
+    #[test]
+    fn tokens_obtained_by_the_app_for_another_app_are_accepted() {
+        assert!(is_issued_to(5, "7", 7));
+    }
+
+    #[test]
+    fn ungranted_scope_values_are_dropped() {
+        assert_eq!(
+            granted_scope("openid profile admin", "openid profile"),
+            "openid profile"
+        );
+        assert_eq!(granted_scope("admin", "openid profile"), "");
+        assert_eq!(granted_scope("openid  mail", "mail openid"), "openid mail");
+    }
+}
@@ -0,0 +1,159 @@
+use axum::{extract::State, Json};
+use http::{header, HeaderMap, Method};
+use serde_json::{Map, Value};
+
+use crate::{
+    users::User,
+    utils::jwt::{AccessClaims, TokenFactory},
+    AppState,
+};
+
+use super::{
+    claims::{ClaimsRequest, ClaimsTarget},
+    dpop::DpopProof,
+    grant::Grant,
+    ErrorCode, OpenIdConnectError,
+};
+
+const USERINFO_ENDPOINT: &str = "/openid/userinfo";
+
+/// Authorization scheme of the access token
+#[derive(Clone, Copy, PartialEq)]
+enum TokenScheme {
+    Bearer,
+    Dpop,
+}
+
+impl TokenScheme {
+    fn name(&self) -> &'static str {
+        match self {
+            TokenScheme::Bearer => "Bearer",
+            TokenScheme::Dpop => "DPoP",
+        }
+    }
+
+    fn invalid_token(&self, description: &str) -> OpenIdConnectError {
+        OpenIdConnectError::challenge(ErrorCode::InvalidToken, description, self.name())
+    }
+}
+
+pub async fn handler(
+    method: Method,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<Map<String, Value>>, OpenIdConnectError> {
+    userinfo_handler(&state, &method, &headers)
+        .await
+        .map_err(|error| error.with_error_uri(&state))
+}
+
+async fn userinfo_handler(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Json<Map<String, Value>>, OpenIdConnectError> {
+    let (scheme, token) = access_token(headers).ok_or(OpenIdConnectError::challenge(
+        ErrorCode::InvalidRequest,
+        "The access token is missing",
+        TokenScheme::Bearer.name(),
+    ))?;
+
+    let token_factory = TokenFactory::for_authenticator(state);
+
+    let claims = token_factory
+        .extract_access_token(token.clone())
+        .map_err(|_| scheme.invalid_token("The access token is invalid or expired"))?
+        .claims;
+
+    validate_binding(state, method, headers, scheme, &token, &claims).await?;
+
+    if !claims
+        .scope
+        .split_whitespace()
+        .any(|value| value == "openid")
+    {
+        return Err(OpenIdConnectError::challenge(
+            ErrorCode::InsufficientScope,
+            "The access token does not grant the openid scope",
+            scheme.name(),
+        ));
+    }
+
+    let user_id = token_factory
+        .access_token_user_id(&claims)
+        .await
+        .map_err(|_| scheme.invalid_token("The user of the access token is unknown"))?;
+
+    let client_id: i32 = claims
+        .client_id
+        .parse()
+        .map_err(|_| scheme.invalid_token("The client of the access token is unknown"))?;
+
+    let grant = Grant::select(state, user_id, client_id)
+        .await
+        .map_err(|_| scheme.invalid_token("The grant of the access token has been revoked"))?;
+
+    let user = User::select_from_id(&state.db_pool, user_id)
+        .await
+        .map_err(|_| scheme.invalid_token("The user of the access token is unknown"))?;
+
+    let released_claims = grant
+        .claims
+        .released_claims(ClaimsTarget::Userinfo, &claims.scope);
+
+    let mut userinfo = ClaimsRequest::user_claims(&user, &released_claims);
+    userinfo.insert("sub".to_owned(), Value::from(claims.sub));
+
+    Ok(Json(userinfo))
+}
+
+fn access_token(headers: &HeaderMap) -> Option<(TokenScheme, String)> {
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())?;
+
+    match authorization.split_once(' ') {
+        Some(("Bearer", token)) => Some((TokenScheme::Bearer, token.to_owned())),
+        Some(("DPoP", token)) => Some((TokenScheme::Dpop, token.to_owned())),
+        _ => None,
+    }
+}
+
+/// A token bound to a key must be sent with a DPoP proof of possession of the key
+async fn validate_binding(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+    scheme: TokenScheme,
+    token: &str,
+    claims: &AccessClaims,
+) -> Result<(), OpenIdConnectError> {
+    match (scheme, &claims.cnf) {
+        (TokenScheme::Bearer, None) => Ok(()),
+
+        (TokenScheme::Bearer, Some(_)) => Err(TokenScheme::Dpop
+            .invalid_token("The access token is bound to a key and requires the DPoP scheme")),
+
+        (TokenScheme::Dpop, Some(confirmation)) => {
+            let dpop_proof = DpopProof::from_headers(
+                state,
+                headers,
+                method,
+                USERINFO_ENDPOINT,
+                Some(token),
+                false,
+            )
+            .await
+            .map_err(|error| error.into_challenge(scheme.name()))?;
+
+            match dpop_proof {
+                Some(dpop_proof) if dpop_proof.confirmation() == *confirmation => Ok(()),
+                _ => Err(scheme.invalid_token("The DPoP proof does not match the access token")),
+            }
+        }
+
+        (TokenScheme::Dpop, None) => {
+            Err(scheme.invalid_token("The access token is not bound to a DPoP key"))
+        }
+    }
+}
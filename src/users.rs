@@ -1,5 +1,9 @@
 pub mod confirm;
+pub mod passkeys;
+pub mod password;
 pub mod profile;
+pub mod recovery_codes;
+pub mod two_factor;
 
 use sqlx::{
     types::{
@@ -173,9 +177,19 @@ impl User {
     pub async fn update_password(
         db_pool: &PgPool,
         user_id: &Uuid,
-        password: &String,
-        confirm_password: &String,
+        password: &str,
+        confirm_password: &str,
     ) -> Result<bool, AuthenticatorError> {
+        let encrypted_password = Self::encrypt_new_password(password, confirm_password)?;
+
+        Self::update_encrypted_password(db_pool, user_id, &encrypted_password).await
+    }
+
+    /// Check the new password before anything is changed (single-use links, codes...)
+    pub fn encrypt_new_password(
+        password: &str,
+        confirm_password: &str,
+    ) -> Result<String, AuthenticatorError> {
         if password.is_empty() || confirm_password.is_empty() {
             return Err(AuthenticatorError::MissingInformation);
         }
@@ -184,8 +198,14 @@ impl User {
             return Err(AuthenticatorError::PasswordsDoNotMatch);
         }
 
-        let encrypted_password = encrypt_text(password)?;
+        encrypt_text(password)
+    }
 
+    pub async fn update_encrypted_password(
+        db_pool: &PgPool,
+        user_id: &Uuid,
+        encrypted_password: &str,
+    ) -> Result<bool, AuthenticatorError> {
         let nb_of_password_updated =
             sqlx::query("UPDATE users SET encrypted_password = $1 WHERE id = $2")
                 .bind(encrypted_password)
@@ -206,10 +226,22 @@ impl User {
         app: &App,
         token: String,
     ) -> Result<String, AuthenticatorError> {
-        let claims = TokenFactory::for_app(state, app)
-            .extract_id_token(token)?
-            .claims;
+        let token_factory = TokenFactory::for_app(state, app);
 
+        let claims = token_factory.extract_id_token(token)?.claims;
+
+        let user_id = token_factory.user_id(&claims).await?;
+
+        Self::mark_mail_as_confirmed(&state.db_pool, user_id, &claims.mail.unwrap_or_default())
+            .await
+    }
+
+    /// Confirm the mail if it is still the mail of the user
+    pub async fn mark_mail_as_confirmed(
+        db_pool: &PgPool,
+        user_id: Uuid,
+        mail: &str,
+    ) -> Result<String, AuthenticatorError> {
         let (confirmed_mail, mail_is_confirmed): (String, bool) = sqlx::query_as(
             "UPDATE users 
             SET 
@@ -221,12 +253,12 @@ impl User {
                 mail, 
                 mail_is_confirmed",
         )
-        .bind(claims.user_id())
-        .bind(&claims.mail)
-        .fetch_one(&state.db_pool)
+        .bind(user_id)
+        .bind(mail)
+        .fetch_one(db_pool)
         .await
         .map_err(|error| {
-            error!("Confirming mail for mail {} -> {:?}", claims.mail, error);
+            error!("Confirming mail for mail {:?} -> {:?}", mail, error);
             AuthenticatorError::UserNotFound
         })?;
 
@@ -1,12 +1,17 @@
 use std::fmt;
 
-use askama_axum::Template;
+use askama_axum::{IntoResponse, Template};
 use axum::extract::{Query, State};
+use axum_extra::extract::CookieJar;
 use serde::Deserialize;
 use sqlx::types::Uuid;
 
 use crate::{
     apps::App,
+    auth::{
+        csrf::CsrfForm,
+        mail_code::{MailCode, MailCodePurpose},
+    },
     general::{
         message::{Level, MessageBlock},
         AuthenticatorError,
@@ -40,6 +45,7 @@ pub struct ConfirmPage {
     action: Action,
     message: MessageBlock,
     app: App,
+    code_user_id: Option<Uuid>,
 }
 
 #[derive(Deserialize)]
@@ -50,9 +56,10 @@ pub struct QueryParams {
 }
 
 pub async fn send_confirm_handler(
+    cookies: CookieJar,
     State(state): State<AppState>,
     Query(params): Query<QueryParams>,
-) -> Result<ConfirmPage, ConfirmPage> {
+) -> Result<impl IntoResponse, ConfirmPage> {
     let app = App::select_app_or_authenticator(&state, params.app_id).await;
 
     let error_response = ConfirmPage {
@@ -63,6 +70,7 @@ pub async fn send_confirm_handler(
             "Veillez réessayer plus tard",
         ),
         app: app.clone(),
+        code_user_id: None,
     };
 
     let user_id =
@@ -83,11 +91,20 @@ pub async fn send_confirm_handler(
             ),
         ),
         app: app.clone(),
+        code_user_id: Some(user.id),
     };
 
+    let (cookies, browser_value) = MailCode::bind_browser(&state, cookies);
+
+    let code = MailCode::generate(&state, &user, MailCodePurpose::Confirmation, &browser_value)
+        .await
+        .map_err(|_| error_response.clone())?;
+
     ConfirmationMail::from(&state, user.clone(), app.clone())
+        .with_code(code)
         .send()
-        .map(|_| successfull_response)
+        .await
+        .map(|_| (cookies, successfull_response))
         .map_err(|_| error_response)
 }
 
@@ -105,6 +122,7 @@ pub async fn confirm_mail_handler(
             "Le code de confirmation est inconnu",
         ),
         app: app.clone(),
+        code_user_id: None,
     };
 
     let confirmed_mail = User::confirm_mail(&state, &app, params.token.unwrap_or_default())
@@ -122,6 +140,63 @@ pub async fn confirm_mail_handler(
             ),
         ),
         app,
+        code_user_id: None,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmCodeForm {
+    app_id: i32,
+    user_id: Uuid,
+    code: String,
+}
+
+/// Confirmation with the code of the mail typed in the browser that asked for it
+pub async fn confirm_code_handler(
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<ConfirmCodeForm>,
+) -> Result<ConfirmPage, ConfirmPage> {
+    let app = App::select_app_or_authenticator(&state, form.app_id).await;
+
+    let error_response = |error: AuthenticatorError| ConfirmPage {
+        action: Action::Confirming,
+        message: MessageBlock::new(Level::Error, "Confirmation impossible", &error.to_string()),
+        app: app.clone(),
+        code_user_id: Some(form.user_id),
+    };
+
+    let user = User::select_from_id(&state.db_pool, form.user_id)
+        .await
+        .map_err(error_response)?;
+
+    // The code only confirms the mail it was sent to
+    MailCode::verify(
+        &state,
+        &user,
+        MailCodePurpose::Confirmation,
+        &form.code,
+        &cookies,
+    )
+    .await
+    .map_err(error_response)?;
+
+    let confirmed_mail = User::mark_mail_as_confirmed(&state.db_pool, user.id, &user.mail)
+        .await
+        .map_err(error_response)?;
+
+    Ok(ConfirmPage {
+        action: Action::Confirming,
+        message: MessageBlock::new(
+            Level::Success,
+            "Mail confirmé",
+            &format!(
+                "Vous avez bien confirmé le mail suivant: {}",
+                confirmed_mail
+            ),
+        ),
+        app,
+        code_user_id: None,
     })
 }
 
@@ -130,6 +205,7 @@ pub struct ConfirmationMail {
     state: AppState,
     user: User,
     app: App,
+    code: Option<String>,
 }
 
 impl ConfirmationMail {
@@ -138,9 +214,16 @@ impl ConfirmationMail {
             state: state.clone(),
             user,
             app,
+            code: None,
         }
     }
 
+    /// Code to type in the browser that asked for the mail, for when the link gets mangled
+    pub fn with_code(mut self, code: String) -> Self {
+        self.code = Some(code);
+        self
+    }
+
     pub fn send_url(&self) -> String {
         format!(
             "{}/send_confirm?app_id={}&user_id={}",
@@ -148,9 +231,10 @@ impl ConfirmationMail {
         )
     }
 
-    pub fn send(&self) -> Result<bool, AuthenticatorError> {
+    pub async fn send(&self) -> Result<bool, AuthenticatorError> {
         let id_token = TokenFactory::for_app(&self.state, &self.app)
             .generate_id_token_with_expire(&self.user, 900)
+            .await
             .map_err(|_| AuthenticatorError::MailConfirmationFailed)?
             .token;
 
@@ -161,6 +245,14 @@ impl ConfirmationMail {
 
         let mail_subject = "Confirmez votre inscription".to_owned();
 
+        let code_paragraph = match &self.code {
+            Some(code) => format!(
+                "\nVous pouvez aussi saisir le code suivant dans la page ouverte dans votre navigateur : {}\n",
+                code
+            ),
+            None => "".to_owned(),
+        };
+
         let mail_body = format!("Bonjour {},
         
 Vous venez de vous inscrire à l'une des app de Brouclean Softwares: {}
@@ -168,12 +260,12 @@ Nous vous souhaitons la bienvenue.
 
 Pour pouvoir continuer et utiliser nos app, veuillez confirmer votre mail en cliquant sur le lien suivant :
 {}
-
+{}
 Notez que ce code n'est valable que 15 minutes.
 
 En vous souhaitant une excellente journée !!
 
-L'équipe de Brouclean Softwares",self.user.name, self.app.name,validation_url);
+L'équipe de Brouclean Softwares",self.user.name, self.app.name,validation_url, code_paragraph);
 
         self.state.mailer.send_mail(
             format!("{} <{}>", self.user.name, self.user.mail),
@@ -0,0 +1,125 @@
+use askama_axum::Template;
+use axum::{extract::State, response::Redirect, Json};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::types::Uuid;
+
+use crate::{
+    auth::{
+        csrf::CsrfForm,
+        reauth::RecentIdSession,
+        webauthn::{Ceremony, RelyingParty, WebauthnChallenge, WebauthnCredential},
+        IdSession,
+    },
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    AppState,
+};
+
+use super::profile::ProfilePage;
+
+/// Passkeys and security keys of the user
+#[derive(Clone, Debug, Template)]
+#[template(path = "users/profile_passkeys_block.html")]
+pub struct ProfilePasskeysBlock {
+    credentials: Vec<WebauthnCredential>,
+}
+
+impl ProfilePasskeysBlock {
+    pub async fn from(state: &AppState, id_session: &IdSession) -> Self {
+        Self {
+            credentials: WebauthnCredential::select_for_user(state, id_session.user_id)
+                .await
+                .unwrap_or_default(),
+        }
+    }
+}
+
+pub async fn register_options_handler(
+    RecentIdSession(id_session): RecentIdSession,
+    State(state): State<AppState>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let options = async {
+        let credentials = WebauthnCredential::select_for_user(&state, id_session.user_id).await?;
+
+        let challenge =
+            WebauthnChallenge::generate(&state, Ceremony::Registration, Some(id_session.user_id))
+                .await?;
+
+        Ok::<Value, AuthenticatorError>(RelyingParty::of(&state)?.creation_options(
+            &challenge,
+            id_session.user_id,
+            &id_session.mail,
+            &id_session.name,
+            &credentials,
+        ))
+    }
+    .await
+    .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+
+    Ok(Json(options))
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRegisterForm {
+    name: String,
+    client_data: String,
+    attestation_object: String,
+}
+
+pub async fn register_handler(
+    RecentIdSession(id_session): RecentIdSession,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<PasskeyRegisterForm>,
+) -> Result<Redirect, ProfilePage> {
+    let registered = WebauthnCredential::register(
+        &state,
+        id_session.user_id,
+        &form.name,
+        &form.client_data,
+        &form.attestation_object,
+    )
+    .await;
+
+    match registered {
+        Ok(_) => Ok(Redirect::to("/profile")),
+        Err(error) => Err(passkey_error(&state, id_session, error).await),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyForm {
+    id: Uuid,
+}
+
+pub async fn delete_handler(
+    RecentIdSession(id_session): RecentIdSession,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<PasskeyForm>,
+) -> Result<Redirect, ProfilePage> {
+    match WebauthnCredential::delete(&state, form.id, id_session.user_id).await {
+        Ok(_) => Ok(Redirect::to("/profile")),
+        Err(error) => Err(passkey_error(&state, id_session, error).await),
+    }
+}
+
+async fn passkey_error(
+    state: &AppState,
+    id_session: IdSession,
+    error: AuthenticatorError,
+) -> ProfilePage {
+    ProfilePage::from(
+        state,
+        id_session,
+        None,
+        MessageBlock::new(
+            Level::Error,
+            "Impossible de modifier les clés d'accès",
+            &error.to_string(),
+        ),
+    )
+    .await
+}
@@ -0,0 +1,64 @@
+use sqlx::types::Uuid;
+
+use crate::{
+    auth::session::Session, general::AuthenticatorError, openid::refresh::RefreshToken, AppState,
+};
+
+use super::User;
+
+/// After a password change every other session and every refresh token are revoked
+/// and the user is warned by mail
+pub async fn sign_out_everywhere(
+    state: &AppState,
+    user: &User,
+    kept_session_id: Option<Uuid>,
+) -> Result<(), AuthenticatorError> {
+    Session::delete_others(state, user.id, kept_session_id).await?;
+    RefreshToken::delete_for_user(state, user.id).await?;
+
+    let _ = PasswordChangedMail::from(state, user.clone()).send();
+
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct PasswordChangedMail {
+    state: AppState,
+    user: User,
+}
+
+impl PasswordChangedMail {
+    pub fn from(state: &AppState, user: User) -> Self {
+        Self {
+            state: state.clone(),
+            user,
+        }
+    }
+
+    pub fn send(&self) -> Result<bool, AuthenticatorError> {
+        let mail_subject = "Votre mot de passe a été modifié".to_owned();
+
+        let mail_body = format!(
+            "Bonjour {},
+
+Le mot de passe de votre compte {} vient d'être modifié.
+Par sécurité, toutes vos autres sessions ont été déconnectées.
+
+Si vous n'êtes pas à l'origine de ce changement, réinitialisez immédiatement votre mot de passe :
+{}/signin
+
+En vous souhaitant une excellente journée !!
+
+L'équipe de Brouclean Softwares",
+            self.user.name,
+            self.state.authenticator_app.name,
+            self.state.authenticator_app.base_url
+        );
+
+        self.state.mailer.send_mail(
+            format!("{} <{}>", self.user.name, self.user.mail),
+            mail_subject,
+            mail_body,
+        )
+    }
+}
@@ -1,18 +1,32 @@
 use askama_axum::{IntoResponse, Template};
-use axum::{extract::State, response::Redirect, Form};
+use axum::{extract::State, response::Redirect};
 use axum_extra::extract::CookieJar;
+use http::{HeaderMap, Method};
 use serde::Deserialize;
 
+use sqlx::types::Uuid;
+
 use crate::{
-    auth::IdSession,
+    auth::{
+        csrf::{CsrfChecked, CsrfForm},
+        mail_code::MailCode,
+        reauth::{redirect_to_reauthentication, RecentIdSession},
+        session::Session,
+        IdSession,
+    },
     general::{
         message::{Level, MessageBlock},
         navbar::NavBarBlock,
+        AuthenticatorError,
     },
+    openid::grant::{AppGrant, Grant},
     AppState,
 };
 
-use super::{confirm::ConfirmationMail, User};
+use super::{
+    confirm::ConfirmationMail, passkeys::ProfilePasskeysBlock, password::sign_out_everywhere,
+    recovery_codes::ProfileRecoveryBlock, two_factor::ProfileTotpBlock, User,
+};
 
 #[derive(Template)]
 #[template(path = "users/profile_page.html")]
@@ -22,6 +36,10 @@ pub struct ProfilePage {
     confirm_send_url: String,
     profile_message: MessageBlock,
     password_message: MessageBlock,
+    totp_block: ProfileTotpBlock,
+    passkeys_block: ProfilePasskeysBlock,
+    recovery_block: ProfileRecoveryBlock,
+    sessions_block: ProfileSessionsBlock,
     delete_block: ProfileDeleteBlock,
 }
 
@@ -44,15 +62,29 @@ impl ProfilePage {
             None => "".to_owned(),
         };
 
+        let totp_block = ProfileTotpBlock::from(state, &user).await;
+        let passkeys_block = ProfilePasskeysBlock::from(state, &id_session).await;
+        let recovery_block = ProfileRecoveryBlock::from(state, &id_session).await;
+        let sessions_block = ProfileSessionsBlock::from(state, &id_session).await;
+
         ProfilePage {
             navbar: NavBarBlock::from(state, Some(id_session)),
             user: user,
             confirm_send_url,
             profile_message,
             password_message: MessageBlock::empty(),
+            totp_block,
+            passkeys_block,
+            recovery_block,
+            sessions_block,
             delete_block: ProfileDeleteBlock::new(),
         }
     }
+
+    pub fn with_recovery_codes(mut self, codes: Vec<String>) -> Self {
+        self.recovery_block = self.recovery_block.with_new_codes(codes);
+        self
+    }
 }
 
 pub async fn get_handler(
@@ -72,10 +104,18 @@ pub struct ProfileForm {
 
 pub async fn update_profile_handler(
     cookies: CookieJar,
+    headers: HeaderMap,
     id_session: IdSession,
     State(state): State<AppState>,
-    Form(form): Form<ProfileForm>,
+    CsrfForm(form): CsrfForm<ProfileForm>,
 ) -> impl IntoResponse {
+    let is_mail_changed = form.mail != id_session.mail;
+
+    // Changing the mail changes how the account is recovered, it is a sensitive action
+    if is_mail_changed && !id_session.is_recently_authenticated() {
+        return redirect_to_reauthentication(&state, &Method::POST, &headers, None);
+    }
+
     let potentially_updated_user = User::update_profile(
         &state.db_pool,
         &id_session.user_id,
@@ -86,26 +126,28 @@ pub async fn update_profile_handler(
     )
     .await;
 
+    // Codes sent to the former mail must not confirm the new one
+    let potentially_updated_user = match potentially_updated_user {
+        Ok(user) if is_mail_changed => MailCode::delete_all(&state, user.id).await.map(|_| user),
+        other => other,
+    };
+
     match potentially_updated_user {
-        Ok(updated_user) => {
-            match IdSession::set_with_redirect_to_endpoint(
-                cookies,
+        // The owner role comes from the mail, so the session gets a new id when it changes
+        Ok(_) if is_mail_changed => match id_session.rotate(&state, cookies).await {
+            Ok(cookies) => (cookies, Redirect::to("/profile")).into_response(),
+            Err(error) => ProfilePage::from(
                 &state,
-                &updated_user,
-                Some("/profile".to_owned()),
-            ) {
-                Ok(response) => response.into_response(),
-
-                Err(error) => ProfilePage::from(
-                    &state,
-                    id_session,
-                    Some(updated_user),
-                    MessageBlock::new(Level::Error, "", &error.to_string()),
-                )
-                .await
-                .into_response(),
-            }
-        }
+                id_session,
+                None,
+                MessageBlock::new(Level::Error, "", &error.to_string()),
+            )
+            .await
+            .into_response(),
+        },
+
+        // The session is loaded from the user so it is up to date
+        Ok(_) => Redirect::to("/profile").into_response(),
 
         Err(error) => ProfilePage::from(
             &state,
@@ -125,10 +167,11 @@ pub struct PasswordForm {
 }
 
 pub async fn update_password_handler(
-    id_session: IdSession,
+    cookies: CookieJar,
+    RecentIdSession(id_session): RecentIdSession,
     State(state): State<AppState>,
-    Form(form): Form<PasswordForm>,
-) -> Result<MessageBlock, MessageBlock> {
+    CsrfForm(form): CsrfForm<PasswordForm>,
+) -> Result<(CookieJar, MessageBlock), MessageBlock> {
     let _ = User::update_password(
         &state.db_pool,
         &id_session.user_id,
@@ -138,10 +181,26 @@ pub async fn update_password_handler(
     .await
     .map_err(|error| MessageBlock::new(Level::Error, "", &error.to_string()))?;
 
-    Ok(MessageBlock::new(
-        Level::Success,
-        "",
-        "Votre password a bien été modifié",
+    let user = User::select_from_id(&state.db_pool, id_session.user_id)
+        .await
+        .map_err(|error| MessageBlock::new(Level::Error, "", &error.to_string()))?;
+
+    sign_out_everywhere(&state, &user, Some(id_session.session_id))
+        .await
+        .map_err(|error| MessageBlock::new(Level::Error, "", &error.to_string()))?;
+
+    let cookies = id_session
+        .rotate(&state, cookies)
+        .await
+        .map_err(|error| MessageBlock::new(Level::Error, "", &error.to_string()))?;
+
+    Ok((
+        cookies,
+        MessageBlock::new(
+            Level::Success,
+            "",
+            "Votre password a bien été modifié et vos autres sessions ont été déconnectées",
+        ),
     ))
 }
 
@@ -154,7 +213,7 @@ pub struct ProfileDeleteBlock {
 impl ProfileDeleteBlock {
     pub fn new() -> Self {
         Self {
-            delete_message: MessageBlock::new(Level::Error, "Attention cette action est définitive", "Si vous voulez vraiment supprimer votre profil, veuillez remplir votre adresse mail et votre mot de passe"),
+            delete_message: MessageBlock::new(Level::Error, "Attention cette action est définitive", "Si vous voulez vraiment supprimer votre profil, veuillez remplir votre adresse mail"),
         }
     }
 }
@@ -162,13 +221,13 @@ impl ProfileDeleteBlock {
 #[derive(Deserialize)]
 pub struct DeleteForm {
     mail: String,
-    password: String,
 }
 
+/// The mail is typed again to confirm
 pub async fn profile_delete_handler(
-    id_session: IdSession,
+    RecentIdSession(id_session): RecentIdSession,
     State(state): State<AppState>,
-    Form(form): Form<DeleteForm>,
+    CsrfForm(form): CsrfForm<DeleteForm>,
 ) -> Result<Redirect, ProfilePage> {
     let connected_user = User::select_from_id(&state.db_pool, id_session.user_id).await;
 
@@ -188,11 +247,7 @@ pub async fn profile_delete_handler(
 
     let connected_user = connected_user.unwrap();
 
-    if connected_user.mail != form.mail
-        || !connected_user
-            .password_match(form.password)
-            .unwrap_or(false)
-    {
+    if connected_user.mail != form.mail {
         return Err(ProfilePage::from(
             &state,
             id_session,
@@ -200,7 +255,7 @@ pub async fn profile_delete_handler(
             MessageBlock::new(
                 Level::Error,
                 "Impossible de supprimer le profil",
-                "Les mail et mot de passe sont incorrects",
+                "Le mail est incorrect",
             ),
         )
         .await);
@@ -222,3 +277,71 @@ pub async fn profile_delete_handler(
         .await),
     }
 }
+
+/// Active sessions of the user on their devices and apps they have given access to
+#[derive(Clone, Debug, Template)]
+#[template(path = "users/profile_sessions_block.html")]
+pub struct ProfileSessionsBlock {
+    current_session_id: Uuid,
+    sessions: Vec<Session>,
+    grants: Vec<AppGrant>,
+}
+
+impl ProfileSessionsBlock {
+    pub async fn from(state: &AppState, id_session: &IdSession) -> Self {
+        Self {
+            current_session_id: id_session.session_id,
+            sessions: Session::select_for_user(state, id_session.user_id)
+                .await
+                .unwrap_or_default(),
+            grants: Grant::select_for_user(state, id_session.user_id)
+                .await
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SessionForm {
+    id: Uuid,
+}
+
+pub async fn session_revoke_handler(
+    id_session: IdSession,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<SessionForm>,
+) -> Result<Redirect, ProfilePage> {
+    match Session::delete(&state, form.id, id_session.user_id).await {
+        Ok(_) => Ok(Redirect::to("/profile")),
+        Err(error) => Err(session_revoke_error(&state, id_session, error).await),
+    }
+}
+
+pub async fn other_sessions_revoke_handler(
+    id_session: IdSession,
+    State(state): State<AppState>,
+    _: CsrfChecked,
+) -> Result<Redirect, ProfilePage> {
+    match Session::delete_others(&state, id_session.user_id, Some(id_session.session_id)).await {
+        Ok(_) => Ok(Redirect::to("/profile")),
+        Err(error) => Err(session_revoke_error(&state, id_session, error).await),
+    }
+}
+
+async fn session_revoke_error(
+    state: &AppState,
+    id_session: IdSession,
+    error: AuthenticatorError,
+) -> ProfilePage {
+    ProfilePage::from(
+        state,
+        id_session,
+        None,
+        MessageBlock::new(
+            Level::Error,
+            "Impossible de déconnecter la session",
+            &error.to_string(),
+        ),
+    )
+    .await
+}
@@ -0,0 +1,70 @@
+use askama_axum::Template;
+use axum::extract::State;
+
+use crate::{
+    auth::{csrf::CsrfChecked, reauth::RecentIdSession, recovery::RecoveryCodes, IdSession},
+    general::message::{Level, MessageBlock},
+    AppState,
+};
+
+use super::profile::ProfilePage;
+
+/// Recovery codes left, the new ones are only shown once right after their generation
+#[derive(Clone, Debug, Template)]
+#[template(path = "users/profile_recovery_block.html")]
+pub struct ProfileRecoveryBlock {
+    remaining: i64,
+    new_codes: Vec<String>,
+}
+
+impl ProfileRecoveryBlock {
+    pub async fn from(state: &AppState, id_session: &IdSession) -> Self {
+        Self {
+            remaining: RecoveryCodes::remaining(state, id_session.user_id)
+                .await
+                .unwrap_or_default(),
+            new_codes: vec![],
+        }
+    }
+
+    pub fn with_new_codes(self, new_codes: Vec<String>) -> Self {
+        Self {
+            remaining: new_codes.len() as i64,
+            new_codes,
+        }
+    }
+}
+
+/// A recent authentication is needed since the codes give access to the account
+pub async fn generate_handler(
+    RecentIdSession(id_session): RecentIdSession,
+    State(state): State<AppState>,
+    _: CsrfChecked,
+) -> Result<ProfilePage, ProfilePage> {
+    match RecoveryCodes::generate(&state, id_session.user_id).await {
+        Ok(codes) => Ok(ProfilePage::from(
+            &state,
+            id_session,
+            None,
+            MessageBlock::new(
+                Level::Success,
+                "Nouveaux codes de récupération",
+                "Imprimez-les ou notez-les maintenant, ils ne seront plus affichés. Les anciens codes ne fonctionnent plus.",
+            ),
+        )
+        .await
+        .with_recovery_codes(codes)),
+
+        Err(error) => Err(ProfilePage::from(
+            &state,
+            id_session,
+            None,
+            MessageBlock::new(
+                Level::Error,
+                "Impossible de générer les codes de récupération",
+                &error.to_string(),
+            ),
+        )
+        .await),
+    }
+}
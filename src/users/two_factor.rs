@@ -0,0 +1,135 @@
+use askama_axum::Template;
+use axum::{extract::State, response::Redirect};
+use serde::Deserialize;
+
+use crate::{
+    auth::{
+        csrf::{CsrfChecked, CsrfForm},
+        reauth::RecentIdSession,
+        totp::{Totp, TotpEnrollment},
+        IdSession,
+    },
+    general::{
+        message::{Level, MessageBlock},
+        AuthenticatorError,
+    },
+    AppState,
+};
+
+use super::{profile::ProfilePage, User};
+
+/// Two steps verification with an authenticator app (TOTP)
+#[derive(Clone, Debug, Template)]
+#[template(path = "users/profile_totp_block.html")]
+pub struct ProfileTotpBlock {
+    is_enabled: bool,
+    enrollment: Option<TotpEnrollment>,
+}
+
+impl ProfileTotpBlock {
+    pub async fn from(state: &AppState, user: &Option<User>) -> Self {
+        let user = match user {
+            Some(user) => user,
+            None => {
+                return Self {
+                    is_enabled: false,
+                    enrollment: None,
+                }
+            }
+        };
+
+        Self {
+            is_enabled: Totp::is_enabled(state, user.id).await.unwrap_or(false),
+            enrollment: Totp::pending_enrollment(state, user)
+                .await
+                .unwrap_or_default(),
+        }
+    }
+}
+
+pub async fn totp_enroll_handler(
+    RecentIdSession(id_session): RecentIdSession,
+    State(state): State<AppState>,
+    _: CsrfChecked,
+) -> Result<Redirect, ProfilePage> {
+    match Totp::start_enrollment(&state, id_session.user_id).await {
+        Ok(_) => Ok(Redirect::to("/profile")),
+        Err(error) => Err(totp_error(&state, id_session, error).await),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TotpConfirmForm {
+    code: String,
+}
+
+pub async fn totp_confirm_handler(
+    RecentIdSession(id_session): RecentIdSession,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<TotpConfirmForm>,
+) -> Result<ProfilePage, ProfilePage> {
+    let confirmed = match User::select_from_id(&state.db_pool, id_session.user_id).await {
+        Ok(user) => Totp::confirm_enrollment(&state, &user, &form.code).await,
+        Err(error) => Err(error),
+    };
+
+    match confirmed {
+        Ok(_) => Ok(ProfilePage::from(
+            &state,
+            id_session,
+            None,
+            MessageBlock::new(
+                Level::Success,
+                "Vérification en deux étapes activée",
+                "Un code de votre application d'authentification vous sera demandé à chaque connexion",
+            ),
+        )
+        .await),
+        Err(error) => Err(totp_error(&state, id_session, error).await),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TotpDisableForm {
+    code: String,
+}
+
+/// A code is still asked to remove the second factor
+pub async fn totp_disable_handler(
+    RecentIdSession(id_session): RecentIdSession,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<TotpDisableForm>,
+) -> Result<Redirect, ProfilePage> {
+    let user = match User::select_from_id(&state.db_pool, id_session.user_id).await {
+        Ok(user) => user,
+        Err(error) => return Err(totp_error(&state, id_session, error).await),
+    };
+
+    let disabled = match Totp::verify(&state, &user, &form.code).await {
+        Ok(_) => Totp::disable(&state, user.id).await,
+        Err(error) => Err(error),
+    };
+
+    match disabled {
+        Ok(_) => Ok(Redirect::to("/profile")),
+        Err(error) => Err(totp_error(&state, id_session, error).await),
+    }
+}
+
+async fn totp_error(
+    state: &AppState,
+    id_session: IdSession,
+    error: AuthenticatorError,
+) -> ProfilePage {
+    ProfilePage::from(
+        state,
+        id_session,
+        None,
+        MessageBlock::new(
+            Level::Error,
+            "Vérification en deux étapes impossible",
+            &error.to_string(),
+        ),
+    )
+    .await
+}
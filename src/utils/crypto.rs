@@ -1,3 +1,12 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, OsRng},
+    Aes256Gcm, Key, KeyInit, Nonce,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
+
 use crate::general::AuthenticatorError;
 
 pub fn encrypt_text(text: &str) -> Result<String, AuthenticatorError> {
@@ -13,3 +22,82 @@ pub fn verify_encrypted_text(text: &str, hash: &str) -> Result<bool, Authenticat
         AuthenticatorError::CryptoError
     })
 }
+
+/// Deterministic SHA-256 hash encoded in base64url (no padding)
+pub fn hash_text(text: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(text.as_bytes()))
+}
+
+/// Signature of the text with the key (HMAC-SHA256)
+pub fn sign_text(key: &str, text: &str) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(text.as_bytes());
+
+    URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+/// Compare secrets without stopping at the first difference
+pub fn secrets_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Random numeric code (used for codes typed by the users)
+pub fn random_digits(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..length)
+        .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+        .collect()
+}
+
+/// Random url safe token (used for codes given to apps)
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// AES-256-GCM encryption of a secret that has to be read back (the key is the SHA-256 of the ENCRYPTION_KEY secret)
+/// The random nonce is saved in front of the encrypted bytes
+pub fn cipher_bytes(encryption_key: &str, bytes: &[u8]) -> Result<String, AuthenticatorError> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&Sha256::digest(
+        encryption_key.as_bytes(),
+    )));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let encrypted_bytes = cipher.encrypt(&nonce, bytes).map_err(|error| {
+        tracing::error!("Ciphering bytes -> {:?}", error);
+        AuthenticatorError::CryptoError
+    })?;
+
+    Ok(URL_SAFE_NO_PAD.encode([nonce.as_slice(), &encrypted_bytes].concat()))
+}
+
+pub fn decipher_bytes(encryption_key: &str, ciphered: &str) -> Result<Vec<u8>, AuthenticatorError> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&Sha256::digest(
+        encryption_key.as_bytes(),
+    )));
+
+    let ciphered_bytes = URL_SAFE_NO_PAD
+        .decode(ciphered)
+        .map_err(|_| AuthenticatorError::CryptoError)?;
+
+    if ciphered_bytes.len() < 12 {
+        return Err(AuthenticatorError::CryptoError);
+    }
+
+    let (nonce, encrypted_bytes) = ciphered_bytes.split_at(12);
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), encrypted_bytes)
+        .map_err(|error| {
+            tracing::error!("Deciphering bytes -> {:?}", error);
+            AuthenticatorError::CryptoError
+        })
+}
@@ -1,31 +1,48 @@
 use core::fmt::Debug;
 
 use jsonwebtoken::{
-    decode, encode, errors::ErrorKind::ExpiredSignature, DecodingKey, EncodingKey, Header,
-    Validation,
+    decode, decode_header, encode, errors::ErrorKind::ExpiredSignature, DecodingKey, EncodingKey,
+    Header, Validation,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sqlx::types::Uuid;
-use time::{Date, OffsetDateTime};
+use time::OffsetDateTime;
 use tracing::error;
 
-use crate::{apps::App, general::AuthenticatorError, users::User, AppState};
+use crate::{
+    apps::{api_resource::ApiResource, subject::Subject, App},
+    auth::assurance::{AuthMethod, Authentication},
+    general::AuthenticatorError,
+    openid::claims::UserClaim,
+    users::User,
+    utils::{crypto::random_token, time::HtmlDate},
+    AppState,
+};
+
+const ACCESS_TOKEN_TYPE: &str = "at+jwt";
 
 pub struct Token<Claims> {
     pub claims: Claims,
     pub token: String,
 }
 
+/// Generates and validates the tokens of an app
+/// audience = the app id or the identifier of one of its API resources
 pub struct TokenFactory {
-    authenticator_app: App,
+    state: AppState,
     app: App,
+    audience: String,
 }
 
 impl TokenFactory {
+    /// Tokens are signed with the secret of the app (HS256)
+    /// Native apps don't know it: they can't check the tokens and trust them
+    /// because they get them from the token endpoint over TLS
     pub fn for_app(state: &AppState, app: &App) -> Self {
         Self {
-            authenticator_app: state.authenticator_app.clone(),
+            state: state.clone(),
             app: app.clone(),
+            audience: app.id.to_string(),
         }
     }
 
@@ -33,35 +50,238 @@ impl TokenFactory {
         Self::for_app(state, &state.authenticator_app)
     }
 
-    pub fn generate_id_token(&self, user: &User) -> Result<Token<IdClaims>, AuthenticatorError> {
-        self.generate_id_token_with_expire(user, self.app.jwt_seconds_to_expire)
+    /// Access tokens of an API resource are signed by the app owning it
+    pub fn for_resource(state: &AppState, app: &App, resource: &ApiResource) -> Self {
+        Self {
+            state: state.clone(),
+            app: app.clone(),
+            audience: resource.identifier.clone(),
+        }
+    }
+
+    /// Factory of the app or of the API resource identified by the audience
+    pub async fn for_audience(
+        state: &AppState,
+        audience: &str,
+    ) -> Result<Self, AuthenticatorError> {
+        match audience.parse::<i32>() {
+            Ok(app_id) => Ok(Self::for_app(
+                state,
+                &App::select_from_app_id(state, app_id).await?,
+            )),
+            Err(_) => {
+                let resource = ApiResource::select_from_identifier(state, audience).await?;
+                let app = App::select_from_app_id(state, resource.app_id).await?;
+
+                Ok(Self::for_resource(state, &app, &resource))
+            }
+        }
+    }
+
+    pub fn app_id(&self) -> i32 {
+        self.app.id
+    }
+
+    pub async fn generate_id_token_with_expire(
+        &self,
+        user: &User,
+        seconds_to_expire: i32,
+    ) -> Result<Token<IdClaims>, AuthenticatorError> {
+        self.generate_id_token_with_claims(
+            user,
+            &UserClaim::all(),
+            None,
+            &Authentication::now(vec![]),
+            seconds_to_expire,
+        )
+        .await
     }
 
-    pub fn generate_id_token_with_expire(
+    /// Id token given to an app with only the released user claims
+    pub async fn generate_id_token_with_claims(
         &self,
         user: &User,
+        released_claims: &[UserClaim],
+        nonce: Option<String>,
+        authentication: &Authentication,
         seconds_to_expire: i32,
     ) -> Result<Token<IdClaims>, AuthenticatorError> {
+        let mut claims = self
+            .id_claims(user, released_claims, authentication, seconds_to_expire)
+            .await?;
+
+        claims.nonce = nonce;
+
+        self.encode_token(&Header::default(), claims)
+    }
+
+    async fn id_claims(
+        &self,
+        user: &User,
+        released_claims: &[UserClaim],
+        authentication: &Authentication,
+        seconds_to_expire: i32,
+    ) -> Result<IdClaims, AuthenticatorError> {
         let now = OffsetDateTime::now_utc().unix_timestamp();
 
         let expiration_time = now + i64::from(seconds_to_expire);
 
+        let is_released = |claim: UserClaim| released_claims.contains(&claim);
+
         let claims = IdClaims {
-            sub: user.id.to_string(),
-            name: user.name.clone(),
-            mail: user.mail.clone(),
-            avatar: user.avatar_url.clone(),
-            birthday: user.birthday.into(),
-            mail_is_confirmed: user.mail_is_confirmed.into(),
-            iss: self.authenticator_app.base_url.clone(),
+            sub: Subject::for_user(&self.state, &self.app, &user.id).await?,
+            iss: self.state.authenticator_app.base_url.clone(),
             aud: self.app.id.to_string(),
             iat: now,
             exp: expiration_time,
-            auth_time: now,
+            auth_time: authentication.time,
+            amr: authentication.amr(),
+            acr: authentication.acr(),
+            nonce: None,
+            name: is_released(UserClaim::Name).then(|| user.name.clone()),
+            mail: is_released(UserClaim::Email).then(|| user.mail.clone()),
+            avatar: is_released(UserClaim::Picture).then(|| user.avatar_url.clone()),
+            birthday: is_released(UserClaim::Birthdate)
+                .then(|| HtmlDate::from(user.birthday).to_string()),
+            mail_is_confirmed: is_released(UserClaim::EmailVerified)
+                .then_some(user.mail_is_confirmed),
+        };
+
+        Ok(claims)
+    }
+
+    pub fn extract_id_token(&self, token: String) -> Result<Token<IdClaims>, AuthenticatorError> {
+        if Self::is_access_token(&token) {
+            return Err(AuthenticatorError::InvalidToken);
+        }
+
+        self.decode_token(token, self.validation())
+    }
+
+    /// Map back the sub of the claims to the user id
+    pub async fn user_id(&self, claims: &IdClaims) -> Result<Uuid, AuthenticatorError> {
+        Subject::user_id(&self.state, &self.app, &claims.sub).await
+    }
+
+    /// Access token to call the app (the resource) given to the client app
+    pub async fn generate_access_token(
+        &self,
+        user: &User,
+        client: &App,
+        scope: &str,
+        authentication: &Authentication,
+        confirmation: Option<Confirmation>,
+    ) -> Result<Token<AccessClaims>, AuthenticatorError> {
+        let mut claims = self
+            .access_claims(user, client, scope, authentication)
+            .await?;
+
+        claims.cnf = confirmation;
+
+        self.encode_access_token(claims)
+    }
+
+    /// Claims of an access token, to be completed before being encoded
+    /// The sub is the one of the resource owner app or of the client when calling the authenticator
+    pub async fn access_claims(
+        &self,
+        user: &User,
+        client: &App,
+        scope: &str,
+        authentication: &Authentication,
+    ) -> Result<AccessClaims, AuthenticatorError> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+
+        let subject_app = if self.app.is_authenticator_app() {
+            client
+        } else {
+            &self.app
         };
 
+        Ok(AccessClaims {
+            iss: self.state.authenticator_app.base_url.clone(),
+            exp: now + i64::from(client.jwt_seconds_to_expire),
+            aud: self.audience.clone(),
+            sub: Subject::for_user(&self.state, subject_app, &user.id).await?,
+            client_id: client.id.to_string(),
+            iat: now,
+            jti: random_token(),
+            scope: scope.to_owned(),
+            auth_time: authentication.time,
+            amr: authentication.amr(),
+            acr: authentication.acr(),
+            act: None,
+            cnf: None,
+        })
+    }
+
+    pub fn encode_access_token(
+        &self,
+        claims: AccessClaims,
+    ) -> Result<Token<AccessClaims>, AuthenticatorError> {
+        let header = Header {
+            typ: Some(ACCESS_TOKEN_TYPE.to_owned()),
+            ..Header::default()
+        };
+
+        self.encode_token(&header, claims)
+    }
+
+    pub fn extract_access_token(
+        &self,
+        token: String,
+    ) -> Result<Token<AccessClaims>, AuthenticatorError> {
+        if !Self::is_access_token(&token) {
+            return Err(AuthenticatorError::InvalidToken);
+        }
+
+        self.decode_token(token, self.validation())
+    }
+
+    /// Map back the sub of the access token claims to the user id
+    pub async fn access_token_user_id(
+        &self,
+        claims: &AccessClaims,
+    ) -> Result<Uuid, AuthenticatorError> {
+        let subject_app = if self.app.is_authenticator_app() {
+            let client_id: i32 = claims
+                .client_id
+                .parse()
+                .map_err(|_| AuthenticatorError::InvalidToken)?;
+
+            App::select_from_app_id(&self.state, client_id).await?
+        } else {
+            self.app.clone()
+        };
+
+        Subject::user_id(&self.state, &subject_app, &claims.sub).await
+    }
+
+    fn is_access_token(token: &str) -> bool {
+        decode_header(token)
+            .ok()
+            .and_then(|header| header.typ)
+            .is_some_and(|typ| {
+                typ.eq_ignore_ascii_case(ACCESS_TOKEN_TYPE)
+                    || typ.eq_ignore_ascii_case(&format!("application/{}", ACCESS_TOKEN_TYPE))
+            })
+    }
+
+    fn validation(&self) -> Validation {
+        let mut validation = Validation::default();
+        validation.set_issuer(&[&self.state.authenticator_app.base_url]);
+        validation.set_audience(&[&self.audience]);
+
+        validation
+    }
+
+    fn encode_token<Claims: Serialize>(
+        &self,
+        header: &Header,
+        claims: Claims,
+    ) -> Result<Token<Claims>, AuthenticatorError> {
         let generated_token = encode(
-            &Header::default(),
+            header,
             &claims,
             &EncodingKey::from_secret(self.app.jwt_secret.as_ref()),
         )
@@ -76,15 +296,12 @@ impl TokenFactory {
         })
     }
 
-    pub fn extract_id_token(&self, token: String) -> Result<Token<IdClaims>, AuthenticatorError> {
-        let validate_issuer = [self.authenticator_app.base_url.clone()];
-        let validate_audience = [self.app.id.to_string()];
-
-        let mut validation = Validation::default();
-        validation.set_issuer(&validate_issuer);
-        validation.set_audience(&validate_audience);
-
-        let decoded_token = decode::<IdClaims>(
+    fn decode_token<Claims: DeserializeOwned>(
+        &self,
+        token: String,
+        validation: Validation,
+    ) -> Result<Token<Claims>, AuthenticatorError> {
+        let decoded_token = decode::<Claims>(
             &token,
             &DecodingKey::from_secret(self.app.jwt_secret.as_ref()),
             &validation,
@@ -102,31 +319,105 @@ impl TokenFactory {
             token,
         })
     }
+
+    /// Read the claims without validating the token
+    /// Only to find which app must validate it
+    pub fn unverified_claims<Claims: DeserializeOwned>(
+        token: &str,
+    ) -> Result<Claims, AuthenticatorError> {
+        let mut validation = Validation::default();
+        validation.insecure_disable_signature_validation();
+        validation.validate_aud = false;
+        validation.validate_exp = false;
+
+        let decoded_token = decode::<Claims>(token, &DecodingKey::from_secret(&[]), &validation)
+            .map_err(|_| AuthenticatorError::InvalidToken)?;
+
+        Ok(decoded_token.claims)
+    }
 }
 
-/// sub = subject -> user unique id
+/// sub = subject -> user unique id (public) or per sector id (pairwise)
 /// iss = issuer -> company url of the auth server
 /// aud = audience -> client id of the app requested auth
 /// iat = issued at -> date of the token generation
 /// exp = expiration -> end date of the token
 /// auth_time = authentication time -> time when the End-User authentication occurred.
+/// amr = authentication methods references -> how the End-User authenticated (pwd, otp...)
+/// acr = authentication context class reference -> level of the authentication (1 or 2 factors)
+/// nonce = value given by the app in the authentication request
+/// User claims are only present when released to the app
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct IdClaims {
     pub sub: String,
     iss: String,
-    aud: String,
+    pub aud: String,
     iat: i64,
-    auth_time: i64,
+    pub auth_time: i64,
     pub exp: i64,
-    pub name: String,
-    pub mail: String,
-    pub avatar: String,
-    pub birthday: Date,
-    pub mail_is_confirmed: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub amr: Vec<AuthMethod>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "email", skip_serializing_if = "Option::is_none")]
+    pub mail: Option<String>,
+    #[serde(rename = "picture", skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    #[serde(rename = "birthdate", skip_serializing_if = "Option::is_none")]
+    pub birthday: Option<String>,
+    #[serde(rename = "email_verified", skip_serializing_if = "Option::is_none")]
+    pub mail_is_confirmed: Option<bool>,
 }
 
-impl IdClaims {
-    pub fn user_id(&self) -> Uuid {
-        Uuid::parse_str(&self.sub).unwrap()
-    }
+/// JWT access token (typ at+jwt)
+/// https://www.rfc-editor.org/rfc/rfc9068.html
+/// aud = audience -> id of the app or identifier of the API resource the token gives access to
+/// sub = subject -> user id as known by the resource (or by the client when calling the authenticator)
+/// client_id = id of the app the token was given to
+/// jti = JWT id -> unique id of the token
+/// scope = scope granted to the client
+/// act = actor -> app acting on behalf of the user (token exchange)
+/// cnf = confirmation -> key the token is bound to (DPoP)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccessClaims {
+    iss: String,
+    pub exp: i64,
+    pub aud: String,
+    pub sub: String,
+    pub client_id: String,
+    pub iat: i64,
+    pub jti: String,
+    pub scope: String,
+    pub auth_time: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub amr: Vec<AuthMethod>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+/// Actor claim of exchanged tokens
+/// sub = client id of the acting app
+/// act = former actor when the token was exchanged several times
+/// https://www.rfc-editor.org/rfc/rfc8693.html#name-act-actor-claim
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<Box<Actor>>,
+}
+
+/// Confirmation claim of sender-constrained tokens
+/// jkt = JWK thumbprint of the DPoP key
+/// https://www.rfc-editor.org/rfc/rfc9449.html#name-jwk-thumbprint-confirmation
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Confirmation {
+    pub jkt: String,
 }
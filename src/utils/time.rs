@@ -1,4 +1,9 @@
-use time::format_description::{self, BorrowedFormatItem};
+use std::fmt;
+
+use time::{
+    format_description::{self, BorrowedFormatItem},
+    Date,
+};
 
 use crate::general::AuthenticatorError;
 
@@ -32,3 +37,17 @@ impl TryInto<sqlx::types::time::Date> for HtmlDate {
             .map_err(|_| AuthenticatorError::InvalidDate)
     }
 }
+
+impl From<Date> for HtmlDate {
+    fn from(date: Date) -> Self {
+        Self {
+            date: date.format(&html_date_formatter()).unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for HtmlDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.date)
+    }
+}